@@ -0,0 +1,182 @@
+//! Alerts pushed to ntfy or Gotify when a source keeps failing. Every
+//! `notify_interval_seconds` each source is fetched as a request would; once
+//! one has failed `after_failures` checks in a row an alert goes to every
+//! target, and another once it answers again.
+
+use crate::calendar::CalendarService;
+use crate::config::{AlertTarget, Alerts, Config};
+use crate::logging;
+use crate::templates;
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// An alert, sent as each target expects it.
+struct Alert<'a> {
+    source: &'a str,
+    group: &'a str,
+    /// Checks failed in a row.
+    failures: u32,
+    /// Why the source is failing, or `None` once it recovered.
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct GotifyMessage<'a> {
+    title: &'a str,
+    message: &'a str,
+    priority: u8,
+}
+
+/// Starts checking sources, if `alerts` is configured.
+pub fn spawn(config: Arc<Config>, service: CalendarService) -> Option<JoinHandle<()>> {
+    config.alerts.as_ref()?;
+    Some(tokio::spawn(run(config, service)))
+}
+
+async fn run(config: Arc<Config>, service: CalendarService) {
+    let Some(ref alerts) = config.alerts else {
+        return;
+    };
+    let timeout = Duration::from_secs(config.request_timeout_seconds);
+    let client = match reqwest::Client::builder().timeout(timeout).build() {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::error!("Failed to set up alerts: {:?}", e);
+            return;
+        }
+    };
+    // Consecutive failures by source URL
+    let mut failures: HashMap<String, u32> = HashMap::new();
+    let mut ticks = tokio::time::interval(Duration::from_secs(config.notify_interval_seconds));
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        // A source in several groups is checked, and alerted on, once
+        let mut checked = HashSet::new();
+        for group in &config.calendars {
+            for cal in &group.calendars {
+                if !checked.insert(cal.url.as_str()) {
+                    continue;
+                }
+                let count = failures.entry(cal.url.clone()).or_default();
+                match service.check_source(group, cal).await {
+                    Ok(()) => {
+                        if *count >= alerts.after_failures {
+                            let alert = Alert {
+                                source: &cal.name,
+                                group: group.display_name(),
+                                failures: *count,
+                                error: None,
+                            };
+                            send(&client, alerts, &alert).await;
+                        }
+                        *count = 0;
+                    }
+                    Err(error) => {
+                        *count += 1;
+                        if *count == alerts.after_failures {
+                            let alert = Alert {
+                                source: &cal.name,
+                                group: group.display_name(),
+                                failures: *count,
+                                error: Some(error.to_string()),
+                            };
+                            send(&client, alerts, &alert).await;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Sends `alert` to every target, logging failures; a missed alert isn't
+/// retried.
+async fn send(client: &reqwest::Client, alerts: &Alerts, alert: &Alert<'_>) {
+    match alert.error {
+        Some(ref error) => tracing::warn!(
+            "Source '{}' of '{}' failed {} checks in a row: {}",
+            alert.source,
+            alert.group,
+            alert.failures,
+            error
+        ),
+        None => tracing::info!(
+            "Source '{}' of '{}' answers again after {} failed checks",
+            alert.source,
+            alert.group,
+            alert.failures
+        ),
+    }
+    let failure = alert.error.is_some();
+    let context = templates::Alert {
+        source: alert.source,
+        group: alert.group,
+        failures: alert.failures,
+        error: alert.error.as_deref(),
+    };
+    let render = |template: Option<&str>, default: &str| {
+        let source = template.unwrap_or(default);
+        templates::render(source, &context).unwrap_or_else(|e| {
+            tracing::warn!("Failed to render an alert template: {}", e);
+            templates::render(default, &context).unwrap_or_default()
+        })
+    };
+    for target in &alerts.targets {
+        let own = target.templates();
+        let title = render(
+            own.title_template.as_deref(),
+            templates::DEFAULT_ALERT_TITLE,
+        );
+        let message = render(own.message.template.as_deref(), templates::DEFAULT_ALERT);
+        let request = match target {
+            AlertTarget::Ntfy { url, token, .. } => {
+                let (priority, tags) = if failure {
+                    ("high", "warning")
+                } else {
+                    ("default", "white_check_mark")
+                };
+                // As query parameters rather than headers, which can't hold
+                // non-ASCII names
+                let query = [
+                    ("title", title.as_str()),
+                    ("priority", priority),
+                    ("tags", tags),
+                ];
+                let mut request = client.post(url).query(&query).body(message);
+                if let Some(token) = token {
+                    request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+                }
+                request
+            }
+            AlertTarget::Gotify { url, token, .. } => {
+                let message = GotifyMessage {
+                    title: &title,
+                    message: &message,
+                    priority: if failure { 8 } else { 4 },
+                };
+                let body = serde_json::to_vec(&message).unwrap_or_default();
+                client
+                    .post(format!("{}/message", url.trim_end_matches('/')))
+                    .header("X-Gotify-Key", token)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(body)
+            }
+        };
+        let sent = request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(e) = sent {
+            tracing::warn!(
+                "Failed to send an alert to {}: {}",
+                logging::redact_url(target.url()),
+                logging::error_chain(&e.without_url())
+            );
+        }
+    }
+}
@@ -0,0 +1,350 @@
+//! Types of the JSON endpoints. The server serializes exactly these, and the
+//! `client` module deserializes them, so the two can't drift apart.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Response of `/status/{key}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatusResponse {
+    pub cache: CacheStats,
+    pub pipeline: Vec<StageStats>,
+    pub errors: Vec<ErrorStats>,
+    /// Uses of deprecated keys, by key name.
+    pub deprecated_keys: BTreeMap<String, KeyUseStats>,
+    pub shadow: Vec<ShadowStats>,
+    /// Every configured source with the outcome of its latest fetch.
+    pub sources: Vec<SourceStatus>,
+}
+
+/// Cache counters and entries, part of `/status/{key}` and the response of
+/// `/admin/cache/stats/{key}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub enabled: bool,
+    pub ttl_seconds: u64,
+    pub entry_count: u64,
+    /// Approximate memory held by the cache: every entry's URL and body.
+    pub bytes: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub hit_ratio: Option<f64>,
+    pub evictions: EvictionStats,
+    pub entries: Vec<CacheEntryStats>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EvictionStats {
+    pub expired: u64,
+    pub size: u64,
+    pub explicit: u64,
+    pub replaced: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntryStats {
+    /// The source URL, redacted like in logs since it may embed a token.
+    pub url: String,
+    /// The configured sources fetched from this URL.
+    pub sources: Vec<CacheEntrySource>,
+    pub age_seconds: u64,
+    pub bytes: usize,
+    /// Lifetime of the entry, from the `cache_ttl_seconds` of the source that
+    /// fetched it.
+    pub ttl_seconds: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntrySource {
+    pub group: String,
+    pub source: String,
+}
+
+/// The stages a group's sources pass through on their way to a combined calendar,
+/// in order.
+///
+/// Fetching lives in `CalendarService`, since it needs the HTTP client and cache.
+/// Every later stage is a plain function over the previous stage's output, so it
+/// can be run on its own; `/admin/explain` replays them one at a time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Fetch,
+    Decode,
+    Parse,
+    Repair,
+    Filter,
+    Transform,
+    Dedup,
+    Window,
+    Sort,
+    Serialize,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 10] = [
+        Stage::Fetch,
+        Stage::Decode,
+        Stage::Parse,
+        Stage::Repair,
+        Stage::Filter,
+        Stage::Transform,
+        Stage::Dedup,
+        Stage::Window,
+        Stage::Sort,
+        Stage::Serialize,
+    ];
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StageStats {
+    pub stage: Stage,
+    pub runs: u64,
+    pub total_ms: f64,
+    pub mean_ms: Option<f64>,
+}
+
+/// Coarse error classes, used as HTTP statuses and metric labels. Only
+/// `Internal` indicates a bug in the combiner itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    UpstreamTimeout,
+    UpstreamError,
+    /// An upstream presented a certificate whose key isn't pinned for its source.
+    PinMismatch,
+    Internal,
+}
+
+impl ErrorKind {
+    pub const ALL: [ErrorKind; 4] = [
+        ErrorKind::UpstreamTimeout,
+        ErrorKind::UpstreamError,
+        ErrorKind::PinMismatch,
+        ErrorKind::Internal,
+    ];
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorStats {
+    pub kind: ErrorKind,
+    pub count: u64,
+}
+
+/// The latest evaluation of a rule running in shadow mode.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShadowStats {
+    pub group: String,
+    pub source: String,
+    /// `include_when`, or the name of a quirk.
+    pub rule: String,
+    pub evaluations: u64,
+    pub would_drop: usize,
+    pub would_change: usize,
+    /// UIDs of the affected events, at most 20.
+    pub uids: Vec<String>,
+    pub reason: Option<String>,
+}
+
+/// How the latest fetch of a source went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FetchOutcome {
+    /// Downloaded from the upstream.
+    Fetched,
+    /// Served from the cache.
+    Cached,
+    /// The refresh failed and an older cached copy was served.
+    Stale,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SourceStatus {
+    pub group: String,
+    pub source: String,
+    /// `None` if the source hasn't been requested since startup.
+    pub outcome: Option<FetchOutcome>,
+    pub last_fetch_seconds_ago: Option<u64>,
+    /// Age of the body that was served, which predates the fetch when cached.
+    pub body_age_seconds: Option<u64>,
+    /// Status of the upstream response that produced the body, or of the failure.
+    pub http_status: Option<u16>,
+    /// Index in the source's `mirrors` of the URL the body came from, or `None`
+    /// for its main URL.
+    pub mirror: Option<usize>,
+    pub bytes: Option<usize>,
+    pub error: Option<String>,
+    /// Problems found while parsing the body, e.g. events without a UID.
+    pub parse_warnings: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyUseStats {
+    pub uses: u64,
+    pub last_used_seconds_ago: Option<u64>,
+}
+
+/// Response of `/admin/explain/{key}/{cal_name}`: every decision the merge made
+/// for the events with one UID.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Explanation {
+    pub group: String,
+    pub uid: String,
+    pub steps: Vec<ExplainStep>,
+    /// The matching events as they appear in the combined calendar.
+    pub output: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExplainStep {
+    pub source: String,
+    /// A pipeline stage such as `repair`, or `match` for the UID lookup.
+    pub stage: String,
+    pub detail: String,
+}
+
+/// Response of `/admin/usage/{key}`: when subscriptions are polled, by group and
+/// by key. Counts are kept in memory since startup, in UTC.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsageReport {
+    pub groups: BTreeMap<String, UsageStats>,
+    /// By key name; signed URLs appear as `signed:` and the start of their signature.
+    pub keys: BTreeMap<String, UsageStats>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsageStats {
+    pub total: u64,
+    pub last_seen_seconds_ago: u64,
+    /// Requests by weekday, Monday first, and hour of the day.
+    pub hourly: Vec<[u64; 24]>,
+    /// Requests on each of the last 30 days, oldest first.
+    pub daily: Vec<DailyUsage>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DailyUsage {
+    /// `YYYY-MM-DD`
+    pub date: String,
+    pub count: u64,
+}
+
+/// Response of `POST /admin/cache/purge/{key}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachePurge {
+    /// Source URLs whose cached bodies were evicted, redacted and sorted.
+    pub purged: Vec<String>,
+}
+
+/// Response of `/healthz/ready`, sent with `503 Service Unavailable` when not ready.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub config: ConfigCheck,
+    /// The sources probed by this check; empty unless `readiness_probe` is set.
+    pub sources: Vec<SourceProbe>,
+}
+
+/// Whether config.json still loads, so a restart would succeed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfigCheck {
+    pub valid: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SourceProbe {
+    pub group: String,
+    pub source: String,
+    pub reachable: bool,
+    /// Status of the HEAD request, if the source answered.
+    pub status: Option<u16>,
+    pub duration_ms: u64,
+    pub error: Option<String>,
+}
+
+/// Response of `/calendar/{key}/{cal_name}/changes`: the changes to a group's
+/// events recorded by its `history`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangeLog {
+    pub group: String,
+    /// Oldest first.
+    pub changes: Vec<ChangeRecord>,
+}
+
+/// A change to one event, as kept in a group's history.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangeRecord {
+    /// When the change was found, in RFC 3339.
+    pub at: String,
+    pub change: ChangeKind,
+    #[serde(flatten)]
+    pub event: EventSummary,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Added,
+    Changed,
+    Removed,
+}
+
+/// Response of `/calendar/{key}/{cal_name}/sync`: the events of a group that
+/// changed since a token, and the token to pass next time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncResponse {
+    pub group: String,
+    /// Opaque; passed back as `?token=` on the next sync.
+    pub token: String,
+    /// Whether `updated` lists every event, for a sync without a token,
+    /// rather than only those that changed.
+    pub full: bool,
+    /// Events added or changed since the token, as they are now.
+    pub updated: Vec<SyncedEvent>,
+    /// Events removed since the token.
+    pub removed: Vec<EventSummary>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncedEvent {
+    #[serde(flatten)]
+    pub event: EventSummary,
+    /// The merged VEVENT, without DTSTAMP.
+    pub data: String,
+}
+
+/// An event as change logs and notifications describe it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventSummary {
+    pub uid: String,
+    /// The occurrence a modified instance replaces.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recurrence_id: Option<String>,
+    pub summary: String,
+    /// A date as `2024-05-01`, or a time as RFC 3339 in the event's zone.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<String>,
+    /// For a changed event, the properties that changed, e.g. `["LOCATION"]`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub properties: Vec<String>,
+}
+
+/// Body of `POST /combine/{key}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CombineRequest {
+    pub sources: Vec<CombineSource>,
+    /// Group options as config.json sets them, e.g.
+    /// `{"exclude_keywords": ["Cancelled"], "timezone": "Europe/Berlin"}`.
+    #[serde(default)]
+    pub options: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CombineSource {
+    pub url: String,
+    /// Defaults to the URL's host.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
@@ -0,0 +1,129 @@
+//! Inline ATTACH payloads, which can make one event megabytes long: stripped,
+//! or moved out of the calendar behind links served by `/attachment`.
+
+use crate::ics::Property;
+use crate::pipeline::ParsedCalendar;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use ring::digest;
+use ring::hmac;
+use ring::rand::SystemRandom;
+use serde::Deserialize;
+
+/// How a group handles ATTACH properties.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AttachmentPolicy {
+    /// Passes attachments through untouched.
+    #[default]
+    Keep,
+    /// Removes every ATTACH, inline or linked.
+    Strip,
+    /// Replaces inline attachments with links to `/attachment`; linked ones
+    /// are kept.
+    Link,
+}
+
+/// The decoded payload of an inline attachment.
+#[derive(Debug)]
+pub struct Attachment {
+    pub bytes: Vec<u8>,
+    /// FMTTYPE, if the source gave one.
+    pub content_type: Option<String>,
+}
+
+/// Removes every ATTACH of `calendar`'s events.
+pub fn strip(calendar: &mut ParsedCalendar) {
+    for event in &mut calendar.events {
+        if !event.contains("ATTACH") {
+            continue;
+        }
+        *event = event
+            .lines()
+            .filter(|line| Property::parse(line).is_none_or(|property| property.name != "ATTACH"))
+            .collect::<Vec<_>>()
+            .join("\n");
+    }
+}
+
+/// Replaces the inline attachments of `calendar`'s events with links under
+/// `base`, e.g. `https://example.com/attachment/work`, returning each payload
+/// by the ID in its link. An ID is the payload's SHA-256 in URL-safe base64, so
+/// a link stays the same across fetches, and each link is signed with `key` for
+/// `group`.
+pub fn link(
+    calendar: &mut ParsedCalendar,
+    base: &str,
+    group: &str,
+    key: &hmac::Key,
+) -> Vec<(String, Attachment)> {
+    let mut found = Vec::new();
+    for event in &mut calendar.events {
+        if !event.contains("ATTACH") {
+            continue;
+        }
+        let mut lines = Vec::new();
+        for line in event.lines() {
+            let Some(mut property) = Property::parse(line) else {
+                lines.push(line.to_string());
+                continue;
+            };
+            let inline = property
+                .param("ENCODING")
+                .is_some_and(|encoding| encoding.eq_ignore_ascii_case("BASE64"));
+            if property.name != "ATTACH" || !inline {
+                lines.push(line.to_string());
+                continue;
+            }
+            // An undecodable payload is no use to anyone, inline or not
+            let Ok(bytes) = STANDARD.decode(&property.value) else {
+                continue;
+            };
+            let id = URL_SAFE_NO_PAD.encode(digest::digest(&digest::SHA256, &bytes));
+            property
+                .params
+                .retain(|(name, _)| name != "ENCODING" && name != "VALUE");
+            property.value = format!("{}/{}?sig={}", base, id, sign(key, group, &id));
+            lines.push(property.to_string());
+            found.push((
+                id,
+                Attachment {
+                    bytes,
+                    content_type: property.param("FMTTYPE").map(str::to_string),
+                },
+            ));
+        }
+        *event = lines.join("\n");
+    }
+    found
+}
+
+/// The key attachment links are signed with: `signing_secret` if there is one,
+/// so links outlive a restart, or else a random one.
+pub fn signing_key(secret: Option<&str>) -> hmac::Key {
+    match secret {
+        Some(secret) => hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+        None => hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new())
+            .expect("no system randomness for the attachment key"),
+    }
+}
+
+/// Signs the link to attachment `id` of `group`, returning the URL-safe base64
+/// signature.
+fn sign(key: &hmac::Key, group: &str, id: &str) -> String {
+    let tag = hmac::sign(key, message(group, id).as_bytes());
+    URL_SAFE_NO_PAD.encode(tag.as_ref())
+}
+
+/// Checks a signature produced by `sign`, in constant time.
+pub fn verify(key: &hmac::Key, group: &str, id: &str, signature: &str) -> bool {
+    let Ok(tag) = URL_SAFE_NO_PAD.decode(signature) else {
+        return false;
+    };
+    hmac::verify(key, message(group, id).as_bytes(), &tag).is_ok()
+}
+
+// Prefixed so a signature can't pass for one of `signing::sign`'s
+fn message(group: &str, id: &str) -> String {
+    format!("attachment\n{}\n{}", group, id)
+}
@@ -0,0 +1,83 @@
+use crate::config::Lockout;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Target of the audit log, so it can be filtered or routed on its own.
+pub const TARGET: &str = "webcal_combiner::audit";
+
+/// Clients tracked before idle ones are pruned.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Failed authentication attempts by client IP, and the IPs banned for them.
+pub struct AuthFailures {
+    lockout: Option<Lockout>,
+    clients: Mutex<HashMap<IpAddr, ClientFailures>>,
+}
+
+#[derive(Default)]
+struct ClientFailures {
+    recent: VecDeque<Instant>,
+    banned_until: Option<Instant>,
+}
+
+impl AuthFailures {
+    pub fn new(lockout: Option<Lockout>) -> Self {
+        Self {
+            lockout,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How much longer `ip` is banned for, if it is.
+    pub fn banned_for(&self, ip: IpAddr) -> Option<Duration> {
+        let banned_until = self.clients.lock().unwrap().get(&ip)?.banned_until?;
+        banned_until.checked_duration_since(Instant::now())
+    }
+
+    /// Logs a failed attempt and bans `ip` once it reaches `max_failures` within
+    /// the lockout window. Lines are fail2ban-friendly, e.g.
+    /// `auth failure from 203.0.113.7 path="/calendar/***/family" user_agent="curl/8.0"`.
+    pub fn record(&self, ip: IpAddr, path: &str, user_agent: &str) {
+        tracing::warn!(
+            target: TARGET,
+            "auth failure from {} path={:?} user_agent={:?}",
+            ip,
+            path,
+            user_agent
+        );
+
+        let Some(ref lockout) = self.lockout else {
+            return;
+        };
+        let now = Instant::now();
+        let window = Duration::from_secs(lockout.window_seconds);
+        let mut clients = self.clients.lock().unwrap();
+
+        if clients.len() >= PRUNE_THRESHOLD {
+            clients.retain(|_, client| {
+                client.banned_until.is_some_and(|until| until > now)
+                    || client.recent.back().is_some_and(|&at| now - at < window)
+            });
+        }
+
+        let client = clients.entry(ip).or_default();
+        client.recent.push_back(now);
+        while client.recent.front().is_some_and(|&at| now - at >= window) {
+            client.recent.pop_front();
+        }
+
+        if client.recent.len() >= lockout.max_failures as usize {
+            client.recent.clear();
+            client.banned_until = Some(now + Duration::from_secs(lockout.ban_seconds));
+            tracing::warn!(
+                target: TARGET,
+                "ban {} for {}s after {} auth failures",
+                ip,
+                lockout.ban_seconds,
+                lockout.max_failures
+            );
+        }
+    }
+}
@@ -0,0 +1,566 @@
+//! A read-only CalDAV view of the combined groups under `/caldav/{key}/`, so
+//! clients like DAVx5 and Thunderbird can sync changed events instead of
+//! downloading the whole calendar on every poll. The key's home lists its
+//! groups as calendars, and each group's events are resources named after
+//! their UID, one per series with its overrides.
+//!
+//! Only what those clients use is understood: PROPFIND, and the
+//! calendar-query and calendar-multiget REPORTs. Requests are read with a
+//! small tag scanner rather than a full XML parser.
+
+use crate::ics::{self, Property};
+use crate::view::escape_html;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use regex::Regex;
+use ring::digest;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::LazyLock;
+
+pub const DAV: &str = "DAV:";
+pub const CALDAV: &str = "urn:ietf:params:xml:ns:caldav";
+pub const CALENDARSERVER: &str = "http://calendarserver.org/ns/";
+
+/// How far from now a time-range without a `start` or `end` reaches, since
+/// expanding recurrences up to chrono's own limits overflows.
+const OPEN_RANGE: Duration = Duration::days(100 * 365);
+
+/// The content type of a single event resource.
+pub const CONTENT_TYPE: &str = "text/calendar; charset=utf-8; component=VEVENT";
+
+/// A start or end tag: whether it closes, its qualified name, its attributes,
+/// and whether it closes itself.
+static RE_TAG: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"<(/?)([A-Za-z_][\w.:-]*)((?:\s[^>]*?)?)(/?)>").unwrap());
+static RE_NAMESPACE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"xmlns(?::([\w.-]+))?\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap());
+static RE_HREF: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"<(?:[\w.-]+:)?href(?:\s[^>]*)?>([^<]*)</(?:[\w.-]+:)?href>").unwrap()
+});
+static RE_ATTRIBUTE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"([\w.-]+)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap());
+
+/// One event series of a group, as a calendar of its own.
+pub struct Resource {
+    /// The last path segment, e.g. `Xb2dFq6pD0s1yXl3fGJw9A.ics`.
+    pub name: String,
+    pub etag: String,
+    pub data: String,
+}
+
+/// A group's events as CalDAV resources.
+pub struct Collection {
+    /// Changes whenever any resource does.
+    pub ctag: String,
+    pub resources: Vec<Resource>,
+}
+
+impl Collection {
+    /// Splits a combined calendar into one resource per UID. Each carries the
+    /// VTIMEZONEs its events refer to.
+    pub fn new(calendar: &str) -> Self {
+        let text = ics::unfold(calendar);
+        let prodid = ics::top_level_properties(&text)
+            .into_iter()
+            .find(|property| property.name == "PRODID")
+            .map(|property| property.value)
+            .unwrap_or_default();
+        let timezones: Vec<(String, &str)> = ics::components(&text, "VTIMEZONE")
+            .into_iter()
+            .filter_map(|block| {
+                let tzid = ics::top_level_properties(block)
+                    .into_iter()
+                    .find(|property| property.name == "TZID")?
+                    .value;
+                Some((tzid, block))
+            })
+            .collect();
+
+        // By UID, in calendar order
+        let mut series: Vec<(String, Vec<&str>)> = Vec::new();
+        let mut index: HashMap<String, usize> = HashMap::new();
+        for event in ics::components(&text, "VEVENT") {
+            // An event without a UID can't be told apart from the next, so
+            // it's keyed by its content
+            let uid = ics::Event::parse(event)
+                .uid
+                .unwrap_or_else(|| event.to_string());
+            match index.get(&uid) {
+                Some(&i) => series[i].1.push(event),
+                None => {
+                    index.insert(uid.clone(), series.len());
+                    series.push((uid, vec![event]));
+                }
+            }
+        }
+
+        let resources: Vec<Resource> = series
+            .iter()
+            .map(|(uid, events)| {
+                let referenced: BTreeSet<String> = events
+                    .iter()
+                    .flat_map(|event| event.lines())
+                    .filter_map(Property::parse)
+                    .filter_map(|property| property.param("TZID").map(str::to_string))
+                    .collect();
+                let mut data = format!("BEGIN:VCALENDAR\nVERSION:2.0\nPRODID:{}\n", prodid);
+                for (_, block) in timezones
+                    .iter()
+                    .filter(|(tzid, _)| referenced.contains(tzid))
+                {
+                    data.push_str(block);
+                    data.push('\n');
+                }
+                for event in events {
+                    data.push_str(event);
+                    data.push('\n');
+                }
+                data.push_str("END:VCALENDAR\n");
+                let data = ics::fold_lines(&data.replace('\n', "\r\n"));
+                Resource {
+                    name: format!("{}.ics", hash(uid.as_bytes())),
+                    etag: format!("\"{}\"", etag(&data)),
+                    data,
+                }
+            })
+            .collect();
+
+        let mut tags = String::new();
+        for resource in &resources {
+            tags.push_str(&resource.name);
+            tags.push_str(&resource.etag);
+        }
+        Self {
+            ctag: hash(tags.as_bytes()),
+            resources,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Resource> {
+        self.resources.iter().find(|resource| resource.name == name)
+    }
+}
+
+/// The first 128 bits of `bytes`' SHA-256 in URL-safe base64.
+fn hash(bytes: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(&digest::digest(&digest::SHA256, bytes).as_ref()[..16])
+}
+
+/// The hash of a resource, ignoring DTSTAMP: some sources set it to the time
+/// of every fetch, which would make clients download unchanged events again.
+fn etag(data: &str) -> String {
+    let compared: String = ics::unfold(data)
+        .lines()
+        .filter(|line| Property::parse(line).is_none_or(|property| property.name != "DTSTAMP"))
+        .collect();
+    hash(compared.as_bytes())
+}
+
+/// A property by namespace and local name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PropName {
+    pub namespace: String,
+    pub name: String,
+}
+
+impl PropName {
+    fn is(&self, namespace: &str, name: &str) -> bool {
+        self.namespace == namespace && self.name == name
+    }
+}
+
+/// The properties a request asks for.
+#[derive(Debug)]
+pub enum Props {
+    /// `allprop`, or an empty PROPFIND body.
+    All,
+    Named(Vec<PropName>),
+}
+
+/// A REPORT this facade answers.
+#[derive(Debug)]
+pub enum Report {
+    /// Resources with an instance in `[start, end)`, or all of them.
+    Query {
+        props: Props,
+        range: Option<(DateTime<Utc>, DateTime<Utc>)>,
+    },
+    /// The resources at `hrefs`.
+    Multiget { props: Props, hrefs: Vec<String> },
+}
+
+/// Why a request body couldn't be answered.
+#[derive(Debug)]
+pub enum RequestError {
+    Malformed,
+    /// A REPORT other than calendar-query or calendar-multiget.
+    UnsupportedReport(String),
+}
+
+struct Tag<'a> {
+    closing: bool,
+    name: PropName,
+    attributes: &'a str,
+    empty: bool,
+}
+
+/// The tags of `body`, with names resolved against the namespaces declared
+/// on them and their ancestors.
+fn tags(body: &str) -> Vec<Tag<'_>> {
+    // Namespace URIs by prefix, with "" for the default, per open element
+    let mut scopes: Vec<HashMap<String, String>> = vec![HashMap::new()];
+    let mut tags = Vec::new();
+    for caps in RE_TAG.captures_iter(body) {
+        let closing = !caps[1].is_empty();
+        let attributes = caps.get(3).map_or("", |m| m.as_str());
+        let empty = !caps[4].is_empty();
+        let mut scope = scopes.last().cloned().unwrap_or_default();
+        if !closing {
+            for declaration in RE_NAMESPACE.captures_iter(attributes) {
+                let prefix = declaration.get(1).map_or("", |m| m.as_str());
+                let uri = declaration.get(2).or(declaration.get(3));
+                scope.insert(
+                    prefix.to_string(),
+                    uri.map_or("", |m| m.as_str()).to_string(),
+                );
+            }
+        }
+        let name = resolve(&caps[2], &scope);
+        if closing {
+            if scopes.len() > 1 {
+                scopes.pop();
+            }
+        } else if !empty {
+            scopes.push(scope);
+        }
+        tags.push(Tag {
+            closing,
+            name,
+            attributes,
+            empty,
+        });
+    }
+    tags
+}
+
+fn resolve(name: &str, namespaces: &HashMap<String, String>) -> PropName {
+    let (prefix, local) = name.split_once(':').unwrap_or(("", name));
+    PropName {
+        namespace: namespaces.get(prefix).cloned().unwrap_or_default(),
+        name: local.to_string(),
+    }
+}
+
+/// The local name of the root element and the properties under its `prop`.
+fn parse(body: &str) -> Result<(String, Props), RequestError> {
+    let tags = tags(body);
+    let Some(root) = tags.first().filter(|tag| !tag.closing) else {
+        return Err(RequestError::Malformed);
+    };
+    let root = root.name.name.clone();
+
+    let mut depth: usize = 0;
+    let mut in_prop = false;
+    let mut names = Vec::new();
+    let mut all = false;
+    for tag in tags {
+        if tag.closing {
+            depth = depth.saturating_sub(1);
+            if depth == 1 {
+                in_prop = false;
+            }
+            continue;
+        }
+        let level = depth;
+        if !tag.empty {
+            depth += 1;
+        }
+        if level == 1 && tag.name.is(DAV, "prop") {
+            in_prop = true;
+        } else if level == 1 && (tag.name.is(DAV, "allprop") || tag.name.is(DAV, "propname")) {
+            all = true;
+        } else if in_prop && level == 2 {
+            names.push(tag.name);
+        }
+    }
+    let props = if all || names.is_empty() {
+        Props::All
+    } else {
+        Props::Named(names)
+    };
+    Ok((root, props))
+}
+
+/// The properties a PROPFIND body asks for; an empty body asks for all.
+pub fn propfind(body: &str) -> Result<Props, RequestError> {
+    if body.trim().is_empty() {
+        return Ok(Props::All);
+    }
+    let (root, props) = parse(body)?;
+    if root != "propfind" {
+        return Err(RequestError::Malformed);
+    }
+    Ok(props)
+}
+
+pub fn report(body: &str) -> Result<Report, RequestError> {
+    let (root, props) = parse(body)?;
+    match root.as_str() {
+        "calendar-query" => {
+            let range = tags(body)
+                .into_iter()
+                .find(|tag| !tag.closing && tag.name.is(CALDAV, "time-range"))
+                .map(|tag| {
+                    let attributes: HashMap<&str, &str> = RE_ATTRIBUTE
+                        .captures_iter(tag.attributes)
+                        .filter_map(|caps| {
+                            let value = caps.get(2).or(caps.get(3))?.as_str();
+                            Some((caps.get(1)?.as_str(), value))
+                        })
+                        .collect();
+                    let bound = |name: &str| {
+                        attributes
+                            .get(name)
+                            .map(|value| parse_utc(value).ok_or(RequestError::Malformed))
+                            .transpose()
+                    };
+                    let now = Utc::now();
+                    let start = bound("start")?.unwrap_or(now - OPEN_RANGE);
+                    let end = bound("end")?.unwrap_or(now + OPEN_RANGE);
+                    Ok((start, end))
+                })
+                .transpose()?;
+            Ok(Report::Query { props, range })
+        }
+        "calendar-multiget" => {
+            let hrefs = RE_HREF
+                .captures_iter(body)
+                .map(|caps| caps[1].trim().to_string())
+                .collect();
+            Ok(Report::Multiget { props, hrefs })
+        }
+        _ => Err(RequestError::UnsupportedReport(root)),
+    }
+}
+
+/// A time-range bound such as `20240501T000000Z`.
+fn parse_utc(value: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(value.trim(), "%Y%m%dT%H%M%SZ")
+        .ok()
+        .map(|naive| naive.and_utc())
+}
+
+/// Whether any instance of `resource` falls in `[start, end)`.
+pub fn in_range(resource: &Resource, start: DateTime<Utc>, end: DateTime<Utc>) -> bool {
+    let text = ics::unfold(&resource.data);
+    let events: Vec<ics::Event> = ics::components(&text, "VEVENT")
+        .into_iter()
+        .map(ics::Event::parse)
+        .collect();
+    !ics::occurrences(&events, start, end).is_empty()
+}
+
+/// What a response describes.
+pub enum Target<'a> {
+    /// The key's home, which is also its principal.
+    Home,
+    /// A group, with its CTag once its events have been merged.
+    Calendar {
+        display_name: &'a str,
+        ctag: Option<&'a str>,
+    },
+    Object(&'a Resource),
+}
+
+impl Target<'_> {
+    /// `prop`'s value as XML, if the target has it.
+    fn value(&self, prop: &PropName, home: &str) -> Option<String> {
+        let href = || format!("<D:href>{}</D:href>", escape_html(home));
+        let value = match (prop.namespace.as_str(), prop.name.as_str(), self) {
+            (DAV, "resourcetype", Target::Home) => "<D:collection/><D:principal/>".to_string(),
+            (DAV, "resourcetype", Target::Calendar { .. }) => {
+                "<D:collection/><C:calendar/>".to_string()
+            }
+            (DAV, "resourcetype", Target::Object(_)) => String::new(),
+            (DAV, "displayname", Target::Home) => "Calendars".to_string(),
+            (DAV, "displayname", Target::Calendar { display_name, .. }) => {
+                escape_html(display_name)
+            }
+            (DAV, "getetag", Target::Object(resource)) => escape_html(&resource.etag),
+            (DAV, "getetag", Target::Calendar { ctag: Some(ctag), .. }) => {
+                escape_html(&format!("\"{}\"", ctag))
+            }
+            (DAV, "getcontenttype", Target::Object(_)) => CONTENT_TYPE.to_string(),
+            (DAV, "getcontentlength", Target::Object(resource)) => resource.data.len().to_string(),
+            (DAV, "current-user-principal" | "principal-URL" | "owner", _) => href(),
+            // Clients show calendars without write privileges as read-only
+            (DAV, "current-user-privilege-set", _) => {
+                "<D:privilege><D:read/></D:privilege>".to_string()
+            }
+            (DAV, "supported-report-set", Target::Calendar { .. }) => {
+                "<D:supported-report><D:report><C:calendar-query/></D:report></D:supported-report>\
+                 <D:supported-report><D:report><C:calendar-multiget/></D:report></D:supported-report>"
+                    .to_string()
+            }
+            (CALDAV, "calendar-home-set", Target::Home | Target::Calendar { .. }) => href(),
+            (CALDAV, "supported-calendar-component-set", Target::Calendar { .. }) => {
+                "<C:comp name=\"VEVENT\"/>".to_string()
+            }
+            (CALDAV, "calendar-data", Target::Object(resource)) => escape_html(&resource.data),
+            (CALENDARSERVER, "getctag", Target::Calendar { ctag: Some(ctag), .. }) => {
+                ctag.to_string()
+            }
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// The properties listed for `allprop`.
+    fn all(&self) -> Vec<PropName> {
+        let dav = |name: &str| PropName {
+            namespace: DAV.to_string(),
+            name: name.to_string(),
+        };
+        let mut names = vec![dav("resourcetype"), dav("displayname"), dav("getetag")];
+        match self {
+            Target::Object(_) => {
+                names.push(dav("getcontenttype"));
+                names.push(dav("getcontentlength"));
+            }
+            Target::Calendar { .. } => names.push(PropName {
+                namespace: CALENDARSERVER.to_string(),
+                name: "getctag".to_string(),
+            }),
+            Target::Home => {}
+        }
+        names
+    }
+}
+
+/// A `207 Multi-Status` body.
+pub struct Multistatus {
+    output: String,
+    /// The href of the key's home, for principal properties.
+    home: String,
+}
+
+impl Multistatus {
+    pub fn new(home: &str) -> Self {
+        let mut output = String::new();
+        output.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+        output.push_str(&format!(
+            "<D:multistatus xmlns:D=\"{}\" xmlns:C=\"{}\" xmlns:CS=\"{}\">\n",
+            DAV, CALDAV, CALENDARSERVER
+        ));
+        Self {
+            output,
+            home: home.to_string(),
+        }
+    }
+
+    /// Describes `target` at `href`, with the properties it has as found and
+    /// the others as not found.
+    pub fn add(&mut self, href: &str, target: &Target, props: &Props) {
+        let names = match props {
+            Props::All => target.all(),
+            Props::Named(names) => names.clone(),
+        };
+        let mut found = String::new();
+        let mut missing = String::new();
+        for name in &names {
+            match target.value(name, &self.home) {
+                Some(value) if value.is_empty() => found.push_str(&element(name, None)),
+                Some(value) => found.push_str(&element(name, Some(&value))),
+                None => missing.push_str(&element(name, None)),
+            }
+        }
+        self.output.push_str("<D:response>\n");
+        self.output
+            .push_str(&format!("<D:href>{}</D:href>\n", escape_html(href)));
+        for (props, status) in [(found, "200 OK"), (missing, "404 Not Found")] {
+            if !props.is_empty() {
+                self.output.push_str(&format!(
+                    "<D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 {}</D:status></D:propstat>\n",
+                    props, status
+                ));
+            }
+        }
+        self.output.push_str("</D:response>\n");
+    }
+
+    /// Reports that nothing is at `href`.
+    pub fn add_missing(&mut self, href: &str) {
+        self.output.push_str(&format!(
+            "<D:response>\n<D:href>{}</D:href>\n<D:status>HTTP/1.1 404 Not Found</D:status>\n</D:response>\n",
+            escape_html(href)
+        ));
+    }
+
+    pub fn finish(mut self) -> String {
+        self.output.push_str("</D:multistatus>\n");
+        self.output
+    }
+}
+
+/// `name` as an element, using the prefixes declared on the multistatus root
+/// for known namespaces.
+fn element(name: &PropName, value: Option<&str>) -> String {
+    let (open, close) = match name.namespace.as_str() {
+        DAV => (format!("D:{}", name.name), format!("D:{}", name.name)),
+        CALDAV => (format!("C:{}", name.name), format!("C:{}", name.name)),
+        CALENDARSERVER => (format!("CS:{}", name.name), format!("CS:{}", name.name)),
+        namespace => (
+            format!("{} xmlns=\"{}\"", name.name, escape_html(namespace)),
+            name.name.clone(),
+        ),
+    };
+    match value {
+        Some(value) => format!("<{}>{}</{}>", open, value, close),
+        None => format!("<{}/>", open),
+    }
+}
+
+/// The body of a 403 for an unsupported REPORT (RFC 3253).
+pub fn unsupported_report() -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:error xmlns:D=\"{}\"><D:supported-report/></D:error>\n",
+        DAV
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn end_only_time_range_matches_recurring_events() {
+        let body = r#"<?xml version="1.0"?>
+<C:calendar-query xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">
+  <D:prop><D:getetag/></D:prop>
+  <C:filter>
+    <C:comp-filter name="VCALENDAR">
+      <C:comp-filter name="VEVENT">
+        <C:time-range end="20240601T000000Z"/>
+      </C:comp-filter>
+    </C:comp-filter>
+  </C:filter>
+</C:calendar-query>"#;
+        let Ok(Report::Query {
+            range: Some((start, end)),
+            ..
+        }) = report(body)
+        else {
+            panic!("expected a calendar-query with a time-range");
+        };
+        let resource = Resource {
+            name: "a.ics".to_string(),
+            etag: String::new(),
+            data: "BEGIN:VCALENDAR\nBEGIN:VEVENT\nUID:a\nDTSTART:20240501T090000Z\nDTEND:20240501T100000Z\nRRULE:FREQ=WEEKLY\nEND:VEVENT\nEND:VCALENDAR".to_string(),
+        };
+
+        assert!(in_range(&resource, start, end));
+        let before = parse_utc("20240401T000000Z").unwrap();
+        assert!(!in_range(&resource, start, before));
+    }
+}
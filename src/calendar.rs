@@ -1,167 +1,1995 @@
-use crate::config::SourceCalendar;
-use anyhow::{Context, Result};
+use crate::attachments::{self, Attachment, AttachmentPolicy};
+use crate::config::{Attribution, CalendarGroup, SourceCalendar, DIRECT_PROXY};
+use crate::freebusy;
+use crate::ics;
+use crate::logging;
+use crate::pinning;
+use crate::pipeline::{self, FetchedCalendar, ParsedCalendar, PipelineMetrics, ShadowEffect};
+use crate::redis;
+use crate::synthetic;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
 use moka::future::Cache;
-use regex::Regex;
-use std::sync::Arc;
-use std::time::Duration;
+use moka::notification::RemovalCause;
+use reqwest::Method;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::io::Read;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
 use tokio::time::timeout;
+pub use webcal_combiner::api::{
+    CacheEntrySource, CacheEntryStats, CacheStats, ErrorKind, ErrorStats, EvictionStats,
+    ExplainStep, Explanation, FetchOutcome, ShadowStats, SourceStatus, Stage, StageStats,
+};
+
+/// Calendar-level properties the combiner always writes itself; sources can never
+/// override them, even through `passthrough_properties`.
+pub const COMBINER_PROPERTIES: &[&str] = &["PRODID", "VERSION", "NAME", "X-WR-CALNAME"];
+
+/// A single expanded event instance, tagged with the source it came from.
+#[derive(Debug, Clone)]
+pub struct AgendaItem {
+    pub source: String,
+    pub uid: Option<String>,
+    pub summary: String,
+    pub location: Option<String>,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub all_day: bool,
+}
+
+/// An upstream body along with when it was fetched and the response's status.
+#[derive(Clone)]
+struct CachedCalendar {
+    body: String,
+    fetched_at: SystemTime,
+    status: reqwest::StatusCode,
+    /// Index of the mirror that served the body, if it wasn't the main URL.
+    mirror: Option<usize>,
+    /// How long the cache keeps the body, set from the source's
+    /// `cache_ttl_seconds` when it's cached.
+    ttl: Duration,
+    /// How long upstream's caching headers say the body stays fresh, within
+    /// the bounds of `upstream_cache_control` when that is set.
+    declared_ttl: Option<Duration>,
+}
+
+/// Expires each cache entry after its own `ttl`, restarting the clock when a
+/// refresh replaces it.
+struct CacheExpiry;
+
+impl moka::Expiry<String, CachedCalendar> for CacheExpiry {
+    fn expire_after_create(
+        &self,
+        _url: &String,
+        cached: &CachedCalendar,
+        _created_at: Instant,
+    ) -> Option<Duration> {
+        Some(cached.ttl)
+    }
+
+    fn expire_after_update(
+        &self,
+        _url: &String,
+        cached: &CachedCalendar,
+        _updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        Some(cached.ttl)
+    }
+}
+
+/// Where cached source bodies live: in this process, or with `redis_cache` in
+/// Redis, shared by every instance. Redis failures are logged and treated as
+/// misses, so an outage only costs extra upstream fetches.
+enum CacheBackend {
+    Memory(Cache<String, CachedCalendar>),
+    Redis {
+        client: redis::Client,
+        prefix: String,
+    },
+}
+
+/// A `CachedCalendar` as stored in Redis.
+#[derive(Serialize, Deserialize)]
+struct StoredCalendar {
+    body: String,
+    fetched_at: SystemTime,
+    status: u16,
+    mirror: Option<usize>,
+    ttl: Duration,
+}
+
+impl CacheBackend {
+    async fn get(&self, url: &str) -> Option<CachedCalendar> {
+        match self {
+            CacheBackend::Memory(cache) => cache.get(url).await,
+            CacheBackend::Redis { client, prefix } => {
+                let stored = client
+                    .get(&format!("{}{}", prefix, url))
+                    .await
+                    .inspect_err(|e| redis_warning("read", e))
+                    .ok()??;
+                let stored: StoredCalendar = serde_json::from_slice(&stored).ok()?;
+                Some(CachedCalendar {
+                    body: stored.body,
+                    fetched_at: stored.fetched_at,
+                    status: reqwest::StatusCode::from_u16(stored.status).ok()?,
+                    mirror: stored.mirror,
+                    ttl: stored.ttl,
+                    declared_ttl: None,
+                })
+            }
+        }
+    }
+
+    async fn insert(&self, url: &str, cached: CachedCalendar) {
+        match self {
+            CacheBackend::Memory(cache) => cache.insert(url.to_string(), cached).await,
+            CacheBackend::Redis { client, prefix } => {
+                let ttl = cached.ttl;
+                let stored = StoredCalendar {
+                    body: cached.body,
+                    fetched_at: cached.fetched_at,
+                    status: cached.status.as_u16(),
+                    mirror: cached.mirror,
+                    ttl,
+                };
+                let value = serde_json::to_vec(&stored).expect("cache entries serialize");
+                if let Err(e) = client.set(&format!("{}{}", prefix, url), &value, ttl).await {
+                    redis_warning("write", &e);
+                }
+            }
+        }
+    }
+
+    /// Removes `url`'s entry, returning whether there was one.
+    async fn remove(&self, url: &str) -> bool {
+        match self {
+            CacheBackend::Memory(cache) => cache.remove(url).await.is_some(),
+            CacheBackend::Redis { client, prefix } => client
+                .del(&format!("{}{}", prefix, url))
+                .await
+                .inspect_err(|e| redis_warning("purge", e))
+                .unwrap_or(false),
+        }
+    }
+
+    /// Every live entry, with its URL.
+    async fn entries(&self) -> Vec<(String, CachedCalendar)> {
+        match self {
+            CacheBackend::Memory(cache) => {
+                // Flush pending expirations so the entries are current
+                cache.run_pending_tasks().await;
+                cache
+                    .iter()
+                    .map(|(url, cached)| (url.as_ref().clone(), cached))
+                    .collect()
+            }
+            CacheBackend::Redis { client, prefix } => {
+                let keys = match client.keys(prefix).await {
+                    Ok(keys) => keys,
+                    Err(e) => {
+                        redis_warning("list", &e);
+                        return Vec::new();
+                    }
+                };
+                let mut entries = Vec::new();
+                for key in keys {
+                    let url = key[prefix.len()..].to_string();
+                    if let Some(cached) = self.get(&url).await {
+                        entries.push((url, cached));
+                    }
+                }
+                entries
+            }
+        }
+    }
+
+    /// Removes every entry, returning their URLs.
+    async fn clear(&self) -> Vec<String> {
+        let urls: Vec<String> = self
+            .entries()
+            .await
+            .into_iter()
+            .map(|(url, _)| url)
+            .collect();
+        match self {
+            CacheBackend::Memory(cache) => cache.invalidate_all(),
+            CacheBackend::Redis { .. } => {
+                for url in &urls {
+                    self.remove(url).await;
+                }
+            }
+        }
+        urls
+    }
+}
+
+/// Builds a client for upstream fetches through `proxy`: a proxy URL,
+/// `DIRECT_PROXY`, or `None` for the environment's HTTPS_PROXY, HTTP_PROXY and
+/// NO_PROXY. NO_PROXY also applies to an explicit proxy. `ca_bundle` is
+/// trusted on top of the system roots. `source` is the source the client is
+/// for, if it has TLS settings of its own: `insecure_skip_verify` accepts any
+/// certificate, and its client identity is presented to servers that ask for
+/// one. A source with `spki_sha256` gets a rustls client that checks the pins
+/// during the handshake.
+fn http_client(
+    config: &crate::config::Config,
+    proxy: Option<&str>,
+    source: Option<&SourceCalendar>,
+) -> reqwest::Client {
+    let insecure = source.is_some_and(SourceCalendar::insecure_skip_verify);
+    let mut builder = reqwest::Client::builder()
+        .timeout(Duration::from_secs(config.request_timeout_seconds))
+        .danger_accept_invalid_certs(insecure);
+    for certificate in &config.ca_certificates {
+        builder = builder.add_root_certificate(certificate.clone());
+    }
+    if let Some(identity) = source.and_then(|cal| cal.client_identity.as_ref()) {
+        builder = builder.identity(identity.clone());
+    }
+    if let Some(cal) = source.filter(|cal| !cal.spki_sha256().is_empty()) {
+        let identity = cal
+            .client_pem
+            .as_ref()
+            .map(|(cert, key)| (cert.as_slice(), key.as_slice()));
+        let tls = pinning::tls_config(
+            cal.spki_sha256(),
+            insecure,
+            config.ca_bundle_pem.as_deref(),
+            identity,
+        )
+        .expect("client identity checked when loading the config");
+        builder = builder.use_preconfigured_tls(tls);
+    }
+    match proxy {
+        None => {}
+        Some(DIRECT_PROXY) => builder = builder.no_proxy(),
+        Some(proxy) => {
+            let proxy = reqwest::Proxy::all(proxy)
+                .expect("proxy checked when loading the config")
+                .no_proxy(reqwest::NoProxy::from_env());
+            builder = builder.proxy(proxy);
+        }
+    }
+    builder.build().expect("Failed to create HTTP client")
+}
+
+/// The in-memory cache's capacity and entry weigher under `cache_max_entries`
+/// and `cache_max_bytes`. moka has a single capacity, so with both set every
+/// entry weighs at least its share of `cache_max_bytes`, which also keeps the
+/// entry count within `cache_max_entries`.
+fn cache_capacity(
+    config: &crate::config::Config,
+) -> Option<(u64, impl Fn(&String, &CachedCalendar) -> u32)> {
+    let (capacity, min_weight, by_size) = match (config.cache_max_entries, config.cache_max_bytes) {
+        (None, None) => return None,
+        (Some(entries), None) => (entries, 1, false),
+        (None, Some(bytes)) => (bytes, 1, true),
+        (Some(entries), Some(bytes)) => (bytes, bytes.div_ceil(entries), true),
+    };
+    let weigher = move |url: &String, cached: &CachedCalendar| {
+        let size = if by_size {
+            (url.len() + cached.body.len()) as u64
+        } else {
+            0
+        };
+        u32::try_from(size.max(min_weight)).unwrap_or(u32::MAX)
+    };
+    Some((capacity, weigher))
+}
+
+fn redis_warning(operation: &str, error: &redis::RedisError) {
+    tracing::warn!(
+        "Redis cache {} failed, continuing without it: {}",
+        operation,
+        logging::error_chain(error)
+    );
+}
+
+/// Counters for cache lookups and evictions, updated from the request path and
+/// moka's eviction listener.
+#[derive(Default)]
+struct CacheMetrics {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    expired: AtomicU64,
+    size_evicted: AtomicU64,
+    explicit: AtomicU64,
+    replaced: AtomicU64,
+}
+
+/// A source body as returned by `fetch_calendar`.
+struct FetchedBody {
+    upstream: CachedCalendar,
+    outcome: FetchOutcome,
+    /// Why the refresh failed, when a stale copy is served.
+    refresh_error: Option<String>,
+}
+
+/// Why an upstream fetch failed.
+#[derive(Debug, thiserror::Error)]
+pub enum FetchError {
+    #[error("Request to {url} timed out after {seconds}s")]
+    Timeout { url: String, seconds: u64 },
+    #[error("Failed to send request to {url}")]
+    Request {
+        url: String,
+        #[source]
+        error: reqwest::Error,
+    },
+    #[error("HTTP error: {status} for URL: {url}")]
+    Status {
+        url: String,
+        status: reqwest::StatusCode,
+    },
+    #[error("Failed to read response body from {url}")]
+    Body {
+        url: String,
+        #[source]
+        error: reqwest::Error,
+    },
+    #[error("Response body from {url} exceeds max_source_bytes ({limit} bytes), aborted")]
+    TooLarge { url: String, limit: u64 },
+    #[error("Failed to decompress response body from {url}")]
+    Decompress {
+        url: String,
+        #[source]
+        error: std::io::Error,
+    },
+    #[error("Certificate of {url} doesn't match its pinned keys (got {found})")]
+    PinMismatch { url: String, found: String },
+    #[error("Skipping {url} for {seconds}s after {failures} consecutive failures")]
+    CircuitOpen {
+        url: String,
+        failures: u32,
+        seconds: u64,
+    },
+    #[error("Not retrying {url} for {seconds}s after it failed: {error}")]
+    RecentlyFailed {
+        url: String,
+        seconds: u64,
+        error: String,
+    },
+}
+
+impl FetchError {
+    /// The source's URL, redacted for logging.
+    pub fn url(&self) -> &str {
+        match self {
+            FetchError::Timeout { url, .. }
+            | FetchError::Request { url, .. }
+            | FetchError::Status { url, .. }
+            | FetchError::Body { url, .. }
+            | FetchError::TooLarge { url, .. }
+            | FetchError::Decompress { url, .. }
+            | FetchError::PinMismatch { url, .. }
+            | FetchError::CircuitOpen { url, .. }
+            | FetchError::RecentlyFailed { url, .. } => url,
+        }
+    }
+}
+
+/// How long `headers` say a response stays fresh: its `s-maxage` or `max-age`
+/// less its `Age`, or else its `Expires` less its `Date`. `no-store` and
+/// `no-cache` mean not at all.
+fn declared_ttl(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    use reqwest::header::{AGE, CACHE_CONTROL, DATE, EXPIRES};
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    let http_date = |value: &str| {
+        DateTime::parse_from_rfc2822(value)
+            .ok()
+            .map(|date| date.with_timezone(&Utc))
+    };
+
+    if let Some(cache_control) = header(CACHE_CONTROL) {
+        let mut max_age = None;
+        let mut shared_max_age = None;
+        for directive in cache_control.split(',') {
+            let directive = directive.trim().to_ascii_lowercase();
+            match directive.split_once('=') {
+                Some(("max-age", seconds)) => max_age = seconds.trim_matches('"').parse().ok(),
+                Some(("s-maxage", seconds)) => {
+                    shared_max_age = seconds.trim_matches('"').parse().ok()
+                }
+                None if directive == "no-store" || directive == "no-cache" => {
+                    return Some(Duration::ZERO)
+                }
+                _ => {}
+            }
+        }
+        // s-maxage is meant for shared caches like this one
+        if let Some(seconds) = shared_max_age.or(max_age) {
+            let age: u64 = header(AGE).and_then(|age| age.parse().ok()).unwrap_or(0);
+            return Some(Duration::from_secs(u64::saturating_sub(seconds, age)));
+        }
+    }
+
+    let expires = header(EXPIRES)?;
+    let date = header(DATE).and_then(http_date).unwrap_or_else(Utc::now);
+    // An invalid date, such as "0", means already expired
+    let expires = http_date(expires).unwrap_or(date);
+    Some((expires - date).to_std().unwrap_or_default())
+}
+
+/// The first bytes of a gzip stream.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Reads a body of at most `limit` bytes, decoded by its charset like
+/// `Response::text`. A bigger body fails once it passes the limit, before it
+/// is held in memory. Declared compression is undone by reqwest as the body
+/// streams in, so the limit applies to the decompressed size.
+async fn read_body(
+    mut response: reqwest::Response,
+    limit: u64,
+    redacted: &str,
+    seconds: u64,
+) -> Result<String, FetchError> {
+    let too_large = || FetchError::TooLarge {
+        url: redacted.to_string(),
+        limit,
+    };
+    if response.content_length().unwrap_or(0) > limit {
+        return Err(too_large());
+    }
+    let encoding = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| {
+            value.split(';').skip(1).find_map(|param| {
+                let (name, charset) = param.split_once('=')?;
+                name.trim()
+                    .eq_ignore_ascii_case("charset")
+                    .then(|| charset.trim().trim_matches('"').to_string())
+            })
+        })
+        .and_then(|charset| encoding_rs::Encoding::for_label(charset.as_bytes()))
+        .unwrap_or(encoding_rs::UTF_8);
+
+    let mut body = Vec::new();
+    loop {
+        let chunk = response.chunk().await.map_err(|error| {
+            if error.is_timeout() {
+                FetchError::Timeout {
+                    url: redacted.to_string(),
+                    seconds,
+                }
+            } else {
+                FetchError::Body {
+                    url: redacted.to_string(),
+                    error: error.without_url(),
+                }
+            }
+        })?;
+        let Some(chunk) = chunk else {
+            break;
+        };
+        if (body.len() + chunk.len()) as u64 > limit {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    // Servers often serve a pre-compressed feed.ics.gz without declaring a
+    // Content-Encoding, or compress an already compressed file once more
+    if body.starts_with(&GZIP_MAGIC) {
+        tracing::debug!(
+            "Body from {} is still gzip-compressed, decompressing",
+            redacted
+        );
+        let mut decompressed = Vec::new();
+        flate2::read::MultiGzDecoder::new(body.as_slice())
+            .take(limit + 1)
+            .read_to_end(&mut decompressed)
+            .map_err(|error| FetchError::Decompress {
+                url: redacted.to_string(),
+                error,
+            })?;
+        if decompressed.len() as u64 > limit {
+            return Err(too_large());
+        }
+        body = decompressed;
+    }
+    Ok(encoding.decode(&body).0.into_owned())
+}
+
+/// Why the service couldn't produce output for a group.
+#[derive(Debug, thiserror::Error)]
+pub enum CalendarError {
+    #[error("Failed to fetch calendar: {calendar}")]
+    Fetch {
+        calendar: String,
+        #[source]
+        error: FetchError,
+    },
+    #[error("Fetch task failed")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+impl CalendarError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            CalendarError::Fetch {
+                error: FetchError::Timeout { .. },
+                ..
+            } => ErrorKind::UpstreamTimeout,
+            CalendarError::Fetch {
+                error: FetchError::PinMismatch { .. },
+                ..
+            } => ErrorKind::PinMismatch,
+            CalendarError::Fetch { .. } => ErrorKind::UpstreamError,
+            CalendarError::Task(_) => ErrorKind::Internal,
+        }
+    }
+}
+
+/// Error counts, indexed by `ErrorKind`.
+#[derive(Default)]
+struct ErrorMetrics {
+    counts: [AtomicU64; ErrorKind::ALL.len()],
+}
+
+/// Affected UIDs kept per shadow rule for `/status` and its log lines.
+const MAX_SHADOW_UIDS: usize = 20;
+
+/// Bytes of attachments kept for `/attachment` by `AttachmentPolicy::Link`.
+const ATTACHMENT_CAPACITY: u64 = 64 * 1024 * 1024;
+/// How long an attachment nobody requests is kept. One evicted earlier is
+/// found again by regenerating its group.
+const ATTACHMENT_IDLE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The latest evaluation of every rule in shadow mode, by group, source and rule.
+#[derive(Default)]
+struct ShadowMetrics {
+    rules: Mutex<BTreeMap<(String, String, String), ShadowStats>>,
+}
+
+/// Consecutive failures of an upstream URL, and until when it's skipped.
+#[derive(Default)]
+struct Breaker {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+/// Circuit breakers of the URLs that are currently failing, so a dead source
+/// doesn't cost every request a full timeout. Used with `circuit_breaker`.
+#[derive(Default)]
+struct CircuitBreakers {
+    urls: Mutex<HashMap<String, Breaker>>,
+}
+
+/// When each source URL or mirror last failed, so failover tries the URLs that
+/// have been failing last for `failover_cooldown_seconds`.
+#[derive(Default)]
+struct FailoverMemory {
+    failed_at: Mutex<HashMap<String, Instant>>,
+}
+
+/// When each failing source last failed and why, so requests within
+/// `negative_cache_seconds` fail at once instead of waiting on it again.
+#[derive(Default)]
+struct FailureCache {
+    failed: Mutex<HashMap<String, (Instant, String)>>,
+}
+
+/// A source URL's body at its latest fetch, and how long it's cached for.
+struct PollInterval {
+    fingerprint: u64,
+    ttl: Duration,
+}
+
+/// The cache TTL learnt for each source URL with `adaptive_refresh` or
+/// `upstream_cache_control`.
+#[derive(Default)]
+struct ChangeRates {
+    urls: Mutex<HashMap<String, PollInterval>>,
+}
+
+/// The latest fetch of a source and what parsing its body turned up.
+struct SourceRecord {
+    attempted_at: SystemTime,
+    outcome: FetchOutcome,
+    fetched_at: Option<SystemTime>,
+    http_status: Option<u16>,
+    mirror: Option<usize>,
+    bytes: Option<usize>,
+    error: Option<String>,
+    parse_warnings: Vec<String>,
+}
+
+/// The latest fetch of every source, by group and source, for `/status`.
+#[derive(Default)]
+struct SourceRegistry {
+    sources: Mutex<HashMap<(String, String), SourceRecord>>,
+}
+
+/// The merged output of a group, plus the sources that were served stale.
+pub struct CombinedCalendar {
+    pub data: String,
+    pub stale_sources: Vec<String>,
+}
 
 #[derive(Clone)]
 pub struct CalendarService {
     client: reqwest::Client,
-    cache: Option<Arc<Cache<String, String>>>,
+    /// Clients of sources with their own `proxy`, by URL and mirror.
+    source_clients: Arc<HashMap<String, reqwest::Client>>,
+    cache: Option<Arc<CacheBackend>>,
+    cache_metrics: Arc<CacheMetrics>,
+    pipeline_metrics: Arc<PipelineMetrics>,
+    error_metrics: Arc<ErrorMetrics>,
+    shadow_metrics: Arc<ShadowMetrics>,
+    source_registry: Arc<SourceRegistry>,
+    breakers: Arc<CircuitBreakers>,
+    failover: Arc<FailoverMemory>,
+    change_rates: Arc<ChangeRates>,
+    failure_cache: Arc<FailureCache>,
+    /// Bounds upstream fetches in flight with `max_concurrent_fetches`.
+    fetch_permits: Option<Arc<Semaphore>>,
+    /// Payloads of inline attachments replaced by links, by ID.
+    attachments: Cache<String, Arc<Attachment>>,
+    /// Signs the links to `attachments`.
+    attachment_key: Arc<hmac::Key>,
     config: Arc<crate::config::Config>,
 }
 
 impl CalendarService {
     pub fn new(enable_cache: bool, config: Arc<crate::config::Config>) -> Self {
-        let client = reqwest::Client::builder()
-            .timeout(Duration::from_secs(config.request_timeout_seconds))
-            .build()
-            .expect("Failed to create HTTP client");
-
-        let cache = if enable_cache {
-            Some(Arc::new(
-                Cache::builder()
-                    .time_to_live(Duration::from_secs(config.cache_ttl_seconds))
-                    .build(),
-            ))
-        } else {
+        let client = http_client(&config, config.proxy.as_deref(), None);
+        let mut clients = HashMap::new();
+        let mut source_clients = HashMap::new();
+        for cal in config.calendars.iter().flat_map(|group| &group.calendars) {
+            let pins = cal.spki_sha256();
+            if cal.proxy.is_none()
+                && !cal.insecure_skip_verify()
+                && cal.client_cert.is_none()
+                && pins.is_empty()
+            {
+                continue;
+            }
+            let proxy = cal.proxy.as_deref().or(config.proxy.as_deref());
+            let insecure = cal.insecure_skip_verify();
+            let identity = (cal.client_cert.as_deref(), cal.client_key.as_deref());
+            let client = clients
+                .entry((proxy, insecure, identity, pins))
+                .or_insert_with(|| http_client(&config, proxy, Some(cal)));
+            for url in std::iter::once(&cal.url).chain(&cal.mirrors) {
+                // A pinned source's client wins, so sharing its URL with
+                // another source can't skip the pin check
+                if pins.is_empty() {
+                    source_clients
+                        .entry(url.clone())
+                        .or_insert_with(|| client.clone());
+                } else {
+                    source_clients.insert(url.clone(), client.clone());
+                }
+            }
+        }
+
+        let cache_metrics = Arc::new(CacheMetrics::default());
+
+        let cache = if !enable_cache {
             None
+        } else if let Some(ref redis) = config.redis_cache {
+            let timeout = Duration::from_millis(redis.timeout_ms);
+            let client = redis::Client::new(&redis.url, timeout)
+                .expect("Redis URL checked when loading the config");
+            Some(Arc::new(CacheBackend::Redis {
+                client,
+                prefix: redis.key_prefix.clone(),
+            }))
+        } else {
+            let metrics = Arc::clone(&cache_metrics);
+            let mut builder = Cache::builder();
+            if let Some((capacity, weigher)) = cache_capacity(&config) {
+                builder = builder.max_capacity(capacity).weigher(weigher);
+            }
+            Some(Arc::new(CacheBackend::Memory(
+                builder
+                    .expire_after(CacheExpiry)
+                    .eviction_listener(move |url: Arc<String>, _, cause| {
+                        let counter = match cause {
+                            RemovalCause::Expired => &metrics.expired,
+                            RemovalCause::Size => &metrics.size_evicted,
+                            RemovalCause::Explicit => &metrics.explicit,
+                            RemovalCause::Replaced => &metrics.replaced,
+                        };
+                        counter.fetch_add(1, Ordering::Relaxed);
+                        tracing::debug!(
+                            "Cache entry for URL {} removed: {:?}",
+                            logging::redact_url(&url),
+                            cause
+                        );
+                    })
+                    .build(),
+            )))
         };
 
-        Self { client, cache, config }
+        Self {
+            client,
+            source_clients: Arc::new(source_clients),
+            cache,
+            cache_metrics,
+            pipeline_metrics: Arc::new(PipelineMetrics::default()),
+            error_metrics: Arc::new(ErrorMetrics::default()),
+            shadow_metrics: Arc::new(ShadowMetrics::default()),
+            source_registry: Arc::new(SourceRegistry::default()),
+            breakers: Arc::new(CircuitBreakers::default()),
+            failover: Arc::new(FailoverMemory::default()),
+            change_rates: Arc::new(ChangeRates::default()),
+            failure_cache: Arc::new(FailureCache::default()),
+            fetch_permits: config
+                .max_concurrent_fetches
+                .map(|permits| Arc::new(Semaphore::new(permits))),
+            attachments: Cache::builder()
+                .max_capacity(ATTACHMENT_CAPACITY)
+                .weigher(|_, attachment: &Arc<Attachment>| {
+                    attachment.bytes.len().try_into().unwrap_or(u32::MAX)
+                })
+                .time_to_idle(ATTACHMENT_IDLE)
+                .build(),
+            attachment_key: Arc::new(attachments::signing_key(config.signing_secret.as_deref())),
+            config,
+        }
+    }
+
+    /// Run counts and timings of every merge pipeline stage.
+    pub fn pipeline_stats(&self) -> Vec<StageStats> {
+        self.pipeline_metrics.stats()
+    }
+
+    /// How often each kind of error has been returned.
+    pub fn error_stats(&self) -> Vec<ErrorStats> {
+        ErrorKind::ALL
+            .iter()
+            .map(|&kind| ErrorStats {
+                kind,
+                count: self.error_metrics.counts[kind as usize].load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// The latest evaluation of every rule in shadow mode.
+    pub fn shadow_stats(&self) -> Vec<ShadowStats> {
+        let rules = self.shadow_metrics.rules.lock().unwrap();
+        rules.values().cloned().collect()
+    }
+
+    /// Every configured source with its latest fetch, in config order. Sources
+    /// that haven't been requested yet have no outcome.
+    pub fn source_status(&self) -> Vec<SourceStatus> {
+        let sources = self.source_registry.sources.lock().unwrap();
+        let now = SystemTime::now();
+        let seconds_ago = |time: SystemTime| now.duration_since(time).unwrap_or_default().as_secs();
+        self.config
+            .calendars
+            .iter()
+            .flat_map(|group| group.calendars.iter().map(move |cal| (group, cal)))
+            .map(|(group, cal)| {
+                let key = (group.name.clone(), cal.name.clone());
+                let record = sources.get(&key);
+                SourceStatus {
+                    group: group.name.clone(),
+                    source: cal.name.clone(),
+                    outcome: record.map(|r| r.outcome),
+                    last_fetch_seconds_ago: record.map(|r| seconds_ago(r.attempted_at)),
+                    body_age_seconds: record.and_then(|r| r.fetched_at).map(seconds_ago),
+                    http_status: record.and_then(|r| r.http_status),
+                    mirror: record.and_then(|r| r.mirror),
+                    bytes: record.and_then(|r| r.bytes),
+                    error: record.and_then(|r| r.error.clone()),
+                    parse_warnings: record.map(|r| r.parse_warnings.clone()).unwrap_or_default(),
+                }
+            })
+            .collect()
+    }
+
+    /// Records the outcome of fetching a source for `/status`. A failure forgets
+    /// the parse warnings, since there's no body they apply to.
+    fn record_fetch(&self, group: &str, source: &str, result: &Result<FetchedBody, FetchError>) {
+        let mut sources = self.source_registry.sources.lock().unwrap();
+        let key = (group.to_string(), source.to_string());
+        let parse_warnings = match (result, sources.remove(&key)) {
+            (Ok(_), Some(previous)) => previous.parse_warnings,
+            _ => Vec::new(),
+        };
+        let record = match result {
+            Ok(fetched) => SourceRecord {
+                attempted_at: SystemTime::now(),
+                outcome: fetched.outcome,
+                fetched_at: Some(fetched.upstream.fetched_at),
+                http_status: Some(fetched.upstream.status.as_u16()),
+                mirror: fetched.upstream.mirror,
+                bytes: Some(fetched.upstream.body.len()),
+                error: fetched.refresh_error.clone(),
+                parse_warnings,
+            },
+            Err(error) => SourceRecord {
+                attempted_at: SystemTime::now(),
+                outcome: FetchOutcome::Failed,
+                fetched_at: None,
+                http_status: match error {
+                    FetchError::Status { status, .. } => Some(status.as_u16()),
+                    _ => None,
+                },
+                mirror: None,
+                bytes: None,
+                error: Some(logging::error_chain(error)),
+                parse_warnings,
+            },
+        };
+        sources.insert(key, record);
+    }
+
+    /// Keeps the warnings from parsing a source's latest body.
+    fn record_parse_warnings(&self, group: &str, source: &str, warnings: Vec<String>) {
+        let mut sources = self.source_registry.sources.lock().unwrap();
+        if let Some(record) = sources.get_mut(&(group.to_string(), source.to_string())) {
+            if !warnings.is_empty() && warnings != record.parse_warnings {
+                tracing::warn!(
+                    "Parsing '{}' in '{}' found problems: {}",
+                    source,
+                    group,
+                    warnings.join("; ")
+                );
+            }
+            record.parse_warnings = warnings;
+        }
+    }
+
+    /// Keeps the latest shadow evaluations of a group, logging whenever what a
+    /// rule would do changes so refreshes don't repeat the same line.
+    fn record_shadow(&self, group: &CalendarGroup, effects: Vec<ShadowEffect>) {
+        let mut rules = self.shadow_metrics.rules.lock().unwrap();
+        for effect in effects {
+            let key = (
+                group.name.clone(),
+                effect.source.clone(),
+                effect.rule.clone(),
+            );
+            let stats = rules.entry(key).or_insert_with(|| ShadowStats {
+                group: group.name.clone(),
+                source: effect.source.clone(),
+                rule: effect.rule.clone(),
+                evaluations: 0,
+                would_drop: 0,
+                would_change: 0,
+                uids: Vec::new(),
+                reason: None,
+            });
+            let uids: Vec<String> = effect.uids.into_iter().take(MAX_SHADOW_UIDS).collect();
+            let affects = effect.dropped + effect.changed > 0;
+            let differs = stats.would_drop != effect.dropped
+                || stats.would_change != effect.changed
+                || stats.uids != uids;
+            if affects && (differs || stats.evaluations == 0) {
+                tracing::info!(
+                    "Shadow rule {} on '{}' in '{}' would drop {} and change {} event(s){}: {}",
+                    effect.rule,
+                    effect.source,
+                    group.name,
+                    effect.dropped,
+                    effect.changed,
+                    effect
+                        .reason
+                        .as_ref()
+                        .map(|reason| format!(" ({})", reason))
+                        .unwrap_or_default(),
+                    uids.join(", ")
+                );
+            } else if differs {
+                tracing::info!(
+                    "Shadow rule {} on '{}' in '{}' no longer changes anything",
+                    effect.rule,
+                    effect.source,
+                    group.name
+                );
+            }
+
+            stats.evaluations += 1;
+            stats.would_drop = effect.dropped;
+            stats.would_change = effect.changed;
+            stats.uids = uids;
+            stats.reason = effect.reason;
+        }
+    }
+
+    /// Snapshot of cache counters and the age of every live entry.
+    pub async fn cache_stats(&self) -> CacheStats {
+        let metrics = &self.cache_metrics;
+        let hits = metrics.hits.load(Ordering::Relaxed);
+        let misses = metrics.misses.load(Ordering::Relaxed);
+
+        let mut sources: HashMap<&str, Vec<CacheEntrySource>> = HashMap::new();
+        for group in &self.config.calendars {
+            for cal in &group.calendars {
+                sources.entry(&cal.url).or_default().push(CacheEntrySource {
+                    group: group.name.clone(),
+                    source: cal.name.clone(),
+                });
+            }
+        }
+
+        let mut bytes = 0;
+        let mut entries = Vec::new();
+        if let Some(ref cache) = self.cache {
+            let now = SystemTime::now();
+            for (url, cached) in cache.entries().await {
+                // Memory use counts the full URL, though only a redacted one is shown
+                bytes += (url.len() + cached.body.len()) as u64;
+                entries.push(CacheEntryStats {
+                    url: logging::redact_url(&url),
+                    sources: sources.remove(url.as_str()).unwrap_or_default(),
+                    age_seconds: now
+                        .duration_since(cached.fetched_at)
+                        .unwrap_or_default()
+                        .as_secs(),
+                    bytes: cached.body.len(),
+                    ttl_seconds: cached.ttl.as_secs(),
+                });
+            }
+            entries.sort_by(|a, b| a.url.cmp(&b.url));
+        }
+
+        CacheStats {
+            enabled: self.cache.is_some(),
+            ttl_seconds: self.config.cache_ttl_seconds,
+            entry_count: entries.len() as u64,
+            bytes,
+            hits,
+            misses,
+            hit_ratio: (hits + misses > 0).then(|| hits as f64 / (hits + misses) as f64),
+            evictions: EvictionStats {
+                expired: metrics.expired.load(Ordering::Relaxed),
+                size: metrics.size_evicted.load(Ordering::Relaxed),
+                explicit: metrics.explicit.load(Ordering::Relaxed),
+                replaced: metrics.replaced.load(Ordering::Relaxed),
+            },
+            entries,
+        }
+    }
+
+    /// Evicts the cached bodies of `urls`, or of every source without them, and
+    /// forgets their recent failures, so the next request fetches them again.
+    /// Returns the URLs that had a cached body, redacted.
+    pub async fn purge_cache(&self, urls: Option<&[String]>) -> Vec<String> {
+        let mut purged = Vec::new();
+        if let Some(ref cache) = self.cache {
+            match urls {
+                Some(urls) => {
+                    for url in urls {
+                        if cache.remove(url).await {
+                            purged.push(url.clone());
+                        }
+                    }
+                }
+                None => purged = cache.clear().await,
+            }
+        }
+
+        let mut failed = self.failure_cache.failed.lock().unwrap();
+        match urls {
+            Some(urls) => {
+                for url in urls {
+                    failed.remove(url);
+                }
+            }
+            None => failed.clear(),
+        }
+
+        let mut purged: Vec<String> = purged.iter().map(|url| logging::redact_url(url)).collect();
+        purged.sort();
+        purged.dedup();
+        purged
     }
 
-    async fn fetch_calendar(&self, url: &str) -> Result<String> {
+    /// Returns a source body, from the cache when possible. Bodies are cached
+    /// for `ttl`, or unless it's `fixed`, the TTL upstream declared or
+    /// `adaptive_refresh` learnt, under the source's main URL, whichever mirror
+    /// served them. A URL shared by sources with different TTLs is refetched
+    /// once it's older than the requesting source's.
+    ///
+    /// A cached body older than `max_age` forces a synchronous refresh; if that
+    /// refresh fails the stale body is still returned, flagged as stale.
+    async fn fetch_calendar(
+        &self,
+        url: &str,
+        mirrors: &[String],
+        ttl: Duration,
+        fixed: bool,
+        max_age: Option<Duration>,
+    ) -> Result<FetchedBody, FetchError> {
+        let mut stale_copy = None;
+        let ttl = if fixed {
+            ttl
+        } else {
+            self.learnt_ttl(url).unwrap_or(ttl)
+        };
+
         // Check cache first
         if let Some(ref cache) = self.cache {
-            if let Some(cached) = cache.get(url).await {
-                tracing::debug!("Cache hit for URL: {}", url);
-                return Ok(cached);
+            let cached = cache.get(url).await.map(|cached| {
+                let age = SystemTime::now()
+                    .duration_since(cached.fetched_at)
+                    .unwrap_or_default();
+                (cached, age)
+            });
+            if let Some((cached, age)) = cached.filter(|(_, age)| *age <= ttl) {
+                if max_age.is_none_or(|max_age| age <= max_age) {
+                    tracing::debug!("Cache hit for URL: {}", logging::redact_url(url));
+                    self.cache_metrics.hits.fetch_add(1, Ordering::Relaxed);
+                    return Ok(FetchedBody {
+                        upstream: cached,
+                        outcome: FetchOutcome::Cached,
+                        refresh_error: None,
+                    });
+                }
+                tracing::debug!(
+                    "Cached copy of URL {} is {}s old, forcing refresh",
+                    logging::redact_url(url),
+                    age.as_secs()
+                );
+                stale_copy = Some(cached);
+            }
+            self.cache_metrics.misses.fetch_add(1, Ordering::Relaxed);
+        }
+
+        match self.fetch_or_recall_failure(url, mirrors).await {
+            Ok(mut upstream) => {
+                upstream.ttl = if fixed {
+                    ttl
+                } else {
+                    self.adapt_ttl(url, &upstream.body, ttl, upstream.declared_ttl)
+                };
+                if let Some(ref cache) = self.cache {
+                    cache.insert(url, upstream.clone()).await;
+                }
+                Ok(FetchedBody {
+                    upstream,
+                    outcome: FetchOutcome::Fetched,
+                    refresh_error: None,
+                })
             }
+            Err(e) => match stale_copy {
+                Some(upstream) => {
+                    tracing::warn!("Refresh of {} failed, serving stale copy: {:?}", e.url(), e);
+                    Ok(FetchedBody {
+                        upstream,
+                        outcome: FetchOutcome::Stale,
+                        refresh_error: Some(logging::error_chain(&e)),
+                    })
+                }
+                None => Err(e),
+            },
         }
+    }
 
-        tracing::debug!("Fetching calendar from URL: {}", url);
+    /// Fetches a source unless it failed within `negative_cache_seconds`, in
+    /// which case that failure is returned again without contacting upstream.
+    async fn fetch_or_recall_failure(
+        &self,
+        url: &str,
+        mirrors: &[String],
+    ) -> Result<CachedCalendar, FetchError> {
+        let Some(seconds) = self.config.negative_cache_seconds else {
+            return self.fetch_failover(url, mirrors).await;
+        };
+        let period = Duration::from_secs(seconds);
 
-        let response = timeout(
-            Duration::from_secs(self.config.request_timeout_seconds),
-            self.client.get(url).send(),
-        )
-        .await
-        .context("Request timed out")?
-        .context("Failed to send request")?;
+        if let Some((failed_at, error)) = self.failure_cache.failed.lock().unwrap().get(url) {
+            let elapsed = failed_at.elapsed();
+            if elapsed < period {
+                return Err(FetchError::RecentlyFailed {
+                    url: logging::redact_url(url),
+                    seconds: (period - elapsed).as_secs() + 1,
+                    error: error.clone(),
+                });
+            }
+        }
+
+        let result = self.fetch_failover(url, mirrors).await;
+        let mut failed = self.failure_cache.failed.lock().unwrap();
+        match result {
+            Ok(_) => {
+                failed.remove(url);
+            }
+            // An open circuit already skips the source, and is reported as such
+            Err(FetchError::CircuitOpen { .. }) => {}
+            Err(ref e) => {
+                failed.insert(url.to_string(), (Instant::now(), logging::error_chain(e)));
+            }
+        }
+        result
+    }
+
+    /// The TTL upstream declared or `adaptive_refresh` has learnt for `url`,
+    /// once it has been fetched.
+    fn learnt_ttl(&self, url: &str) -> Option<Duration> {
+        let urls = self.change_rates.urls.lock().unwrap();
+        urls.get(url).map(|interval| interval.ttl)
+    }
+
+    /// How long to cache a freshly fetched `body` of `url`. With
+    /// `adaptive_refresh`, the TTL starts from `ttl` and doubles each time the
+    /// body is unchanged and halves each time it changed, within the configured
+    /// bounds. DTSTAMP lines are ignored, since many servers stamp every export
+    /// with the current time. A TTL `declared` by upstream's caching headers
+    /// takes precedence.
+    fn adapt_ttl(
+        &self,
+        url: &str,
+        body: &str,
+        ttl: Duration,
+        declared: Option<Duration>,
+    ) -> Duration {
+        if self.config.adaptive_refresh.is_none() && self.config.upstream_cache_control.is_none() {
+            return ttl;
+        }
+
+        let mut hasher = DefaultHasher::new();
+        for line in body.lines().filter(|line| !line.starts_with("DTSTAMP")) {
+            line.hash(&mut hasher);
+        }
+        let fingerprint = hasher.finish();
+
+        let mut urls = self.change_rates.urls.lock().unwrap();
+        let learnt = match declared {
+            Some(declared) => declared,
+            None => {
+                let Some(ref adaptive) = self.config.adaptive_refresh else {
+                    // Upstream no longer declares a TTL
+                    urls.remove(url);
+                    return ttl;
+                };
+                let min = Duration::from_secs(adaptive.min_seconds);
+                let max = Duration::from_secs(adaptive.max_seconds);
+                match urls.get(url) {
+                    None => ttl.clamp(min, max),
+                    Some(previous) => {
+                        let changed = previous.fingerprint != fingerprint;
+                        let learnt = if changed {
+                            (previous.ttl / 2).max(min)
+                        } else {
+                            (previous.ttl * 2).min(max)
+                        };
+                        if learnt != previous.ttl {
+                            tracing::debug!(
+                                "Source URL {} {}, now cached for {}s",
+                                logging::redact_url(url),
+                                if changed { "changed" } else { "unchanged" },
+                                learnt.as_secs()
+                            );
+                        }
+                        learnt
+                    }
+                }
+            }
+        };
+        urls.insert(
+            url.to_string(),
+            PollInterval {
+                fingerprint,
+                ttl: learnt,
+            },
+        );
+        learnt
+    }
+
+    /// Fetches a source from its main URL or, when that fails, from its mirrors
+    /// in turn. URLs that failed within `failover_cooldown_seconds` are tried
+    /// after the others, so a flaky main URL doesn't slow down every refresh. If
+    /// every URL fails, the error of the first one tried is returned.
+    async fn fetch_failover(
+        &self,
+        url: &str,
+        mirrors: &[String],
+    ) -> Result<CachedCalendar, FetchError> {
+        if mirrors.is_empty() {
+            return self.fetch_guarded(url).await;
+        }
+
+        let mut candidates: Vec<(Option<usize>, &str)> = std::iter::once((None, url))
+            .chain(
+                mirrors
+                    .iter()
+                    .map(String::as_str)
+                    .enumerate()
+                    .map(|(idx, mirror)| (Some(idx), mirror)),
+            )
+            .collect();
+        {
+            let cooldown = Duration::from_secs(self.config.failover_cooldown_seconds);
+            let failed_at = self.failover.failed_at.lock().unwrap();
+            let now = Instant::now();
+            // A stable sort, so healthy URLs keep their configured order
+            candidates.sort_by_key(|(_, candidate)| {
+                failed_at
+                    .get(*candidate)
+                    .is_some_and(|&at| now.duration_since(at) < cooldown)
+            });
+        }
+
+        let mut first_error = None;
+        for (mirror, candidate) in candidates {
+            match self.fetch_guarded(candidate).await {
+                Ok(mut fetched) => {
+                    self.failover.failed_at.lock().unwrap().remove(candidate);
+                    fetched.mirror = mirror;
+                    return Ok(fetched);
+                }
+                Err(e) => {
+                    tracing::warn!("Fetching {} failed: {}", e.url(), logging::error_chain(&e));
+                    let mut failed_at = self.failover.failed_at.lock().unwrap();
+                    failed_at.insert(candidate.to_string(), Instant::now());
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        Err(first_error.expect("a source has at least one URL"))
+    }
+
+    /// Fetches a source body through its circuit breaker. While the circuit is
+    /// open the upstream isn't contacted at all. Once the cool-down ends, a single
+    /// request is let through to test it, and its success closes the circuit.
+    async fn fetch_guarded(&self, url: &str) -> Result<CachedCalendar, FetchError> {
+        let Some(ref settings) = self.config.circuit_breaker else {
+            return self.fetch_upstream(url).await;
+        };
+        let cooldown = Duration::from_secs(settings.cooldown_seconds);
+
+        if let Some(breaker) = self.breakers.urls.lock().unwrap().get_mut(url) {
+            if let Some(open_until) = breaker.open_until {
+                let now = Instant::now();
+                if now < open_until {
+                    return Err(FetchError::CircuitOpen {
+                        url: logging::redact_url(url),
+                        failures: breaker.failures,
+                        seconds: (open_until - now).as_secs() + 1,
+                    });
+                }
+                // Other requests keep skipping the source while this one tests it
+                breaker.open_until = Some(now + cooldown);
+            }
+        }
+
+        let result = self.fetch_upstream(url).await;
+        let mut urls = self.breakers.urls.lock().unwrap();
+        match result {
+            Ok(_) => {
+                let reopened = urls.remove(url).and_then(|breaker| breaker.open_until);
+                if reopened.is_some() {
+                    tracing::info!(
+                        "Circuit for {} closed, source is back",
+                        logging::redact_url(url)
+                    );
+                }
+            }
+            Err(ref e) => {
+                let breaker = urls.entry(url.to_string()).or_default();
+                breaker.failures += 1;
+                if breaker.failures >= settings.failure_threshold {
+                    if breaker.open_until.is_none() {
+                        tracing::warn!(
+                            "Circuit for {} opened after {} consecutive failures, skipping it for {}s",
+                            e.url(),
+                            breaker.failures,
+                            settings.cooldown_seconds
+                        );
+                    }
+                    breaker.open_until = Some(Instant::now() + cooldown);
+                }
+            }
+        }
+        result
+    }
+
+    /// Fetches a source body. A pinned source's key is checked by its client,
+    /// before the request is sent.
+    async fn fetch_upstream(&self, url: &str) -> Result<CachedCalendar, FetchError> {
+        // URLs may embed tokens, so errors and logs only carry a redacted copy
+        let redacted = logging::redact_url(url);
+        tracing::debug!("Fetching calendar from URL: {}", redacted);
+        let started = Instant::now();
+
+        let seconds = self.config.request_timeout_seconds;
+        if synthetic::is_synthetic(url) {
+            return self.fetch_synthetic(url, redacted, seconds).await;
+        }
+        // Held until the body is read, since the connection stays busy until then
+        let _permit = match self.fetch_permits {
+            Some(ref permits) => Some(permits.acquire().await.expect("semaphore is never closed")),
+            None => None,
+        };
+        let response = self.send(Method::GET, url, &redacted, seconds).await?;
 
         let status = response.status();
         if !status.is_success() {
-            anyhow::bail!("HTTP error: {} for URL: {}", status, url);
+            return Err(FetchError::Status {
+                url: redacted,
+                status,
+            });
         }
+        let bounds = self.config.upstream_cache_control.as_ref();
+        let declared_ttl = bounds.and_then(|bounds| {
+            let min = Duration::from_secs(bounds.min_seconds);
+            let max = Duration::from_secs(bounds.max_seconds);
+            declared_ttl(response.headers()).map(|ttl| ttl.clamp(min, max))
+        });
 
-        let body = response.text().await.context("Failed to read response body")?;
+        let limit = self.config.max_source_bytes;
+        let body = read_body(response, limit, &redacted, seconds).await?;
+        tracing::debug!(
+            url = %redacted,
+            status = status.as_u16(),
+            duration_ms = u64::try_from(started.elapsed().as_millis()).unwrap_or(u64::MAX),
+            "fetched source"
+        );
 
-        // Store in cache if enabled
-        if let Some(ref cache) = self.cache {
-            cache.insert(url.to_string(), body.clone()).await;
+        Ok(CachedCalendar {
+            body,
+            fetched_at: SystemTime::now(),
+            status,
+            mirror: None,
+            ttl: Duration::ZERO,
+            declared_ttl,
+        })
+    }
+
+    /// Generates the body of a built-in source, bounded by the request timeout
+    /// like a real fetch.
+    async fn fetch_synthetic(
+        &self,
+        url: &str,
+        redacted: String,
+        seconds: u64,
+    ) -> Result<CachedCalendar, FetchError> {
+        let body = timeout(Duration::from_secs(seconds), synthetic::generate(url))
+            .await
+            .map_err(|_| FetchError::Timeout {
+                url: redacted.clone(),
+                seconds,
+            })?
+            .ok_or(FetchError::Status {
+                url: redacted,
+                status: reqwest::StatusCode::NOT_FOUND,
+            })?;
+        Ok(CachedCalendar {
+            body,
+            fetched_at: SystemTime::now(),
+            status: reqwest::StatusCode::OK,
+            mirror: None,
+            ttl: Duration::ZERO,
+            declared_ttl: None,
+        })
+    }
+
+    /// Checks that a source answers a HEAD request within `seconds`, for readiness
+    /// probes. `405 Method Not Allowed` counts as an answer, since some servers
+    /// only serve GET.
+    pub async fn probe(&self, url: &str, seconds: u64) -> Result<reqwest::StatusCode, FetchError> {
+        if synthetic::is_synthetic(url) {
+            return Ok(reqwest::StatusCode::OK);
         }
+        let redacted = logging::redact_url(url);
+        let response = self.send(Method::HEAD, url, &redacted, seconds).await?;
 
-        Ok(body)
+        let status = response.status();
+        if !status.is_success() && status != reqwest::StatusCode::METHOD_NOT_ALLOWED {
+            return Err(FetchError::Status {
+                url: redacted,
+                status,
+            });
+        }
+        Ok(status)
     }
 
-    pub async fn generate_combined_calendar(
+    /// Sends a request to a source, tagged with the current request ID and bounded
+    /// by `seconds`.
+    async fn send(
         &self,
-        name: &str,
-        calendars: &[SourceCalendar],
-    ) -> Result<String> {
-        // Fetch all calendars in parallel
-        let fetch_tasks: Vec<_> = calendars
+        method: Method,
+        url: &str,
+        redacted: &str,
+        seconds: u64,
+    ) -> Result<reqwest::Response, FetchError> {
+        let timed_out = || FetchError::Timeout {
+            url: redacted.to_string(),
+            seconds,
+        };
+        let client = self.source_clients.get(url).unwrap_or(&self.client);
+        let mut request = client.request(method, url);
+        if let Some(request_id) = logging::current_request_id() {
+            request = request.header(logging::REQUEST_ID_HEADER, request_id);
+        }
+        timeout(Duration::from_secs(seconds), request.send())
+            .await
+            .map_err(|_| timed_out())?
+            .map_err(|error| {
+                if error.is_timeout() {
+                    timed_out()
+                } else if let Some(found) = pinning::mismatch(&error) {
+                    tracing::error!(
+                        "Certificate pin mismatch for URL {}: got {}",
+                        redacted,
+                        found
+                    );
+                    FetchError::PinMismatch {
+                        url: redacted.to_string(),
+                        found,
+                    }
+                } else {
+                    FetchError::Request {
+                        url: redacted.to_string(),
+                        error: error.without_url(),
+                    }
+                }
+            })
+    }
+
+    /// `fetch_sources`, counting a failure by `ErrorKind` for `/status`.
+    async fn fetch_all(
+        &self,
+        group: &CalendarGroup,
+        skip_open_circuits: bool,
+    ) -> Result<Vec<FetchedCalendar>, CalendarError> {
+        let result = self.fetch_sources(group, skip_open_circuits).await;
+        if let Err(ref e) = result {
+            self.error_metrics.counts[e.kind() as usize].fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Fetches every source calendar of a group in parallel, in the same order
+    /// as `group.calendars`. With `skip_open_circuits`, a source whose circuit
+    /// is open and that has no cached copy is served empty and flagged as
+    /// stale, so one dead source doesn't fail the whole group.
+    async fn fetch_sources(
+        &self,
+        group: &CalendarGroup,
+        skip_open_circuits: bool,
+    ) -> Result<Vec<FetchedCalendar>, CalendarError> {
+        let started = Instant::now();
+        let max_age = group.max_staleness_seconds.map(Duration::from_secs);
+        let calendars = &group.calendars;
+
+        // Dropping the set, when the client disconnects or a fetch fails, aborts
+        // the fetches still in flight
+        let mut fetches = JoinSet::new();
+        for (idx, cal) in calendars.iter().enumerate() {
+            let service = self.clone();
+            let url = cal.url.clone();
+            let mirrors = cal.mirrors.clone();
+            let ttl = group.cache_ttl_seconds(cal, self.config.cache_ttl_seconds);
+            let ttl = Duration::from_secs(ttl);
+            let fixed = cal.cache_ttl_seconds.is_some();
+            let group_name = group.name.clone();
+            let cal_name = cal.name.clone();
+            let adhoc = group.adhoc;
+            logging::spawn_in(&mut fetches, async move {
+                let result = service
+                    .fetch_calendar(&url, &mirrors, ttl, fixed, max_age)
+                    .await;
+                if !adhoc {
+                    service.record_fetch(&group_name, &cal_name, &result);
+                }
+                let result = match result {
+                    Err(FetchError::CircuitOpen { .. }) if skip_open_circuits => {
+                        tracing::warn!(
+                            "Leaving '{}' out of '{}' while its circuit is open",
+                            cal_name,
+                            group_name
+                        );
+                        Ok(None)
+                    }
+                    result => result.map(Some).map_err(|error| CalendarError::Fetch {
+                        calendar: cal_name,
+                        error,
+                    }),
+                };
+                (idx, result)
+            });
+        }
+
+        // Wait for all fetches to complete, then restore the configured order
+        let mut fetched: Vec<Option<Option<FetchedBody>>> =
+            calendars.iter().map(|_| None).collect();
+        while let Some(joined) = fetches.join_next().await {
+            let (idx, result) = joined?;
+            fetched[idx] = Some(result?);
+        }
+        let fetched_calendars = calendars
             .iter()
-            .map(|cal| {
-                let service = self.clone();
-                let url = cal.url.clone();
-                let cal_name = cal.name.clone();
-                tokio::spawn(async move {
-                    service
-                        .fetch_calendar(&url)
-                        .await
-                        .context(format!("Failed to fetch calendar: {}", cal_name))
-                })
+            .zip(fetched)
+            .map(|(cal, fetched)| {
+                let fetched = fetched.expect("every fetch was joined");
+                let Some(fetched) = fetched else {
+                    return FetchedCalendar {
+                        name: cal.name.clone(),
+                        body: String::new(),
+                        stale: true,
+                        missing: true,
+                        fetched_at: SystemTime::now(),
+                    };
+                };
+                FetchedCalendar {
+                    name: cal.name.clone(),
+                    body: fetched.upstream.body,
+                    stale: fetched.outcome == FetchOutcome::Stale,
+                    missing: false,
+                    fetched_at: fetched.upstream.fetched_at,
+                }
             })
             .collect();
 
-        // Wait for all fetches to complete
-        let mut fetched_calendars = Vec::new();
-        for (idx, task) in fetch_tasks.into_iter().enumerate() {
-            let result = task.await.context("Task panicked")?;
-            fetched_calendars.push((calendars[idx].name.clone(), result?));
-        }
-
-        // --- String-based merging ---
-        let mut combined_cal_string = String::new();
-        combined_cal_string.push_str("BEGIN:VCALENDAR\r\n");
-        combined_cal_string.push_str(&format!("PRODID:{}\r\n", name));
-        combined_cal_string.push_str("VERSION:2.0\r\n");
-        combined_cal_string.push_str(&format!("NAME:{}\r\n", name));
-        combined_cal_string.push_str(&format!("X-WR-CALNAME:{}\r\n", name));
-
-        let mut all_timezones = std::collections::HashMap::new();
-        let mut all_events = Vec::new();
-
-        let unfold_re = Regex::new(r"\r?\n[ \t]").unwrap();
-        let re_tz = Regex::new(r"(?ms)BEGIN:VTIMEZONE.*?END:VTIMEZONE").unwrap();
-        let re_event = Regex::new(r"(?ms)BEGIN:VEVENT.*?END:VEVENT").unwrap();
-        let re_summary = Regex::new(r"SUMMARY:(.*)").unwrap();
-        let re_tzid = Regex::new(r"TZID:(.*)").unwrap();
-
-        for (source_name, cal_text) in &fetched_calendars {
-            // Pre-process to "unfold" long lines and normalize all line endings to \n
-            let unfolded_cal_text = unfold_re.replace_all(cal_text, "");
-            let normalized_cal_text = unfolded_cal_text.replace("\r\n", "\n");
-
-            // Extract timezones
-            for cap in re_tz.captures_iter(&normalized_cal_text) {
-                let tz_text = cap.get(0).unwrap().as_str();
-                if let Some(tzid_match) = re_tzid.captures(tz_text) {
-                    let tzid = tzid_match.get(1).unwrap().as_str().trim();
-                    all_timezones.entry(tzid.to_string()).or_insert_with(|| tz_text.to_string());
-                }
-            }
-
-            // Extract and modify events
-            for cap in re_event.captures_iter(&normalized_cal_text) {
-                let event_text = cap.get(0).unwrap().as_str();
-                let new_event_text = if let Some(summary_match) = re_summary.captures(event_text) {
-                    let original_summary = summary_match.get(1).unwrap().as_str().trim();
-                    let new_summary = format!("SUMMARY:{} [{}]", original_summary, source_name);
-                    event_text.replacen(summary_match.get(0).unwrap().as_str(), &new_summary, 1)
-                } else {
-                    event_text.to_string()
-                };
-                all_events.push(new_event_text);
+        self.pipeline_metrics.record(Stage::Fetch, started);
+        Ok(fetched_calendars)
+    }
+
+    /// Runs the pipeline stages every output shares, from fetching up to dropping
+    /// sources whose `include_when` rule isn't met.
+    async fn prepare(
+        &self,
+        group: &CalendarGroup,
+    ) -> Result<Vec<ParsedCalendar>, CalendarError> {
+        let fetched = self.fetch_all(group, true).await?;
+        let metrics = &self.pipeline_metrics;
+
+        let started = Instant::now();
+        let decoded = pipeline::decode(fetched);
+        metrics.record(Stage::Decode, started);
+
+        let started = Instant::now();
+        for calendar in decoded.iter().filter(|c| !group.adhoc && !c.missing) {
+            let warnings = pipeline::parse_warnings(calendar);
+            self.record_parse_warnings(&group.name, &calendar.name, warnings);
+        }
+        let mut parsed = pipeline::parse(decoded);
+        metrics.record(Stage::Parse, started);
+
+        let started = Instant::now();
+        pipeline::repair(group, &mut parsed);
+        metrics.record(Stage::Repair, started);
+
+        let started = Instant::now();
+        let now = Utc::now();
+        self.record_shadow(group, pipeline::shadow(group, &parsed, now));
+        let opt_out = self.config.opt_out_category.as_deref();
+        let parsed = pipeline::filter(group, parsed, opt_out, now);
+        metrics.record(Stage::Filter, started);
+
+        Ok(parsed)
+    }
+
+    /// Merges a group's sources into one calendar. With a target `tz`, event
+    /// date-times are rewritten into that zone and the upstream VTIMEZONEs are
+    /// replaced by a single canonical one.
+    pub async fn generate_combined_calendar(
+        &self,
+        group: &CalendarGroup,
+        tz: Option<Tz>,
+        base_url: &str,
+    ) -> Result<CombinedCalendar, CalendarError> {
+        let mut parsed = self.prepare(group).await?;
+        let metrics = &self.pipeline_metrics;
+
+        let started = Instant::now();
+        let mut transform = pipeline::Transform::new(tz, group.attribution);
+        pipeline::transform(&mut parsed, &mut transform);
+        let path = self.config.group_path(&group.name);
+        let base = format!("{}/attachment/{}", base_url, path);
+        for calendar in &mut parsed {
+            match group.attachments {
+                AttachmentPolicy::Keep => {}
+                AttachmentPolicy::Strip => attachments::strip(calendar),
+                AttachmentPolicy::Link => {
+                    for (id, attachment) in
+                        attachments::link(calendar, &base, &group.name, &self.attachment_key)
+                    {
+                        self.attachments.insert(id, Arc::new(attachment)).await;
+                    }
+                }
             }
         }
+        metrics.record(Stage::Transform, started);
+
+        let started = Instant::now();
+        pipeline::dedup(&mut parsed);
+        metrics.record(Stage::Dedup, started);
+
+        let started = Instant::now();
+        let now = Utc::now();
+        pipeline::window(group, &mut parsed, now);
+        metrics.record(Stage::Window, started);
 
-        // Append unique timezones, ensuring CRLF endings
-        for tz_text in all_timezones.values() {
-            combined_cal_string.push_str(&tz_text.trim().replace('\n', "\r\n"));
-            combined_cal_string.push_str("\r\n");
+        let started = Instant::now();
+        let mut events = pipeline::sort(&parsed);
+        if group.calendars.is_empty() && self.config.placeholder_event {
+            events.push(pipeline::placeholder_event(&group.name, now));
         }
+        metrics.record(Stage::Sort, started);
 
-        // Append events, ensuring CRLF endings
-        for event_text in &all_events {
-            combined_cal_string.push_str(&event_text.trim().replace('\n', "\r\n"));
-            combined_cal_string.push_str("\r\n");
+        let started = Instant::now();
+        let name_properties = group.name_properties.unwrap_or(self.config.name_properties);
+        let converter = transform.converter();
+        let data = pipeline::serialize(group, name_properties, &parsed, &events, converter, now);
+        metrics.record(Stage::Serialize, started);
+
+        Ok(CombinedCalendar {
+            data,
+            stale_sources: parsed
+                .into_iter()
+                .filter(|calendar| calendar.stale)
+                .map(|calendar| calendar.name)
+                .collect(),
+        })
+    }
+
+    /// An attachment linked by `AttachmentPolicy::Link`, if it is still kept.
+    pub async fn attachment(&self, id: &str) -> Option<Arc<Attachment>> {
+        self.attachments.get(id).await
+    }
+
+    /// Whether `signature` is the one on the link to attachment `id` of `group`.
+    pub fn verify_attachment(&self, group: &str, id: &str, signature: &str) -> bool {
+        attachments::verify(&self.attachment_key, group, id, signature)
+    }
+
+    /// Fetches one of a group's sources as a request for the group would, for
+    /// background checks. A stale copy served because the refresh failed
+    /// counts as a failure.
+    pub async fn check_source(
+        &self,
+        group: &CalendarGroup,
+        cal: &SourceCalendar,
+    ) -> Result<(), String> {
+        let ttl = group.cache_ttl_seconds(cal, self.config.cache_ttl_seconds);
+        let ttl = Duration::from_secs(ttl);
+        let fixed = cal.cache_ttl_seconds.is_some();
+        let max_age = group.max_staleness_seconds.map(Duration::from_secs);
+        let result = self
+            .fetch_calendar(&cal.url, &cal.mirrors, ttl, fixed, max_age)
+            .await;
+        self.record_fetch(&group.name, &cal.name, &result);
+        match result {
+            Ok(fetched) => fetched.refresh_error.map_or(Ok(()), Err),
+            Err(error) => Err(logging::error_chain(&error)),
         }
+    }
 
-        combined_cal_string.push_str("END:VCALENDAR\r\n");
+    /// The body of `cal`, one of `group`'s sources, as upstream served it,
+    /// through the cache.
+    pub async fn source_body(
+        &self,
+        group: &CalendarGroup,
+        cal: &SourceCalendar,
+    ) -> Result<FetchedCalendar, CalendarError> {
+        let group = CalendarGroup {
+            calendars: vec![cal.clone()],
+            ..group.clone()
+        };
+        let fetched = self.fetch_all(&group, false).await?;
+        Ok(fetched.into_iter().next().expect("the source was fetched"))
+    }
 
-        Ok(combined_cal_string)
+    /// A group's merged events, for comparing between checks. Sources are
+    /// always named in SUMMARY, since the DESCRIPTION attribution holds the
+    /// fetch date and would change every day.
+    pub async fn merged_events(&self, group: &CalendarGroup) -> Result<Vec<String>, CalendarError> {
+        let mut parsed = self.prepare(group).await?;
+        let mut transform = pipeline::Transform::new(None, Attribution::Summary);
+        pipeline::transform(&mut parsed, &mut transform);
+        pipeline::dedup(&mut parsed);
+        pipeline::window(group, &mut parsed, Utc::now());
+        Ok(pipeline::sort(&parsed))
     }
 
-    pub async fn combine_all_calendars(
+    /// Computes a VFREEBUSY summary of the busy time across all calendars in `[start, end)`.
+    pub async fn generate_freebusy(
         &self,
-        calendars: &[SourceCalendar],
-    ) -> Result<String> {
-        self.generate_combined_calendar("all-calendars", calendars)
-            .await
+        group: &CalendarGroup,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<String, CalendarError> {
+        let events: Vec<ics::Event> = self
+            .prepare(group)
+            .await?
+            .iter()
+            .flat_map(|calendar| &calendar.events)
+            .map(|event| ics::Event::parse(event))
+            .collect();
+
+        let busy = freebusy::busy_intervals(&events, start, end);
+
+        Ok(freebusy::render(&group.name, start, end, &busy))
+    }
+
+    /// Expands every non-cancelled event instance in `[from, to)`, sorted by start time.
+    pub async fn agenda(
+        &self,
+        group: &CalendarGroup,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<AgendaItem>, CalendarError> {
+        let parsed = self.prepare(group).await?;
+
+        let mut items = Vec::new();
+        for calendar in &parsed {
+            let source_name = &calendar.name;
+            let events: Vec<ics::Event> = calendar
+                .events
+                .iter()
+                .map(|event| ics::Event::parse(event))
+                .collect();
+
+            for (idx, occurrence) in ics::occurrences(&events, from, to) {
+                let event = &events[idx];
+                if event.is_cancelled() {
+                    continue;
+                }
+                items.push(AgendaItem {
+                    source: source_name.clone(),
+                    uid: event.uid.clone(),
+                    summary: event.summary.clone().unwrap_or_default(),
+                    location: event.location.clone(),
+                    start: occurrence.start,
+                    end: occurrence.end,
+                    all_day: event.start.is_some_and(|start| start.all_day),
+                });
+            }
+        }
+
+        items.sort_by(|a, b| a.start.cmp(&b.start).then_with(|| a.summary.cmp(&b.summary)));
+        Ok(items)
+    }
+
+    /// Replays the merge pipeline of a group for the events with `uid`, recording
+    /// every decision made along the way.
+    pub async fn explain(
+        &self,
+        group: &CalendarGroup,
+        uid: &str,
+    ) -> Result<Explanation, CalendarError> {
+        let parsed = pipeline::parse(pipeline::decode(self.fetch_all(group, true).await?));
+        let has_uid = |event: &String| ics::Event::parse(event).uid.as_deref() == Some(uid);
+        let before_repair: Vec<Vec<String>> = parsed
+            .iter()
+            .map(|calendar| calendar.events.iter().filter(|e| has_uid(e)).cloned().collect())
+            .collect();
+        let mut parsed = parsed;
+        pipeline::repair(group, &mut parsed);
+
+        let mut transform = pipeline::Transform::new(
+            group.default_timezone().and_then(ics::resolve_tz),
+            group.attribution,
+        );
+        let defined_tzids: HashSet<&str> = parsed
+            .iter()
+            .flat_map(|calendar| &calendar.timezones)
+            .map(|(tzid, _)| tzid.as_str())
+            .collect();
+
+        let mut explanation = Explanation {
+            group: group.name.clone(),
+            uid: uid.to_string(),
+            steps: Vec::new(),
+            output: Vec::new(),
+        };
+        let now = Utc::now();
+        let shadow = pipeline::shadow(group, &parsed, now);
+        let kept = pipeline::filter(
+            group,
+            parsed.clone(),
+            self.config.opt_out_category.as_deref(),
+            now,
+        );
+        let kept_events: Vec<(usize, ics::Event)> = kept
+            .iter()
+            .enumerate()
+            .flat_map(|(i, calendar)| {
+                calendar
+                    .events
+                    .iter()
+                    .filter(|e| has_uid(e))
+                    .map(move |e| (i, ics::Event::parse(e)))
+            })
+            .collect();
+        let owner = pipeline::series_owners(kept_events.iter().map(|(i, event)| (*i, event)))
+            .get(uid)
+            .map(|&i| kept[i].name.as_str());
+        let mut windowed = kept.clone();
+        pipeline::dedup(&mut windowed);
+        pipeline::window(group, &mut windowed, now);
+        for ((cal, calendar), originals) in group.calendars.iter().zip(&parsed).zip(before_repair) {
+            let step = |stage: &str, detail| ExplainStep {
+                source: calendar.name.clone(),
+                stage: stage.to_string(),
+                detail,
+            };
+            explanation.steps.push(step(
+                "fetch",
+                if calendar.missing {
+                    "left out: its circuit is open and nothing is cached".to_string()
+                } else if calendar.stale {
+                    "served stale: refresh failed after max_staleness_seconds".to_string()
+                } else {
+                    "fetched".to_string()
+                },
+            ));
+            if let Some(reason) = pipeline::exclusion_reason(cal, &parsed, now) {
+                if cal.include_when.as_ref().is_some_and(|c| c.shadow) {
+                    explanation.steps.push(step(
+                        "shadow",
+                        format!("include_when would exclude: {}", reason),
+                    ));
+                } else {
+                    explanation
+                        .steps
+                        .push(step("filter", format!("excluded: {}", reason)));
+                    continue;
+                }
+            }
+
+            let matching: Vec<&String> = calendar.events.iter().filter(|e| has_uid(e)).collect();
+            if matching.is_empty() {
+                explanation
+                    .steps
+                    .push(step("match", "no event with this UID".to_string()));
+                continue;
+            }
+            for effect in shadow.iter().filter(|effect| {
+                effect.source == cal.name
+                    && effect.rule != "include_when"
+                    && effect.uids.iter().any(|affected| affected == uid)
+            }) {
+                explanation.steps.push(step(
+                    "shadow",
+                    format!("{} would drop or rewrite this event", effect.rule),
+                ));
+            }
+
+            let opt_out = self.config.opt_out_category.as_deref();
+            // Why the filter stage drops an event, if it does
+            let dropped_by = |event: &ics::Event| {
+                if opt_out.is_some_and(|category| pipeline::is_opted_out(event, category)) {
+                    return Some(format!(
+                        "opted out with CATEGORIES {}",
+                        opt_out.unwrap_or_default()
+                    ));
+                }
+                pipeline::all_day_reason(cal, event)
+                    .or_else(|| pipeline::keyword_reason(group, event))
+            };
+            let events: Vec<ics::Event> = matching.iter().map(|e| ics::Event::parse(e)).collect();
+            if let Some(reason) = events
+                .iter()
+                .filter(|e| e.recurrence_id.is_none())
+                .find_map(dropped_by)
+            {
+                explanation.steps.push(step("filter", reason));
+                continue;
+            }
+            if let Some(max) = group.max_events_per_source {
+                let capped = kept
+                    .iter()
+                    .find(|kept| kept.name == calendar.name)
+                    .is_some_and(|kept| !kept.events.iter().any(&has_uid));
+                if capped {
+                    explanation.steps.push(step(
+                        "filter",
+                        format!(
+                            "dropped: not among the {} events kept by max_events_per_source",
+                            max
+                        ),
+                    ));
+                    continue;
+                }
+            }
+            if let Some(owner) = owner.filter(|owner| *owner != calendar.name) {
+                explanation.steps.push(step(
+                    "dedup",
+                    format!("dropped: the series is served from '{}'", owner),
+                ));
+                continue;
+            }
+            if let Some(ref window) = group.window {
+                let outside = windowed
+                    .iter()
+                    .find(|windowed| windowed.name == calendar.name)
+                    .is_some_and(|windowed| !windowed.events.iter().any(&has_uid));
+                if outside {
+                    explanation.steps.push(step(
+                        "window",
+                        format!(
+                            "dropped: no instance from {} days ago to {} days ahead",
+                            window.past_days, window.future_days
+                        ),
+                    ));
+                    continue;
+                }
+            }
+            let dropped_instances: Vec<ics::Property> = matching
+                .iter()
+                .zip(&events)
+                .filter(|(_, event)| event.recurrence_id.is_some() && dropped_by(event).is_some())
+                .filter_map(|(text, _)| {
+                    ics::top_level_properties(text)
+                        .into_iter()
+                        .find(|p| p.name == "RECURRENCE-ID")
+                })
+                .collect();
+
+            for ((event_text, event), original) in matching.into_iter().zip(events).zip(&originals)
+            {
+                explanation.steps.push(step(
+                    "match",
+                    match event.recurrence_id {
+                        Some(recurrence_id) => format!(
+                            "override for instance {}",
+                            ics::format_utc(recurrence_id.utc())
+                        ),
+                        None => "event found".to_string(),
+                    },
+                ));
+
+                let repaired = pipeline::changed_lines(original, event_text);
+                if !repaired.is_empty() {
+                    explanation.steps.push(step("repair", repaired.join("; ")));
+                }
+
+                if let Some(reason) = event.recurrence_id.and_then(|_| dropped_by(&event)) {
+                    explanation
+                        .steps
+                        .push(step("filter", format!("instance {}", reason)));
+                    continue;
+                }
+                let excluded;
+                let event_text = if event.recurrence_id.is_none() && !dropped_instances.is_empty() {
+                    explanation.steps.push(step(
+                        "filter",
+                        format!(
+                            "EXDATE added for {} dropped instance(s)",
+                            dropped_instances.len()
+                        ),
+                    ));
+                    excluded = pipeline::add_exdates(event_text, &dropped_instances);
+                    &excluded
+                } else {
+                    event_text
+                };
+
+                let mut steps = Vec::new();
+                let output = transform.event(event_text, calendar, Some(&mut steps));
+                explanation
+                    .steps
+                    .extend(steps.into_iter().map(|(stage, detail)| step(stage, detail)));
+
+                if transform.converter().is_none() {
+                    let mut referenced = std::collections::BTreeMap::new();
+                    crate::timezone::referenced_tzids(&output, &mut referenced);
+                    for tzid in referenced.keys() {
+                        let detail = if defined_tzids.contains(tzid.as_str()) {
+                            format!("VTIMEZONE {} copied from a source", tzid)
+                        } else if ics::resolve_tz(tzid).is_some() {
+                            format!("VTIMEZONE {} generated from tzdb", tzid)
+                        } else {
+                            format!("no VTIMEZONE available for {}", tzid)
+                        };
+                        explanation.steps.push(step("serialize", detail));
+                    }
+                }
+
+                explanation.output.push(output);
+            }
+        }
+
+        Ok(explanation)
     }
 }
@@ -0,0 +1,246 @@
+//! The command line, and `--check-config`, which validates the config for
+//! deploy pipelines without starting the server.
+
+use crate::calendar::CalendarService;
+use crate::config::{self, CalendarGroup, Config, ConfigError, Format};
+use crate::config_source::ConfigSource;
+use crate::logging;
+use clap::{Parser, Subcommand};
+use serde::de::DeserializeOwned;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::task::JoinSet;
+
+/// The parsed command line. Options left out fall back to the config file.
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "Serves several calendar feeds combined into one",
+    args_conflicts_with_subcommands = true
+)]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// JSON, YAML or TOML config file [default: config.json, config.yaml,
+    /// config.yml or config.toml, whichever exists]
+    #[arg(long, value_name = "PATH", global = true)]
+    pub config: Option<String>,
+    /// Listen on PORT instead of the config's server_port
+    #[arg(long)]
+    pub port: Option<u16>,
+    /// Listen on ADDRESS instead of the config's bind_address
+    #[arg(long, value_name = "ADDRESS")]
+    pub bind: Option<IpAddr>,
+    /// Cache source calendars
+    #[arg(long, overrides_with = "no_cache")]
+    enable_cache: bool,
+    /// Don't cache source calendars
+    #[arg(long)]
+    no_cache: bool,
+    /// Validate the config and exit
+    #[arg(long)]
+    pub check_config: bool,
+    /// With --check-config, also request every source
+    #[arg(long, requires = "check_config")]
+    pub probe: bool,
+}
+
+/// A command other than serving the combined calendars.
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Restore the newest config backup, or BACKUP
+    Rollback {
+        /// Path of the backup to restore
+        backup: Option<String>,
+    },
+    /// Add a source to a group of the config file
+    AddSource {
+        /// Name of the group in `calendars`
+        group: String,
+        /// Name of the new source
+        name: String,
+        /// URL of the source's calendar
+        url: String,
+        /// Description of the source
+        description: Option<String>,
+    },
+}
+
+impl Args {
+    /// Whether to cache sources: `--enable-cache`, unless `--no-cache` comes
+    /// after it. The `ENABLE_CACHE` environment variable is still read when
+    /// neither is given, but deprecated.
+    pub fn enable_cache(&self) -> bool {
+        if self.enable_cache || self.no_cache {
+            return self.enable_cache;
+        }
+        let Ok(value) = std::env::var("ENABLE_CACHE") else {
+            return false;
+        };
+        tracing::warn!(
+            "ENABLE_CACHE is deprecated and will be removed; pass --enable-cache instead"
+        );
+        value.parse().unwrap_or(false)
+    }
+}
+
+/// Checks the config from `source`, printing every problem found, and returns
+/// whether it is usable. With `probe`, every source URL and mirror is also
+/// requested; unreachable ones are reported as warnings, since an upstream
+/// outage shouldn't block a deploy.
+pub async fn check_config(mut source: ConfigSource, probe: bool) -> bool {
+    let config = match source.load().await {
+        Ok(config) => config,
+        Err(e) => {
+            let error = match source {
+                ConfigSource::File(ref path) => describe_load_error(path, &e),
+                _ => logging::error_chain(&e),
+            };
+            eprintln!("error: {}", error);
+            return false;
+        }
+    };
+    let path = source.to_string();
+
+    let mut errors = Vec::new();
+    let mut groups = HashSet::new();
+    for group in &config.calendars {
+        if !groups.insert(group.name.as_str()) {
+            errors.push(format!(
+                "Group name '{}' is used more than once; only the last one is served",
+                group.name
+            ));
+        }
+        let mut sources = HashSet::new();
+        for cal in &group.calendars {
+            if !sources.insert(cal.name.as_str()) {
+                errors.push(format!(
+                    "Group '{}': source name '{}' is used more than once; give each source its own name",
+                    group.name, cal.name
+                ));
+            }
+        }
+    }
+    for error in &errors {
+        eprintln!("error: {}", error);
+    }
+
+    let mut warnings = 0;
+    if probe {
+        warnings = probe_sources(Arc::new(config.clone())).await;
+    }
+
+    let sources: usize = config.calendars.iter().map(|g| g.calendars.len()).sum();
+    if errors.is_empty() {
+        println!(
+            "{} is valid: {} groups, {} sources, {} warnings",
+            path,
+            config.calendars.len(),
+            sources,
+            warnings
+        );
+    } else {
+        eprintln!("{} has {} errors", path, errors.len());
+    }
+    errors.is_empty()
+}
+
+/// A load error, with the JSON path of the offending value for parse errors,
+/// e.g. `calendars[1].cache_ttl_seconds`. For an included file, the path is
+/// prefixed with the file's name.
+fn describe_load_error(path: &str, error: &ConfigError) -> String {
+    match error {
+        ConfigError::Parse(parse) => {
+            locate::<Config>(path).unwrap_or_else(|| format!("{}: {}", path, parse))
+        }
+        ConfigError::Include { path, error } => match **error {
+            ConfigError::Parse(ref parse) => {
+                let is_array = std::fs::read_to_string(path)
+                    .ok()
+                    .and_then(|content| config::to_value(&content, Format::of(path)).ok())
+                    .is_some_and(|value| value.is_array());
+                let located = if is_array {
+                    locate::<Vec<CalendarGroup>>(path)
+                } else {
+                    locate::<CalendarGroup>(path)
+                };
+                let located = located.unwrap_or_else(|| parse.to_string());
+                format!("{}: {}", path, located)
+            }
+            _ => logging::error_chain(error),
+        },
+        _ => logging::error_chain(error),
+    }
+}
+
+/// The JSON path and message of the error parsing the file at `path` as `T`,
+/// if it has one.
+fn locate<T: DeserializeOwned>(path: &str) -> Option<String> {
+    let content = std::fs::read_to_string(path).ok()?;
+    let located = match Format::of(path) {
+        Format::Json => {
+            let content = config::prepare_json(&content).ok()?;
+            let deserializer = &mut serde_json::Deserializer::from_str(&content);
+            serde_path_to_error::deserialize::<_, T>(deserializer).err()?
+        }
+        format => {
+            let value = config::to_value(&content, format).ok()?;
+            serde_path_to_error::deserialize::<_, T>(value).err()?
+        }
+    };
+    let at = located.path().to_string();
+    (at != ".").then(|| format!("{}: {}", at, located.inner()))
+}
+
+/// Requests every source URL and mirror once, printing a warning for each that
+/// doesn't answer. Returns the number of warnings.
+async fn probe_sources(config: Arc<Config>) -> usize {
+    let service = CalendarService::new(false, Arc::clone(&config));
+    let seconds = config.request_timeout_seconds;
+    let mut probed = HashSet::new();
+    let mut probes = JoinSet::new();
+    for group in &config.calendars {
+        for cal in &group.calendars {
+            for url in std::iter::once(&cal.url).chain(&cal.mirrors) {
+                if !probed.insert(url.as_str()) {
+                    continue;
+                }
+                let service = service.clone();
+                let url = url.clone();
+                let what = format!("Group '{}': source '{}'", group.name, cal.name);
+                probes.spawn(async move {
+                    let result = service.probe(&url, seconds).await;
+                    (what, result)
+                });
+            }
+        }
+    }
+
+    let mut warnings = 0;
+    while let Some(joined) = probes.join_next().await {
+        let Ok((what, result)) = joined else {
+            continue;
+        };
+        if let Err(e) = result {
+            eprintln!(
+                "warning: {} is unreachable: {}",
+                what,
+                logging::error_chain(&e)
+            );
+            warnings += 1;
+        }
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn args_are_consistent() {
+        Args::command().debug_assert();
+    }
+}
@@ -0,0 +1,173 @@
+//! A small typed client for the JSON endpoints, enabled with the `client`
+//! feature:
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), webcal_combiner::client::Error> {
+//! let client = webcal_combiner::client::Client::new("https://cal.example.com", "secret")?;
+//! let status = client.status().await?;
+//! println!("cache hit ratio: {:?}", status.cache.hit_ratio);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::api::{
+    CachePurge, CacheStats, ChangeLog, Explanation, Readiness, StatusResponse, SyncResponse,
+    UsageReport,
+};
+use reqwest::{StatusCode, Url};
+use serde::de::DeserializeOwned;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Invalid base URL: {0}")]
+    BaseUrl(String),
+    #[error("Request failed")]
+    Request(#[from] reqwest::Error),
+    /// The server answered with a non-success status; `body` is its message.
+    #[error("HTTP error: {status}: {body}")]
+    Status { status: StatusCode, body: String },
+    #[error("Failed to decode response")]
+    Decode(#[from] serde_json::Error),
+}
+
+/// A client for one webcal-combiner instance, authenticating with one key.
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: Url,
+    key: String,
+}
+
+impl Client {
+    /// `base_url` is the service's public URL, including any path prefix it's
+    /// served under.
+    pub fn new(base_url: &str, key: impl Into<String>) -> Result<Self, Error> {
+        Self::with_http_client(reqwest::Client::new(), base_url, key)
+    }
+
+    /// Like `new`, with a preconfigured `reqwest::Client` (timeouts, proxies, ...).
+    pub fn with_http_client(
+        http: reqwest::Client,
+        base_url: &str,
+        key: impl Into<String>,
+    ) -> Result<Self, Error> {
+        let base_url = Url::parse(base_url).map_err(|e| Error::BaseUrl(e.to_string()))?;
+        if base_url.cannot_be_a_base() {
+            return Err(Error::BaseUrl(base_url.to_string()));
+        }
+        Ok(Self {
+            http,
+            base_url,
+            key: key.into(),
+        })
+    }
+
+    /// `GET /status/{key}`. Needs a key allowed for all groups.
+    pub async fn status(&self) -> Result<StatusResponse, Error> {
+        self.get(self.url(&["status", &self.key])).await
+    }
+
+    /// `GET /admin/explain/{key}/{group}?uid=...`. The group needs `explain` enabled.
+    pub async fn explain(&self, group: &str, uid: &str) -> Result<Explanation, Error> {
+        let mut url = self.url(&["admin", "explain", &self.key, group]);
+        url.query_pairs_mut().append_pair("uid", uid);
+        self.get(url).await
+    }
+
+    /// `GET /calendar/{key}/{group}/changes`, optionally only the changes after
+    /// `since`, e.g. `2024-05-01T09:00:00Z`. The group needs `history` enabled.
+    pub async fn changes(&self, group: &str, since: Option<&str>) -> Result<ChangeLog, Error> {
+        let mut url = self.url(&["calendar", &self.key, group, "changes"]);
+        if let Some(since) = since {
+            url.query_pairs_mut().append_pair("since", since);
+        }
+        self.get(url).await
+    }
+
+    /// `GET /calendar/{key}/{group}/sync`: every event without a `token`, or
+    /// those that changed since the `token` of an earlier sync. The group needs
+    /// `history` enabled.
+    pub async fn sync(&self, group: &str, token: Option<&str>) -> Result<SyncResponse, Error> {
+        let mut url = self.url(&["calendar", &self.key, group, "sync"]);
+        if let Some(token) = token {
+            url.query_pairs_mut().append_pair("token", token);
+        }
+        self.get(url).await
+    }
+
+    /// `GET /admin/usage/{key}`. Needs a key allowed for all groups.
+    pub async fn usage(&self) -> Result<UsageReport, Error> {
+        self.get(self.url(&["admin", "usage", &self.key])).await
+    }
+
+    /// `GET /admin/cache/stats/{key}`. Needs a key allowed for all groups.
+    pub async fn cache_stats(&self) -> Result<CacheStats, Error> {
+        let url = self.url(&["admin", "cache", "stats", &self.key]);
+        self.get(url).await
+    }
+
+    /// `POST /admin/cache/purge/{key}`, scoped to a `group` or a source `url`, or
+    /// purging everything without either. Needs a key allowed for all groups.
+    pub async fn purge_cache(
+        &self,
+        group: Option<&str>,
+        url: Option<&str>,
+    ) -> Result<CachePurge, Error> {
+        let mut request_url = self.url(&["admin", "cache", "purge", &self.key]);
+        for (name, value) in [("group", group), ("url", url)] {
+            if let Some(value) = value {
+                request_url.query_pairs_mut().append_pair(name, value);
+            }
+        }
+        self.send(self.http.post(request_url), &[]).await
+    }
+
+    /// `GET /healthz/ready`. A service that isn't ready answers 503 with the same
+    /// body, so it's returned as a `Readiness` too rather than as an error.
+    pub async fn ready(&self) -> Result<Readiness, Error> {
+        let url = self.url(&["healthz", "ready"]);
+        self.get_with(url, &[StatusCode::SERVICE_UNAVAILABLE]).await
+    }
+
+    /// The base URL with `segments` appended, each percent-encoded.
+    fn url(&self, segments: &[&str]) -> Url {
+        let mut url = self.base_url.clone();
+        url.path_segments_mut()
+            .expect("base URL checked in new")
+            .pop_if_empty()
+            .extend(segments);
+        url
+    }
+
+    async fn get<T: DeserializeOwned>(&self, url: Url) -> Result<T, Error> {
+        self.get_with(url, &[]).await
+    }
+
+    /// Like `get`, also decoding the body of the failure statuses in `accepted`.
+    async fn get_with<T: DeserializeOwned>(
+        &self,
+        url: Url,
+        accepted: &[StatusCode],
+    ) -> Result<T, Error> {
+        self.send(self.http.get(url), accepted).await
+    }
+
+    /// Sends `request` and decodes the body of a success, or of a failure status
+    /// in `accepted`.
+    async fn send<T: DeserializeOwned>(
+        &self,
+        request: reqwest::RequestBuilder,
+        accepted: &[StatusCode],
+    ) -> Result<T, Error> {
+        let response = request.send().await?;
+        let status = response.status();
+        let body = response.bytes().await?;
+        if !status.is_success() && !accepted.contains(&status) {
+            return Err(Error::Status {
+                status,
+                body: String::from_utf8_lossy(&body).into_owned(),
+            });
+        }
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
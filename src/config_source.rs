@@ -0,0 +1,104 @@
+//! Where the config comes from: a file, a URL shared by a fleet of instances, or
+//! environment variables alone.
+
+use crate::config::{Config, ConfigError};
+use crate::logging;
+use reqwest::header::AUTHORIZATION;
+use std::fmt;
+use std::time::Duration;
+
+/// How long fetching the config from a URL may take.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub enum ConfigSource {
+    File(String),
+    Url(RemoteConfig),
+    /// See `Config::from_env`.
+    Env,
+}
+
+impl ConfigSource {
+    pub async fn load(&mut self) -> Result<Config, ConfigError> {
+        match self {
+            ConfigSource::File(path) => Config::load(path),
+            ConfigSource::Url(remote) => {
+                let content = remote.fetch().await?;
+                let config = Config::from_remote(&content)?;
+                remote.last = Some(content);
+                Ok(config)
+            }
+            ConfigSource::Env => Config::from_env(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigSource::File(path) => f.write_str(path),
+            ConfigSource::Url(remote) => f.write_str(&logging::redact_url(&remote.url)),
+            ConfigSource::Env => f.write_str("The environment's config"),
+        }
+    }
+}
+
+/// A config fetched from a URL, remembering the last body that loaded so a
+/// refresh can tell whether it changed.
+pub struct RemoteConfig {
+    url: String,
+    /// Value of the `Authorization` header sent with each fetch, e.g.
+    /// "Bearer <token>".
+    authorization: Option<String>,
+    last: Option<String>,
+}
+
+impl RemoteConfig {
+    /// The config holds the access keys, so only HTTPS is accepted, except for
+    /// a server on the same machine.
+    pub fn new(url: String, authorization: Option<String>) -> Result<Self, ConfigError> {
+        let parsed = reqwest::Url::parse(&url).ok();
+        let allowed = parsed.as_ref().is_some_and(|parsed| match parsed.scheme() {
+            "https" => true,
+            "http" => matches!(parsed.host_str(), Some("localhost" | "127.0.0.1" | "[::1]")),
+            _ => false,
+        });
+        if !allowed {
+            return Err(ConfigError::Invalid(format!(
+                "Config URL {} must be an https:// URL",
+                logging::redact_url(&url)
+            )));
+        }
+        Ok(Self {
+            url,
+            authorization,
+            last: None,
+        })
+    }
+
+    /// Fetches the config, returning it only when it changed since the last
+    /// successful load and is valid.
+    pub async fn refresh(&mut self) -> Result<Option<Config>, ConfigError> {
+        let content = self.fetch().await?;
+        if self.last.as_ref() == Some(&content) {
+            return Ok(None);
+        }
+        let config = Config::from_remote(&content)?;
+        self.last = Some(content);
+        Ok(Some(config))
+    }
+
+    async fn fetch(&self) -> Result<String, ConfigError> {
+        let fetch = async {
+            let client = reqwest::Client::builder().timeout(FETCH_TIMEOUT).build()?;
+            let mut request = client.get(&self.url);
+            if let Some(ref authorization) = self.authorization {
+                request = request.header(AUTHORIZATION, authorization);
+            }
+            request.send().await?.error_for_status()?.text().await
+        };
+        fetch.await.map_err(|error| ConfigError::Fetch {
+            url: logging::redact_url(&self.url),
+            error: error.without_url(),
+        })
+    }
+}
@@ -0,0 +1,116 @@
+//! Rewriting the config file. Writes go to a temporary file that is synced and
+//! renamed over the config, so a crash or a full disk leaves either the old
+//! file or the new one, never a truncated mix. The replaced file is kept as a
+//! timestamped backup that `rollback` can restore.
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Suffix of backup files, which are named `<config>.<timestamp>.bak`.
+const BACKUP_SUFFIX: &str = ".bak";
+
+/// Replaces the config at `path` with `contents`, first backing up the current
+/// file and pruning all but the newest `keep` backups.
+pub fn write(path: &Path, contents: &str, keep: usize) -> Result<()> {
+    if keep > 0 && path.exists() {
+        let backup = backup_path(path);
+        fs::copy(path, &backup).context(format!("Failed to back up to {}", backup.display()))?;
+    }
+    replace(path, contents.as_bytes())?;
+    prune(path, keep)
+}
+
+/// Backups of the config at `path`, oldest first.
+pub fn backups(path: &Path) -> Result<Vec<PathBuf>> {
+    let dir = parent(path);
+    let prefix = format!("{}.", file_name(path));
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(dir).context(format!("Failed to list {}", dir.display()))? {
+        let entry = entry.context(format!("Failed to list {}", dir.display()))?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with(&prefix) && name.ends_with(BACKUP_SUFFIX) {
+            backups.push(entry.path());
+        }
+    }
+    // Timestamps are fixed width, so names sort by age
+    backups.sort();
+    Ok(backups)
+}
+
+/// Restores `backup` over the config at `path`. The restored backup is
+/// removed and the replaced config becomes the newest backup, so rolling back
+/// again undoes this. Only one of the config's own backups is accepted, since
+/// it's deleted afterwards.
+pub fn rollback(path: &Path, backup: &Path, keep: usize) -> Result<()> {
+    let backup = backup
+        .canonicalize()
+        .context(format!("Failed to read {}", backup.display()))?;
+    let is_backup = backups(path)?
+        .iter()
+        .any(|known| known.canonicalize().is_ok_and(|known| known == backup));
+    if !is_backup {
+        anyhow::bail!("{} isn't a backup of {}", backup.display(), path.display());
+    }
+    let backup = backup.as_path();
+    let contents =
+        fs::read_to_string(backup).context(format!("Failed to read {}", backup.display()))?;
+    // Prune only once `backup` is gone, so it can't be pruned out from under us
+    write(path, &contents, usize::MAX)?;
+    fs::remove_file(backup).context(format!("Failed to remove {}", backup.display()))?;
+    prune(path, keep)
+}
+
+/// Writes `contents` to a synced temporary file beside `path` and renames it
+/// into place.
+fn replace(path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp = parent(path).join(format!("{}.tmp", file_name(path)));
+    let written = (|| {
+        let mut file = fs::File::create(&tmp)?;
+        // The config holds secrets, so the new file keeps the old one's mode
+        if let Ok(metadata) = fs::metadata(path) {
+            file.set_permissions(metadata.permissions())?;
+        }
+        file.write_all(contents)?;
+        file.sync_all()
+    })();
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp);
+        return Err(e).context(format!("Failed to write {}", tmp.display()));
+    }
+    fs::rename(&tmp, path).context(format!("Failed to replace {}", path.display()))?;
+    // Persist the rename itself; not every platform can open a directory
+    if let Ok(dir) = fs::File::open(parent(path)) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+fn prune(path: &Path, keep: usize) -> Result<()> {
+    let backups = backups(path)?;
+    let excess = backups.len().saturating_sub(keep);
+    for backup in &backups[..excess] {
+        fs::remove_file(backup).context(format!("Failed to remove {}", backup.display()))?;
+    }
+    Ok(())
+}
+
+fn backup_path(path: &Path) -> PathBuf {
+    let timestamp = chrono::Utc::now().format("%Y%m%dT%H%M%S%.6fZ");
+    let name = format!("{}.{}{}", file_name(path), timestamp, BACKUP_SUFFIX);
+    parent(path).join(name)
+}
+
+fn parent(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
@@ -0,0 +1,379 @@
+//! Summaries of a group's upcoming events: for `/digest`, as plain text or
+//! HTML to pipe into a cron email or a scheduled chat message, and for groups
+//! with `daily_digest`, posted to a Slack or Discord webhook once a day.
+
+use crate::calendar::{AgendaItem, CalendarService};
+use crate::config::{CalendarGroup, ChatService, Config, DailyDigest};
+use crate::i18n::Locale;
+use crate::ics;
+use crate::logging;
+use crate::templates;
+use crate::view::{escape_html, item_day};
+use chrono::{DateTime, Duration, NaiveDate, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+
+/// Discord rejects messages longer than this many characters.
+const MAX_DISCORD_LENGTH: usize = 2000;
+
+/// How far ahead a digest looks, from local midnight today.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    #[default]
+    Day,
+    Week,
+}
+
+impl Period {
+    pub fn days(self) -> i64 {
+        match self {
+            Period::Day => 1,
+            Period::Week => 7,
+        }
+    }
+}
+
+/// How a digest is rendered.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Text,
+    Html,
+}
+
+/// Renders `items`, which start on or after `first`, grouped by day.
+pub fn render(
+    format: Format,
+    title: &str,
+    items: &[AgendaItem],
+    first: NaiveDate,
+    period: Period,
+    tz: Tz,
+    locale: Locale,
+) -> String {
+    let last = first + Duration::days(period.days() - 1);
+    let range = if last == first {
+        locale.format_day(first)
+    } else {
+        format!("{} – {}", locale.format_day(first), locale.format_day(last))
+    };
+    match format {
+        Format::Text => text(title, &range, items, tz, locale),
+        Format::Html => html(title, &range, items, tz, locale),
+    }
+}
+
+fn text(title: &str, range: &str, items: &[AgendaItem], tz: Tz, locale: Locale) -> String {
+    let mut output = format!("{}: {}\n", title, range);
+    if items.is_empty() {
+        output.push_str(&format!("\n{}\n", locale.no_events()));
+    }
+    let mut current_day = None;
+    for item in items {
+        let day = item_day(item, tz);
+        if current_day != Some(day) {
+            output.push_str(&format!("\n{}\n", locale.format_day(day)));
+            current_day = Some(day);
+        }
+        output.push_str(&format!(
+            "  {}  {} [{}]",
+            time(item, tz, locale, "–"),
+            item.summary,
+            item.source
+        ));
+        if let Some(location) = item.location.as_deref().filter(|l| !l.is_empty()) {
+            output.push_str(&format!(" @ {}", location));
+        }
+        output.push('\n');
+    }
+    output
+}
+
+/// A bare fragment with inline styles rather than a page, since mail clients
+/// drop `<style>` and `<head>`.
+fn html(title: &str, range: &str, items: &[AgendaItem], tz: Tz, locale: Locale) -> String {
+    let mut output = format!(
+        "<div lang=\"{}\" style=\"font-family:sans-serif\">\n<h2>{}</h2>\n<p style=\"color:#555\">{}</p>\n",
+        locale.tag(),
+        escape_html(title),
+        escape_html(range)
+    );
+    if items.is_empty() {
+        output.push_str(&format!("<p>{}</p>\n", locale.no_events()));
+    }
+    let mut current_day = None;
+    for item in items {
+        let day = item_day(item, tz);
+        if current_day != Some(day) {
+            if current_day.is_some() {
+                output.push_str("</ul>\n");
+            }
+            output.push_str(&format!(
+                "<h3>{}</h3>\n<ul style=\"list-style:none;padding:0\">\n",
+                locale.format_day(day)
+            ));
+            current_day = Some(day);
+        }
+        output.push_str(&format!(
+            "<li><span style=\"color:#555\">{}</span> {} <small>[{}]</small>",
+            time(item, tz, locale, "&ndash;"),
+            escape_html(&item.summary),
+            escape_html(&item.source)
+        ));
+        if let Some(location) = item.location.as_deref().filter(|l| !l.is_empty()) {
+            output.push_str(&format!("<br><small>{}</small>", escape_html(location)));
+        }
+        output.push_str("</li>\n");
+    }
+    if current_day.is_some() {
+        output.push_str("</ul>\n");
+    }
+    output.push_str("</div>\n");
+    output
+}
+
+/// "09:00–10:30", the start alone for an instant, or "All day".
+fn time(item: &AgendaItem, tz: Tz, locale: Locale, dash: &str) -> String {
+    if item.all_day {
+        return locale.all_day().to_string();
+    }
+    let start = item.start.with_timezone(&tz);
+    let end = item.end.with_timezone(&tz);
+    if end > start {
+        format!("{}{}{}", start.format("%H:%M"), dash, end.format("%H:%M"))
+    } else {
+        start.format("%H:%M").to_string()
+    }
+}
+
+/// The body posted to a digest webhook.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ChatMessage {
+    Slack {
+        text: String,
+    },
+    Discord {
+        content: String,
+        allowed_mentions: AllowedMentions,
+    },
+}
+
+/// The mentions Discord turns into pings: none, so an `@everyone` in an
+/// upstream summary stays text.
+#[derive(Serialize, Default)]
+struct AllowedMentions {
+    parse: [&'static str; 0],
+}
+
+/// Starts posting daily digests, if any group has `daily_digest`.
+pub fn spawn(config: Arc<Config>, service: CalendarService) -> Option<JoinHandle<()>> {
+    if !config.calendars.iter().any(has_digest) {
+        return None;
+    }
+    Some(tokio::spawn(run(config, service)))
+}
+
+async fn run(config: Arc<Config>, service: CalendarService) {
+    let timeout = std::time::Duration::from_secs(config.request_timeout_seconds);
+    let client = match reqwest::Client::builder().timeout(timeout).build() {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::error!("Failed to set up daily digests: {:?}", e);
+            return;
+        }
+    };
+    let groups: Vec<(&CalendarGroup, &DailyDigest, Tz)> = config
+        .calendars
+        .iter()
+        .filter(|group| has_digest(group))
+        .filter_map(|group| {
+            let digest = group.daily_digest.as_ref()?;
+            Some((group, digest, digest_tz(group, digest)))
+        })
+        .collect();
+    let now = Utc::now();
+    let mut next: Vec<DateTime<Utc>> = groups
+        .iter()
+        .map(|(_, digest, tz)| next_run(digest.at, *tz, now))
+        .collect();
+    loop {
+        let Some(due) = next.iter().min().copied() else {
+            return;
+        };
+        let wait = (due - Utc::now()).to_std().unwrap_or_default();
+        tokio::time::sleep(wait).await;
+        for (i, (group, digest, tz)) in groups.iter().enumerate() {
+            if next[i] > due {
+                continue;
+            }
+            post(&client, &config, &service, group, digest, *tz, due).await;
+            next[i] = next_run(digest.at, *tz, due);
+        }
+    }
+}
+
+fn has_digest(group: &CalendarGroup) -> bool {
+    group.enabled && group.daily_digest.is_some()
+}
+
+/// The zone of a digest: its own `tz`, else the group's `timezone`, else UTC.
+fn digest_tz(group: &CalendarGroup, digest: &DailyDigest) -> Tz {
+    digest
+        .tz
+        .as_deref()
+        .or(group.timezone.as_deref())
+        .and_then(ics::resolve_tz)
+        .unwrap_or(chrono_tz::UTC)
+}
+
+/// The first time after `after` that the clock in `tz` reads `at`. A time
+/// skipped by a daylight saving change is taken an hour later.
+fn next_run(at: NaiveTime, tz: Tz, after: DateTime<Utc>) -> DateTime<Utc> {
+    let mut date = after.with_timezone(&tz).date_naive();
+    loop {
+        let local = date.and_time(at);
+        let run = tz
+            .from_local_datetime(&local)
+            .earliest()
+            .or_else(|| {
+                tz.from_local_datetime(&(local + Duration::hours(1)))
+                    .earliest()
+            })
+            .map(|run| run.with_timezone(&Utc));
+        if let Some(run) = run.filter(|run| *run > after) {
+            return run;
+        }
+        date += Duration::days(1);
+    }
+}
+
+/// Midnight starting `date` in `tz`.
+fn midnight(tz: Tz, date: NaiveDate) -> DateTime<Utc> {
+    tz.from_local_datetime(&date.and_time(NaiveTime::MIN))
+        .earliest()
+        .map(|midnight| midnight.with_timezone(&Utc))
+        .unwrap_or_else(|| date.and_time(NaiveTime::MIN).and_utc())
+}
+
+/// Posts the events of the day after `due` to the digest's webhook, logging
+/// failures; a missed digest isn't retried.
+async fn post(
+    client: &reqwest::Client,
+    config: &Config,
+    service: &CalendarService,
+    group: &CalendarGroup,
+    digest: &DailyDigest,
+    tz: Tz,
+    due: DateTime<Utc>,
+) {
+    let day = due.with_timezone(&tz).date_naive() + Duration::days(1);
+    let from = midnight(tz, day);
+    let to = midnight(tz, day + Duration::days(1));
+    let items = match service.agenda(group, from, to).await {
+        Ok(items) => items,
+        Err(e) => {
+            tracing::warn!(
+                "Failed to generate the daily digest of '{}': {}",
+                group.name,
+                logging::error_chain(&e)
+            );
+            return;
+        }
+    };
+    if items.is_empty() && !digest.when_empty {
+        return;
+    }
+
+    let text = message(group, digest, &items, day, tz, config.default_locale);
+    let message = match digest.service {
+        ChatService::Slack => ChatMessage::Slack { text },
+        ChatService::Discord => ChatMessage::Discord {
+            content: truncate(text, MAX_DISCORD_LENGTH),
+            allowed_mentions: AllowedMentions::default(),
+        },
+    };
+    let body = serde_json::to_vec(&message).unwrap_or_default();
+    let sent = client
+        .post(&digest.webhook)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+    // Webhook URLs end in their token, so only the host is logged
+    let host = reqwest::Url::parse(&digest.webhook)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default();
+    match sent {
+        Ok(_) => tracing::info!("Posted the daily digest of '{}' to {}", group.name, host),
+        Err(e) => tracing::warn!(
+            "Failed to post the daily digest of '{}' to {}: {}",
+            group.name,
+            host,
+            logging::error_chain(&e.without_url())
+        ),
+    }
+}
+
+/// The digest's message for `items` on `day`, rendered from its template, or
+/// the default one should it fail.
+fn message(
+    group: &CalendarGroup,
+    digest: &DailyDigest,
+    items: &[AgendaItem],
+    day: NaiveDate,
+    tz: Tz,
+    locale: Locale,
+) -> String {
+    let escape = |text: &str| match digest.service {
+        // Slack reads these as markup, e.g. `<!channel>`
+        ChatService::Slack => text
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;"),
+        ChatService::Discord => text.to_string(),
+    };
+    let events = items
+        .iter()
+        .map(|item| templates::DigestEvent {
+            time: time(item, tz, locale, "–"),
+            summary: escape(&item.summary),
+            source: escape(&item.source),
+            location: item.location.as_deref().map(escape),
+            all_day: item.all_day,
+        })
+        .collect();
+    let context = templates::Digest {
+        group: escape(group.display_name()),
+        date: locale.format_day(day),
+        count: items.len(),
+        events,
+        no_events: locale.no_events(),
+    };
+    let source = digest.message.source(templates::DEFAULT_DIGEST);
+    templates::render(source, &context).unwrap_or_else(|e| {
+        tracing::warn!(
+            "Failed to render the daily digest template of '{}': {}",
+            group.name,
+            e
+        );
+        templates::render(templates::DEFAULT_DIGEST, &context).unwrap_or_default()
+    })
+}
+
+/// `text` cut to at most `max` characters, ending in `…` if it was cut.
+fn truncate(text: String, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text;
+    }
+    let mut cut: String = text.chars().take(max - 1).collect();
+    cut.push('…');
+    cut
+}
@@ -0,0 +1,57 @@
+use crate::calendar::AgendaItem;
+use crate::view::escape_html;
+use chrono::{DateTime, SecondsFormat, Utc};
+
+/// Renders upcoming events as an Atom feed, one entry per event instance.
+///
+/// Entry IDs combine the event UID with the instance start so that each
+/// occurrence of a recurring event is a distinct, stable entry across polls.
+pub fn render(title: &str, feed_url: &str, items: &[AgendaItem], generated: DateTime<Utc>) -> String {
+    let mut output = String::new();
+    output.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    output.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    output.push_str(&format!("  <title>{}</title>\n", escape_html(title)));
+    output.push_str(&format!("  <id>{}</id>\n", escape_html(feed_url)));
+    output.push_str(&format!(
+        "  <link rel=\"self\" href=\"{}\"/>\n",
+        escape_html(feed_url)
+    ));
+    output.push_str(&format!("  <updated>{}</updated>\n", generated.to_rfc3339_opts(SecondsFormat::Secs, true)));
+    output.push_str("  <author><name>webcal-combiner</name></author>\n");
+
+    for item in items {
+        let uid = item
+            .uid
+            .clone()
+            .unwrap_or_else(|| format!("{}-{}", item.source, item.summary));
+        let id = format!("urn:webcal-combiner:{}:{}", uid, item.start.timestamp());
+
+        let when = if item.all_day {
+            item.start.format("%a %b %-d, %Y (all day)").to_string()
+        } else {
+            format!(
+                "{} - {} UTC",
+                item.start.format("%a %b %-d, %Y %H:%M"),
+                item.end.format("%H:%M")
+            )
+        };
+        let mut content = when;
+        if let Some(location) = item.location.as_deref().filter(|l| !l.is_empty()) {
+            content.push_str(&format!(" @ {}", location));
+        }
+
+        output.push_str("  <entry>\n");
+        output.push_str(&format!(
+            "    <title>{} [{}]</title>\n",
+            escape_html(&item.summary),
+            escape_html(&item.source)
+        ));
+        output.push_str(&format!("    <id>{}</id>\n", escape_html(&id)));
+        output.push_str(&format!("    <updated>{}</updated>\n", item.start.to_rfc3339_opts(SecondsFormat::Secs, true)));
+        output.push_str(&format!("    <summary>{}</summary>\n", escape_html(&content)));
+        output.push_str("  </entry>\n");
+    }
+
+    output.push_str("</feed>\n");
+    output
+}
@@ -0,0 +1,82 @@
+use crate::ics::{self, Event, Occurrence};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+
+/// Parses a `start`/`end` query value. Accepts RFC 3339 (`2024-05-01T09:00:00Z`),
+/// plain dates (`2024-05-01`) and iCalendar UTC values (`20240501T090000Z`).
+pub fn parse_bound(value: &str) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+    if let Ok(dt) = DateTime::parse_from_rfc3339(value) {
+        return Some(dt.with_timezone(&Utc));
+    }
+    for format in ["%Y-%m-%d", "%Y%m%d"] {
+        if let Ok(date) = NaiveDate::parse_from_str(value, format) {
+            return Some(date.and_hms_opt(0, 0, 0)?.and_utc());
+        }
+    }
+    NaiveDateTime::parse_from_str(value.trim_end_matches('Z'), "%Y%m%dT%H%M%S")
+        .ok()
+        .map(|naive| naive.and_utc())
+}
+
+/// Computes the merged busy intervals within `[start, end)` across `events`.
+/// Cancelled and transparent events don't block time.
+pub fn busy_intervals(events: &[Event], start: DateTime<Utc>, end: DateTime<Utc>) -> Vec<Occurrence> {
+    let busy = ics::occurrences(events, start, end)
+        .into_iter()
+        .filter(|(idx, occurrence)| {
+            let event = &events[*idx];
+            !event.is_cancelled() && !event.transparent && occurrence.end > occurrence.start
+        })
+        .map(|(_, occurrence)| Occurrence {
+            start: occurrence.start.max(start),
+            end: occurrence.end.min(end),
+        })
+        .collect();
+
+    coalesce(busy)
+}
+
+/// Merges overlapping and adjacent intervals into a sorted, disjoint list.
+fn coalesce(mut intervals: Vec<Occurrence>) -> Vec<Occurrence> {
+    intervals.sort();
+
+    let mut merged: Vec<Occurrence> = Vec::with_capacity(intervals.len());
+    for interval in intervals {
+        match merged.last_mut() {
+            Some(last) if interval.start <= last.end => last.end = last.end.max(interval.end),
+            _ => merged.push(interval),
+        }
+    }
+    merged
+}
+
+/// Renders a VCALENDAR containing a single VFREEBUSY component.
+pub fn render(name: &str, start: DateTime<Utc>, end: DateTime<Utc>, busy: &[Occurrence]) -> String {
+    let mut output = String::new();
+    output.push_str("BEGIN:VCALENDAR\r\n");
+    output.push_str(&format!("PRODID:{}\r\n", name));
+    output.push_str("VERSION:2.0\r\n");
+    output.push_str("METHOD:PUBLISH\r\n");
+    output.push_str("BEGIN:VFREEBUSY\r\n");
+    output.push_str(&format!(
+        "UID:{}-{}-freebusy\r\n",
+        name,
+        ics::format_utc(start)
+    ));
+    output.push_str(&format!("DTSTAMP:{}\r\n", ics::format_utc(Utc::now())));
+    output.push_str(&format!("DTSTART:{}\r\n", ics::format_utc(start)));
+    output.push_str(&format!("DTEND:{}\r\n", ics::format_utc(end)));
+
+    // One period per line keeps every line well under the folding limit
+    for interval in busy {
+        output.push_str(&format!(
+            "FREEBUSY;FBTYPE=BUSY:{}/{}\r\n",
+            ics::format_utc(interval.start),
+            ics::format_utc(interval.end)
+        ));
+    }
+
+    output.push_str("END:VFREEBUSY\r\n");
+    output.push_str("END:VCALENDAR\r\n");
+    output
+}
@@ -0,0 +1,162 @@
+use chrono::{Datelike, NaiveDate};
+use serde::Deserialize;
+
+/// Languages the HTML pages are available in.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Locale {
+    #[default]
+    En,
+    De,
+    Fr,
+    Es,
+    Nl,
+}
+
+impl Locale {
+    const ALL: [Locale; 5] = [Locale::En, Locale::De, Locale::Fr, Locale::Es, Locale::Nl];
+
+    /// The BCP 47 tag, for `lang` attributes and `Content-Language`.
+    pub fn tag(self) -> &'static str {
+        match self {
+            Locale::En => "en",
+            Locale::De => "de",
+            Locale::Fr => "fr",
+            Locale::Es => "es",
+            Locale::Nl => "nl",
+        }
+    }
+
+    /// Picks the best supported locale from an `Accept-Language` header, by
+    /// quality and then order. Region subtags are ignored, so `de-AT` gets German.
+    pub fn negotiate(accept_language: Option<&str>, default: Locale) -> Locale {
+        let Some(header) = accept_language else {
+            return default;
+        };
+
+        let mut ranges: Vec<(&str, f32)> = header
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';');
+                let tag = parts.next()?.trim();
+                let quality = parts
+                    .filter_map(|param| param.trim().strip_prefix("q="))
+                    .find_map(|q| q.trim().parse().ok())
+                    .unwrap_or(1.0);
+                (!tag.is_empty() && quality > 0.0).then_some((tag, quality))
+            })
+            .collect();
+        // Stable, so equal qualities keep the client's order
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        ranges
+            .into_iter()
+            .find_map(|(tag, _)| {
+                let language = tag.split('-').next().unwrap_or(tag);
+                Self::ALL
+                    .into_iter()
+                    .find(|locale| locale.tag().eq_ignore_ascii_case(language))
+            })
+            .unwrap_or(default)
+    }
+
+    /// A day heading such as "Monday, March 5" or "Montag, 5. März".
+    pub fn format_day(self, day: NaiveDate) -> String {
+        let weekday = self.weekdays()[day.weekday().num_days_from_monday() as usize];
+        let month = self.months()[day.month0() as usize];
+        let date = day.day();
+        match self {
+            Locale::En => format!("{}, {} {}", weekday, month, date),
+            Locale::De => format!("{}, {}. {}", weekday, date, month),
+            Locale::Fr | Locale::Nl => format!("{} {} {}", weekday, date, month),
+            Locale::Es => format!("{}, {} de {}", weekday, date, month),
+        }
+    }
+
+    pub fn all_day(self) -> &'static str {
+        match self {
+            Locale::En => "All day",
+            Locale::De => "Ganztägig",
+            Locale::Fr => "Toute la journée",
+            Locale::Es => "Todo el día",
+            Locale::Nl => "Hele dag",
+        }
+    }
+
+    pub fn no_events(self) -> &'static str {
+        match self {
+            Locale::En => "No upcoming events.",
+            Locale::De => "Keine anstehenden Termine.",
+            Locale::Fr => "Aucun événement à venir.",
+            Locale::Es => "No hay eventos próximos.",
+            Locale::Nl => "Geen komende afspraken.",
+        }
+    }
+
+    /// "Next 4 weeks", with the plural agreeing with `weeks`.
+    pub fn next_weeks(self, weeks: u32) -> String {
+        let one = weeks == 1;
+        match self {
+            Locale::En => format!("Next {} week{}", weeks, if one { "" } else { "s" }),
+            Locale::De => format!("Nächste {} {}", weeks, if one { "Woche" } else { "Wochen" }),
+            Locale::Fr => format!(
+                "{} {} {}",
+                if one { "Prochaine" } else { "Prochaines" },
+                weeks,
+                if one { "semaine" } else { "semaines" }
+            ),
+            Locale::Es => format!(
+                "{} {} {}",
+                if one { "Próxima" } else { "Próximas" },
+                weeks,
+                if one { "semana" } else { "semanas" }
+            ),
+            Locale::Nl => format!("Komende {} {}", weeks, if one { "week" } else { "weken" }),
+        }
+    }
+
+    fn weekdays(self) -> [&'static str; 7] {
+        match self {
+            Locale::En => [
+                "Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday", "Sunday",
+            ],
+            Locale::De => [
+                "Montag", "Dienstag", "Mittwoch", "Donnerstag", "Freitag", "Samstag", "Sonntag",
+            ],
+            Locale::Fr => [
+                "lundi", "mardi", "mercredi", "jeudi", "vendredi", "samedi", "dimanche",
+            ],
+            Locale::Es => [
+                "lunes", "martes", "miércoles", "jueves", "viernes", "sábado", "domingo",
+            ],
+            Locale::Nl => [
+                "maandag", "dinsdag", "woensdag", "donderdag", "vrijdag", "zaterdag", "zondag",
+            ],
+        }
+    }
+
+    fn months(self) -> [&'static str; 12] {
+        match self {
+            Locale::En => [
+                "January", "February", "March", "April", "May", "June", "July", "August",
+                "September", "October", "November", "December",
+            ],
+            Locale::De => [
+                "Januar", "Februar", "März", "April", "Mai", "Juni", "Juli", "August",
+                "September", "Oktober", "November", "Dezember",
+            ],
+            Locale::Fr => [
+                "janvier", "février", "mars", "avril", "mai", "juin", "juillet", "août",
+                "septembre", "octobre", "novembre", "décembre",
+            ],
+            Locale::Es => [
+                "enero", "febrero", "marzo", "abril", "mayo", "junio", "julio", "agosto",
+                "septiembre", "octubre", "noviembre", "diciembre",
+            ],
+            Locale::Nl => [
+                "januari", "februari", "maart", "april", "mei", "juni", "juli", "augustus",
+                "september", "oktober", "november", "december",
+            ],
+        }
+    }
+}
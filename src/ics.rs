@@ -0,0 +1,539 @@
+use crate::windows_zones;
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use rrule::{RRule, RRuleSet, Unvalidated};
+
+/// Upper bound on the number of instances expanded from a single recurring event.
+const MAX_OCCURRENCES: u16 = 1000;
+
+/// A single unfolded content line, e.g. `DTSTART;TZID=Europe/Berlin:20240101T100000`.
+#[derive(Debug, Clone)]
+pub struct Property {
+    pub name: String,
+    pub params: Vec<(String, String)>,
+    pub value: String,
+}
+
+impl Property {
+    pub fn parse(line: &str) -> Option<Self> {
+        // The value starts at the first colon that isn't inside a quoted parameter
+        let mut in_quotes = false;
+        let colon = line.char_indices().find_map(|(idx, c)| {
+            match c {
+                '"' => in_quotes = !in_quotes,
+                ':' if !in_quotes => return Some(idx),
+                _ => {}
+            }
+            None
+        })?;
+
+        let mut parts = split_unquoted(&line[..colon], ';').into_iter();
+        let name = parts.next()?.trim().to_ascii_uppercase();
+        if name.is_empty() {
+            return None;
+        }
+
+        let params = parts
+            .filter_map(|param| {
+                let (key, value) = param.split_once('=')?;
+                Some((
+                    key.trim().to_ascii_uppercase(),
+                    value.trim().trim_matches('"').to_string(),
+                ))
+            })
+            .collect();
+
+        Some(Self {
+            name,
+            params,
+            value: line[colon + 1..].to_string(),
+        })
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Puts the parameters in canonical order: `TZID` first, then registered
+    /// parameters, then `X-` parameters, each group sorted by name. Together with
+    /// the uppercasing in `parse` and the quoting in `Display`, equivalent lines
+    /// print byte-identically however the source wrote them.
+    pub fn canonicalize(&mut self) {
+        let rank = |key: &str| (key != "TZID", key.starts_with("X-"));
+        self.params
+            .sort_by(|(a, _), (b, _)| rank(a).cmp(&rank(b)).then_with(|| a.cmp(b)));
+    }
+}
+
+impl std::fmt::Display for Property {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+        for (key, value) in &self.params {
+            if value.contains([':', ';', ',']) {
+                write!(f, ";{}=\"{}\"", key, value)?;
+            } else {
+                write!(f, ";{}={}", key, value)?;
+            }
+        }
+        write!(f, ":{}", self.value)
+    }
+}
+
+/// Rewrites every content line of an unfolded component in canonical form; lines
+/// that aren't properties are kept as they are.
+pub fn canonicalize_lines(component: &str) -> String {
+    component
+        .lines()
+        .map(|line| match Property::parse(line) {
+            Some(mut property) => {
+                property.canonicalize();
+                property.to_string()
+            }
+            None => line.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn split_unquoted(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut in_quotes = false;
+    let mut start = 0;
+    for (idx, c) in text.char_indices() {
+        if c == '"' {
+            in_quotes = !in_quotes;
+        } else if c == separator && !in_quotes {
+            parts.push(&text[start..idx]);
+            start = idx + c.len_utf8();
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+/// Unfolds continuation lines and normalizes all line endings to `\n`.
+pub fn unfold(text: &str) -> String {
+    text.replace("\r\n", "\n")
+        .replace("\n ", "")
+        .replace("\n\t", "")
+}
+
+/// Returns the raw text of every `BEGIN:{name}` ... `END:{name}` block in unfolded text.
+pub fn components<'a>(text: &'a str, name: &str) -> Vec<&'a str> {
+    let begin = format!("BEGIN:{}", name);
+    let end = format!("END:{}", name);
+
+    let mut blocks = Vec::new();
+    let mut start = None;
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim_end();
+        if start.is_none() && trimmed.eq_ignore_ascii_case(&begin) {
+            start = Some(offset);
+        } else if trimmed.eq_ignore_ascii_case(&end) {
+            if let Some(block_start) = start.take() {
+                blocks.push(text[block_start..offset + trimmed.len()].trim_end());
+            }
+        }
+        offset += line.len();
+    }
+    blocks
+}
+
+/// Parses the properties that belong directly to a component, skipping nested
+/// components such as VALARM.
+pub fn top_level_properties(component: &str) -> Vec<Property> {
+    let mut depth = 0;
+    let mut properties = Vec::new();
+    for line in component.lines() {
+        let line = line.trim_end();
+        let upper = line.to_ascii_uppercase();
+        if upper.starts_with("BEGIN:") {
+            depth += 1;
+        } else if upper.starts_with("END:") {
+            depth -= 1;
+        } else if depth == 1 {
+            if let Some(property) = Property::parse(line) {
+                properties.push(property);
+            }
+        }
+    }
+    properties
+}
+
+/// Resolves a TZID to an IANA zone, if chrono-tz knows it. Windows zone IDs
+/// such as "W. Europe Standard Time" resolve to their IANA equivalent.
+pub fn resolve_tz(tzid: &str) -> Option<Tz> {
+    let tzid = tzid.trim().trim_start_matches('/');
+    tzid.parse()
+        .ok()
+        .or_else(|| windows_zones::to_iana(tzid)?.parse().ok())
+}
+
+/// A DATE or DATE-TIME value resolved to an absolute instant.
+///
+/// Floating times and unknown TZIDs are interpreted as UTC.
+#[derive(Debug, Clone, Copy)]
+pub struct DateValue {
+    pub instant: DateTime<Tz>,
+    pub all_day: bool,
+}
+
+impl DateValue {
+    pub fn parse(property: &Property) -> Option<Self> {
+        Self::parse_value(&property.value, property)
+    }
+
+    fn parse_value(value: &str, property: &Property) -> Option<Self> {
+        let value = value.trim();
+        let tz = property.param("TZID").and_then(resolve_tz).unwrap_or(Tz::UTC);
+
+        let is_date = property
+            .param("VALUE")
+            .is_some_and(|v| v.eq_ignore_ascii_case("DATE"))
+            || value.len() == 8;
+        if is_date {
+            let date = NaiveDate::parse_from_str(value, "%Y%m%d").ok()?;
+            return Some(Self {
+                instant: localize(tz, date.and_hms_opt(0, 0, 0)?),
+                all_day: true,
+            });
+        }
+
+        if let Some(utc) = value.strip_suffix('Z') {
+            let naive = NaiveDateTime::parse_from_str(utc, "%Y%m%dT%H%M%S").ok()?;
+            return Some(Self {
+                instant: Tz::UTC.from_utc_datetime(&naive),
+                all_day: false,
+            });
+        }
+
+        let naive = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").ok()?;
+        Some(Self {
+            instant: localize(tz, naive),
+            all_day: false,
+        })
+    }
+
+    /// Parses every value of a multi-valued property such as EXDATE or RDATE.
+    pub fn parse_list(property: &Property) -> Vec<Self> {
+        property
+            .value
+            .split(',')
+            .filter_map(|value| Self::parse_value(value, property))
+            .collect()
+    }
+
+    pub fn utc(&self) -> DateTime<Utc> {
+        self.instant.with_timezone(&Utc)
+    }
+}
+
+fn localize(tz: Tz, naive: NaiveDateTime) -> DateTime<Tz> {
+    // Times inside a DST gap don't exist locally; treat them as UTC rather than dropping them
+    tz.from_local_datetime(&naive)
+        .earliest()
+        .unwrap_or_else(|| tz.from_utc_datetime(&naive))
+}
+
+/// Parses an RFC 5545 DURATION value such as `PT1H30M`, `P1D` or `-P1W`.
+pub fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let (negative, value) = match value.as_bytes().first()? {
+        b'-' => (true, &value[1..]),
+        b'+' => (false, &value[1..]),
+        _ => (false, value),
+    };
+    let mut rest = value.strip_prefix('P')?;
+
+    let mut total = Duration::zero();
+    let mut in_time = false;
+    while !rest.is_empty() {
+        if let Some(stripped) = rest.strip_prefix('T') {
+            in_time = true;
+            rest = stripped;
+            continue;
+        }
+        let digits = rest.find(|c: char| !c.is_ascii_digit())?;
+        let amount: i64 = rest[..digits].parse().ok()?;
+        let unit = rest[digits..].chars().next()?;
+        total += match (unit, in_time) {
+            ('W', false) => Duration::weeks(amount),
+            ('D', false) => Duration::days(amount),
+            ('H', true) => Duration::hours(amount),
+            ('M', true) => Duration::minutes(amount),
+            ('S', true) => Duration::seconds(amount),
+            _ => return None,
+        };
+        rest = &rest[digits + unit.len_utf8()..];
+    }
+
+    Some(if negative { -total } else { total })
+}
+
+/// Splits a comma-separated list of TEXT values, such as CATEGORIES, on the
+/// commas that aren't escaped, and decodes each value.
+pub fn split_text_list(value: &str) -> Vec<String> {
+    let mut values = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    for (idx, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            ',' => {
+                values.push(&value[start..idx]);
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+    values.push(&value[start..]);
+    values
+        .into_iter()
+        .map(|value| unescape_text(value.trim()))
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+/// Decodes the backslash escapes of a TEXT value (`\n`, `\,`, `\;`, `\\`).
+pub fn unescape_text(value: &str) -> String {
+    let mut output = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            output.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') | Some('N') => output.push('\n'),
+            Some(other) => output.push(other),
+            None => output.push('\\'),
+        }
+    }
+    output
+}
+
+/// Escapes a string for use in a TEXT value, the inverse of `unescape_text`.
+pub fn escape_text(value: &str) -> String {
+    let mut output = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' | ';' | ',' => {
+                output.push('\\');
+                output.push(c);
+            }
+            '\n' => output.push_str("\\n"),
+            '\r' => {}
+            _ => output.push(c),
+        }
+    }
+    output
+}
+
+/// Folds every CRLF-terminated line of `text` to at most 75 octets, as RFC 5545
+/// requires. Continuation lines start with a single space and never split a
+/// UTF-8 character.
+pub fn fold_lines(text: &str) -> String {
+    const MAX_OCTETS: usize = 75;
+
+    let mut output = String::with_capacity(text.len() + text.len() / MAX_OCTETS * 3);
+    for line in text.split_inclusive("\r\n") {
+        let (content, ending) = match line.strip_suffix("\r\n") {
+            Some(content) => (content, "\r\n"),
+            None => (line, ""),
+        };
+
+        let mut width = 0;
+        for c in content.chars() {
+            if width + c.len_utf8() > MAX_OCTETS {
+                output.push_str("\r\n ");
+                width = 1;
+            }
+            output.push(c);
+            width += c.len_utf8();
+        }
+        output.push_str(ending);
+    }
+    output
+}
+
+/// Formats an instant as a UTC DATE-TIME value, e.g. `20240101T100000Z`.
+pub fn format_utc(dt: DateTime<Utc>) -> String {
+    dt.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// The scheduling-relevant parts of a VEVENT.
+#[derive(Debug, Clone, Default)]
+pub struct Event {
+    pub uid: Option<String>,
+    pub summary: Option<String>,
+    pub description: Option<String>,
+    pub location: Option<String>,
+    pub start: Option<DateValue>,
+    pub end: Option<DateValue>,
+    pub duration: Option<Duration>,
+    pub rrules: Vec<String>,
+    pub rdates: Vec<DateValue>,
+    pub exdates: Vec<DateValue>,
+    pub recurrence_id: Option<DateValue>,
+    pub status: Option<String>,
+    pub transparent: bool,
+    pub categories: Vec<String>,
+}
+
+impl Event {
+    pub fn parse(component: &str) -> Self {
+        let mut event = Self::default();
+        for property in top_level_properties(component) {
+            match property.name.as_str() {
+                "UID" => event.uid = Some(property.value.trim().to_string()),
+                "SUMMARY" => event.summary = Some(unescape_text(property.value.trim())),
+                "DESCRIPTION" => event.description = Some(unescape_text(property.value.trim())),
+                "LOCATION" => event.location = Some(unescape_text(property.value.trim())),
+                "DTSTART" => event.start = DateValue::parse(&property),
+                "DTEND" => event.end = DateValue::parse(&property),
+                "DURATION" => event.duration = parse_duration(&property.value),
+                "RRULE" => event.rrules.push(property.value.trim().to_string()),
+                "RDATE" => event.rdates.extend(DateValue::parse_list(&property)),
+                "EXDATE" => event.exdates.extend(DateValue::parse_list(&property)),
+                "RECURRENCE-ID" => event.recurrence_id = DateValue::parse(&property),
+                "STATUS" => event.status = Some(property.value.trim().to_ascii_uppercase()),
+                "TRANSP" => {
+                    event.transparent = property.value.trim().eq_ignore_ascii_case("TRANSPARENT")
+                }
+                "CATEGORIES" => event.categories.extend(split_text_list(&property.value)),
+                _ => {}
+            }
+        }
+        event
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.status.as_deref() == Some("CANCELLED")
+    }
+
+    /// Length of a single instance, falling back to the RFC 5545 defaults of one
+    /// day for all-day events and zero otherwise.
+    fn instance_length(&self) -> Duration {
+        match (self.start, self.end, self.duration) {
+            (Some(start), Some(end), _) => end.instant.signed_duration_since(start.instant),
+            (_, _, Some(duration)) => duration,
+            (Some(start), None, None) if start.all_day => Duration::days(1),
+            _ => Duration::zero(),
+        }
+        .max(Duration::zero())
+    }
+
+    /// Start instants of every instance of this event that could overlap the window,
+    /// expanding RRULE/RDATE/EXDATE. Overridden instances are removed via `skip`.
+    fn instance_starts(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        skip: &[DateTime<Utc>],
+    ) -> Vec<DateTime<Utc>> {
+        let Some(start) = self.start else {
+            return Vec::new();
+        };
+
+        if self.rrules.is_empty() && self.rdates.is_empty() {
+            return vec![start.utc()];
+        }
+
+        let to_rrule_tz = |dt: DateTime<Tz>| dt.with_timezone(&rrule::Tz::Tz(dt.timezone()));
+        let dt_start = to_rrule_tz(start.instant);
+
+        let mut set = RRuleSet::new(dt_start);
+        let mut has_rules = false;
+        for rule in &self.rrules {
+            match rule
+                .parse::<RRule<Unvalidated>>()
+                .and_then(|rule| rule.validate(dt_start))
+            {
+                Ok(rule) => {
+                    set = set.rrule(rule);
+                    has_rules = true;
+                }
+                Err(e) => tracing::debug!("Ignoring unsupported RRULE '{}': {}", rule, e),
+            }
+        }
+        for rdate in &self.rdates {
+            set = set.rdate(to_rrule_tz(rdate.instant));
+        }
+        for exdate in &self.exdates {
+            set = set.exdate(to_rrule_tz(exdate.instant));
+        }
+
+        let Some(window_start) = from
+            .checked_sub_signed(self.instance_length())
+            .and_then(|from| from.checked_sub_signed(Duration::seconds(1)))
+        else {
+            return Vec::new();
+        };
+        let mut starts: Vec<DateTime<Utc>> = set
+            .after(window_start.with_timezone(&rrule::Tz::UTC))
+            .before(to.with_timezone(&rrule::Tz::UTC))
+            .all(MAX_OCCURRENCES)
+            .dates
+            .into_iter()
+            .map(|dt| dt.with_timezone(&Utc))
+            .filter(|dt| !skip.contains(dt))
+            .collect();
+
+        // Without a usable RRULE the set only yields the RDATEs, but DTSTART is always an instance
+        let first = start.utc();
+        let excluded = skip.contains(&first) || self.exdates.iter().any(|e| e.utc() == first);
+        if !has_rules && !excluded && !starts.contains(&first) {
+            starts.push(first);
+        }
+        starts
+    }
+}
+
+/// A concrete instance of an event in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Occurrence {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+/// Expands a set of events into the instances overlapping `[from, to)`.
+///
+/// Instances of a recurring event that are overridden by another event with the
+/// same UID and a matching RECURRENCE-ID are replaced by that override.
+pub fn occurrences(
+    events: &[Event],
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Vec<(usize, Occurrence)> {
+    let overrides: Vec<(&str, DateTime<Utc>)> = events
+        .iter()
+        .filter_map(|event| Some((event.uid.as_deref()?, event.recurrence_id?.utc())))
+        .collect();
+
+    let mut result = Vec::new();
+    for (idx, event) in events.iter().enumerate() {
+        let skip: Vec<DateTime<Utc>> = match (&event.uid, event.recurrence_id) {
+            (Some(uid), None) => overrides
+                .iter()
+                .filter(|(override_uid, _)| override_uid == uid)
+                .map(|(_, recurrence_id)| *recurrence_id)
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        let length = event.instance_length();
+        for start in event.instance_starts(from, to, &skip) {
+            let end = start + length;
+            // Zero-length events still occupy their start instant
+            if start < to && (end > from || (start == end && start >= from)) {
+                result.push((idx, Occurrence { start, end }));
+            }
+        }
+    }
+    result
+}
@@ -0,0 +1,6 @@
+//! Shared types of webcal-combiner's JSON API, and with the `client` feature, a
+//! typed client for it.
+
+pub mod api;
+#[cfg(feature = "client")]
+pub mod client;
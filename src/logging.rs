@@ -0,0 +1,156 @@
+use axum::extract::{MatchedPath, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::future::Future;
+use std::time::Duration;
+use tokio::task::{AbortHandle, JoinHandle, JoinSet};
+use tracing::{Instrument, Span};
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+/// Header carrying the request ID, generated unless the client or a proxy set it.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+tokio::task_local! {
+    /// ID of the request the current task works for, forwarded on upstream fetches.
+    static REQUEST_ID: Option<String>;
+}
+
+/// Sets up the tracing subscriber. `LOG_FORMAT=json` writes one JSON object per
+/// line, with the fields of the current request span, for Loki or Elasticsearch;
+/// anything else gives the usual human-readable lines.
+pub fn init() {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| "webcal_combiner=info,tower_http=info".into());
+    let format = std::env::var("LOG_FORMAT").unwrap_or_default();
+    let registry = tracing_subscriber::registry().with(filter);
+
+    if format.eq_ignore_ascii_case("json") {
+        registry
+            .with(
+                fmt::layer()
+                    .json()
+                    .flatten_event(true)
+                    .with_current_span(true)
+                    .with_span_list(false),
+            )
+            .init();
+        return;
+    }
+
+    registry.with(fmt::layer()).init();
+    if !format.is_empty() && !format.eq_ignore_ascii_case("text") {
+        tracing::warn!("Unknown LOG_FORMAT '{}', using text", format);
+    }
+}
+
+/// A source URL fit for logs. Source URLs often embed access tokens, so only the
+/// scheme, host and file name are kept, e.g.
+/// `https://calendar.google.com/***/basic.ics`; credentials and queries are dropped.
+pub fn redact_url(url: &str) -> String {
+    let Ok(parsed) = reqwest::Url::parse(url) else {
+        return "<invalid url>".to_string();
+    };
+    // URLs without a host, such as `synthetic:fixed`, keep their path
+    if parsed.cannot_be_a_base() {
+        return format!("{}:{}", parsed.scheme(), parsed.path());
+    }
+
+    let mut redacted = format!("{}://{}", parsed.scheme(), parsed.host_str().unwrap_or(""));
+    if let Some(port) = parsed.port() {
+        redacted.push_str(&format!(":{}", port));
+    }
+    let segments: Vec<&str> = parsed
+        .path_segments()
+        .map(|segments| segments.filter(|s| !s.is_empty()).collect())
+        .unwrap_or_default();
+    if let Some((file, directories)) = segments.split_last() {
+        if !directories.is_empty() {
+            redacted.push_str("/***");
+        }
+        redacted.push('/');
+        redacted.push_str(file);
+    }
+    if parsed.query().is_some() {
+        redacted.push_str("?***");
+    }
+    redacted
+}
+
+/// An error with its causes, e.g. "Failed to send request to ...: connection refused".
+pub fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        message.push_str(&format!(": {}", cause));
+        source = cause.source();
+    }
+    message
+}
+
+/// Span around each request, with its ID and route template. The path itself is
+/// left out, since it may contain a key.
+pub fn request_span(request: &Request) -> Span {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("-");
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("-", MatchedPath::as_str);
+    tracing::info_span!("request", request_id, method = %request.method(), route)
+}
+
+/// Logs the outcome of a request, inside its span.
+pub fn log_response(response: &Response, latency: Duration, _span: &Span) {
+    tracing::info!(
+        status = response.status().as_u16(),
+        duration_ms = u64::try_from(latency.as_millis()).unwrap_or(u64::MAX),
+        "finished request"
+    );
+}
+
+/// Makes the request's ID available to `current_request_id` while it's handled.
+pub async fn scope_request_id(request: Request, next: Next) -> Response {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    REQUEST_ID.scope(request_id, next.run(request)).await
+}
+
+/// The ID of the request being handled, if any.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok().flatten()
+}
+
+/// `tokio::spawn`, keeping the current span and request ID, so the task's logs
+/// and upstream fetches can be tied back to the request.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(REQUEST_ID.scope(current_request_id(), future.in_current_span()))
+}
+
+/// `JoinSet::spawn`, keeping the current span and request ID like `spawn`.
+pub fn spawn_in<F>(set: &mut JoinSet<F::Output>, future: F) -> AbortHandle
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    set.spawn(REQUEST_ID.scope(current_request_id(), future.in_current_span()))
+}
+
+/// Aborts a task when dropped, e.g. along with a request whose client went away,
+/// instead of leaving it running detached.
+pub struct AbortOnDrop(pub AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
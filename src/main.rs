@@ -1,125 +1,1086 @@
+mod alerts;
+mod attachments;
+mod audit;
+mod caldav;
 mod calendar;
+mod cli;
 mod config;
+mod config_source;
+mod config_store;
+mod digest;
+mod feed;
+mod freebusy;
+mod i18n;
+mod ics;
+mod logging;
+mod metrics;
+mod migrate;
+mod notify;
+mod pinning;
+mod pipeline;
+mod quirks;
+mod ratelimit;
+mod redis;
+mod sanitize;
+mod signing;
+mod store;
+mod synthetic;
+#[cfg(unix)]
+mod systemd;
+mod templates;
+mod timezone;
+mod units;
+mod usage;
+mod view;
+mod windows_zones;
+mod zip;
 
+use attachments::AttachmentPolicy;
+use audit::AuthFailures;
 use axum::{
-    extract::{Path, State},
-    http::{header, HeaderMap, StatusCode},
-    response::{IntoResponse, Response},
-    routing::get,
-    Router,
+    extract::{ConnectInfo, MatchedPath, Path, Query, RawPathParams, Request, State},
+    http::{header, HeaderMap, Method, StatusCode},
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Response},
+    routing::{any, get, post, MethodRouter},
+    Extension, Json, Router,
 };
-use calendar::CalendarService;
-use config::Config;
-use std::sync::Arc;
-use subtle::ConstantTimeEq;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use calendar::{CalendarError, CalendarService, CombinedCalendar, ErrorKind, FetchError};
+use chrono::{Duration, TimeZone, Utc};
+use clap::Parser;
+use config::{
+    AuthError, CalendarGroup, Config, ConfigError, EmptyGroupPolicy, LegacyAction, LegacyRoute,
+    UrlPrecedence,
+};
+use config_source::{ConfigSource, RemoteConfig};
+use i18n::Locale;
+use percent_encoding::percent_decode_str;
+use ratelimit::RateLimiter;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Instant;
+use store::Store;
+use tokio::task::JoinSet;
+use tower::ServiceExt;
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
 use tower_http::set_header::SetResponseHeaderLayer;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tower_http::trace::TraceLayer;
+use usage::UsageTracker;
+use webcal_combiner::api::{
+    CachePurge, ChangeLog, CombineRequest, ConfigCheck, KeyUseStats, Readiness, SourceProbe,
+    StatusResponse, SyncResponse,
+};
+
+/// Where the configuration is loaded from without `--config`, relative to the
+/// working directory: the first of these that exists, else the first.
+const DEFAULT_CONFIG_PATHS: &[&str] = &["config.json", "config.yaml", "config.yml", "config.toml"];
+
+/// The path given with `--config`, set once at startup.
+static CONFIG_PATH: OnceLock<String> = OnceLock::new();
+
+fn config_path() -> &'static str {
+    match CONFIG_PATH.get() {
+        Some(path) => path,
+        None => DEFAULT_CONFIG_PATHS
+            .iter()
+            .find(|path| std::path::Path::new(path).exists())
+            .unwrap_or(&DEFAULT_CONFIG_PATHS[0]),
+    }
+}
+
+/// Where to load the config from: the `--config` file, else `CONFIG_URL`, else
+/// the environment when `WEBCAL_CONFIG_JSON` or `WEBCAL_CALENDARS_JSON` is set
+/// (see `Config::from_env`), else config.json.
+fn config_source() -> Result<ConfigSource, ConfigError> {
+    if CONFIG_PATH.get().is_none() {
+        if let Ok(url) = std::env::var("CONFIG_URL") {
+            let authorization = std::env::var("CONFIG_URL_AUTHORIZATION").ok();
+            return RemoteConfig::new(url, authorization).map(ConfigSource::Url);
+        }
+        if Config::env_configured() {
+            return Ok(ConfigSource::Env);
+        }
+    }
+    Ok(ConfigSource::File(config_path().to_string()))
+}
+
+/// How often a config from `CONFIG_URL` is fetched again, from
+/// `CONFIG_URL_REFRESH_SECONDS`; `None` when it is `0`.
+fn config_refresh_interval() -> Result<Option<std::time::Duration>, ConfigError> {
+    let seconds = match std::env::var("CONFIG_URL_REFRESH_SECONDS") {
+        Ok(seconds) => seconds.parse().map_err(|_| {
+            ConfigError::Invalid(format!(
+                "CONFIG_URL_REFRESH_SECONDS '{}' isn't a number of seconds",
+                seconds
+            ))
+        })?,
+        Err(_) => DEFAULT_CONFIG_REFRESH_SECONDS,
+    };
+    Ok((seconds > 0).then(|| std::time::Duration::from_secs(seconds)))
+}
+
+const DEFAULT_CONFIG_REFRESH_SECONDS: u64 = 300;
+
+/// Window used by `/freebusy` when the request doesn't specify one.
+const DEFAULT_FREEBUSY_DAYS: i64 = 30;
+/// Longest window `/freebusy` will expand recurring events over.
+const MAX_FREEBUSY_DAYS: i64 = 366;
+/// Number of weeks `/view` shows when the request doesn't specify one.
+const DEFAULT_VIEW_WEEKS: u32 = 4;
+/// Longest agenda `/view` will render.
+const MAX_VIEW_WEEKS: u32 = 26;
+/// Number of days of upcoming events included in the Atom feed by default.
+const DEFAULT_FEED_DAYS: i64 = 30;
+/// Longest window the Atom feed will cover.
+const MAX_FEED_DAYS: i64 = 366;
 
 #[derive(Clone)]
 struct AppState {
     config: Arc<Config>,
     calendar_service: CalendarService,
+    key_usage: Arc<KeyUsage>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    auth_failures: Arc<AuthFailures>,
+    usage: Arc<UsageTracker>,
+    /// Held only to stop the background checks with the state they belong to.
+    _background: Arc<Vec<BackgroundTask>>,
+}
+
+/// A task that runs until its state is dropped, e.g. once a refreshed config
+/// replaces it.
+struct BackgroundTask(tokio::task::JoinHandle<()>);
+
+impl Drop for BackgroundTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+impl AppState {
+    fn new(config: Arc<Config>, enable_cache: bool, usage: Arc<UsageTracker>) -> Self {
+        let calendar_service = CalendarService::new(enable_cache, Arc::clone(&config));
+        AppState {
+            _background: Arc::new(
+                [notify::spawn, alerts::spawn, digest::spawn]
+                    .into_iter()
+                    .filter_map(|spawn| spawn(Arc::clone(&config), calendar_service.clone()))
+                    .map(BackgroundTask)
+                    .collect(),
+            ),
+            calendar_service,
+            key_usage: Arc::new(KeyUsage::new(&config)),
+            rate_limiter: config
+                .rate_limit
+                .as_ref()
+                .map(|limit| Arc::new(RateLimiter::new(limit))),
+            auth_failures: Arc::new(AuthFailures::new(config.lockout.clone())),
+            usage,
+            config,
+        }
+    }
+
+    /// Checks the request's key against `group` ("*" for every group), recording
+    /// uses of deprecated keys.
+    ///
+    /// With `header_auth`, a key sent in an `Authorization` header takes
+    /// precedence over `path_key`, so the URL can carry a placeholder instead.
+    fn authorize(
+        &self,
+        headers: &HeaderMap,
+        path_key: &str,
+        group: &str,
+    ) -> Result<(), AuthError> {
+        let header_key = self
+            .config
+            .header_auth
+            .then(|| header_key(headers))
+            .flatten();
+        let key = header_key.as_deref().unwrap_or(path_key);
+
+        let access_key = self.config.authorize(key, group)?;
+        if access_key.deprecated {
+            tracing::warn!(
+                "Request for '{}' used deprecated key '{}'",
+                group,
+                access_key.name
+            );
+            self.key_usage.record(&access_key.name);
+        }
+        self.throttle(&format!("key:{}", access_key.name))?;
+        if group != "*" {
+            self.usage.record(&access_key.name, group);
+        }
+        Ok(())
+    }
+
+    /// Checks the request's key for a listing of the groups it may read,
+    /// returning the groups.
+    fn readable_groups(
+        &self,
+        headers: &HeaderMap,
+        path_key: &str,
+    ) -> Result<Vec<&CalendarGroup>, AuthError> {
+        let header_key = self
+            .config
+            .header_auth
+            .then(|| header_key(headers))
+            .flatten();
+        let key = header_key.as_deref().unwrap_or(path_key);
+
+        let access_key = self.config.find_key(key)?;
+        if access_key.deprecated {
+            tracing::warn!("Group listing used deprecated key '{}'", access_key.name);
+            self.key_usage.record(&access_key.name);
+        }
+        self.throttle(&format!("key:{}", access_key.name))?;
+        Ok(self
+            .config
+            .calendars
+            .iter()
+            .filter(|group| access_key.allows(&group.name))
+            .collect())
+    }
+
+    /// Applies `rate_limit` to one client.
+    fn throttle(&self, client: &str) -> Result<(), AuthError> {
+        let Some(ref limiter) = self.rate_limiter else {
+            return Ok(());
+        };
+        limiter.check(client).map_err(|retry_after| {
+            tracing::debug!("Rate limited {}, retry after {:?}", client, retry_after);
+            AuthError::RateLimited { retry_after }
+        })
+    }
+}
+
+/// Turns away banned clients, and records every 401 in the audit log and
+/// towards `lockout`. With `header_auth`, a 401 to a request without an
+/// `Authorization` header is only the challenge Basic-auth clients get before
+/// they send the password, so it isn't counted.
+async fn audit_auth(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    route: MatchedPath,
+    request: Request,
+    next: Next,
+) -> Response {
+    let ip = client_ip(&state.config, request.headers(), addr);
+    if let Some(remaining) = state.auth_failures.banned_for(ip) {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RETRY_AFTER, (remaining.as_secs() + 1).into());
+        return (StatusCode::TOO_MANY_REQUESTS, headers, "Too many failed attempts")
+            .into_response();
+    }
+
+    let carried_credential =
+        !state.config.header_auth || request.headers().contains_key(header::AUTHORIZATION);
+    // Keep attempted keys out of the log; they may be near-misses of real ones.
+    // The key is found by its position in the route, since the path is still
+    // percent-encoded.
+    let key_segment = route
+        .as_str()
+        .split('/')
+        .position(|segment| segment == "{key}");
+    let path = request
+        .uri()
+        .path()
+        .split('/')
+        .enumerate()
+        .map(|(i, segment)| {
+            if Some(i) == key_segment {
+                "***"
+            } else {
+                segment
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+    let user_agent = request
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("-")
+        .to_string();
+
+    let response = next.run(request).await;
+    if response.status() == StatusCode::UNAUTHORIZED && carried_credential {
+        state.auth_failures.record(ip, &path, &user_agent);
+    }
+    response
+}
+
+/// Bounds the total time spent on a request, using the group's
+/// `generation_timeout_seconds` when it sets one. The handler runs on its own
+/// task, so the deadline holds even while it's busy merging rather than waiting
+/// on an upstream; it's aborted once the deadline passes, or when the client
+/// disconnects and the request is dropped.
+async fn generation_timeout(
+    State(state): State<AppState>,
+    route: MatchedPath,
+    params: RawPathParams,
+    request: Request,
+    next: Next,
+) -> Response {
+    let cal_name = params
+        .iter()
+        .find(|(name, _)| *name == "cal_name")
+        .map(|(_, cal_name)| {
+            let cal_name = percent_decode_str(cal_name).decode_utf8_lossy();
+            state.config.group_name(&cal_name).to_string()
+        });
+    let group_timeout = cal_name
+        .as_ref()
+        .and_then(|cal_name| state.config.get_calendar_map().get(cal_name))
+        .and_then(|group| group.generation_timeout_seconds);
+    let seconds = group_timeout.unwrap_or(state.config.generation_timeout_seconds);
+
+    // The route template rather than the path, which may contain a key
+    let target = match cal_name {
+        Some(cal_name) => format!("{} ('{}')", route.as_str(), cal_name),
+        None => route.as_str().to_string(),
+    };
+    let handler = logging::spawn(next.run(request));
+    let _abort = logging::AbortOnDrop(handler.abort_handle());
+    match tokio::time::timeout(std::time::Duration::from_secs(seconds), handler).await {
+        Ok(Ok(response)) => response,
+        Ok(Err(e)) => {
+            tracing::error!("Handler for {} failed: {:?}", target, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+        Err(_) => {
+            tracing::warn!("Request for {} timed out after {}s", target, seconds);
+            let mut headers = HeaderMap::new();
+            headers.insert(header::RETRY_AFTER, seconds.into());
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                headers,
+                "Timed out generating the response",
+            )
+                .into_response()
+        }
+    }
+}
+
+/// The base URL links to this service are built from, following
+/// `url_precedence`. A `url` that doesn't match the host the request came in on
+/// is logged once, since it usually means the config is stale after a move.
+fn base_url(state: &AppState, headers: &HeaderMap) -> String {
+    static MISMATCH_LOGGED: AtomicBool = AtomicBool::new(false);
+    let configured = &state.config.url;
+    let from_request = request_url(headers);
+
+    if let Some(ref request_url) = from_request {
+        if !configured.is_empty()
+            && !url_host_matches(configured, request_url)
+            && !MISMATCH_LOGGED.swap(true, Ordering::Relaxed)
+        {
+            tracing::warn!(
+                "Configured url {} doesn't match request URL {}; set url_precedence \
+                 to \"request\" if the service has moved",
+                configured,
+                request_url
+            );
+        }
+    }
+
+    match (state.config.url_precedence, from_request) {
+        (UrlPrecedence::Request, Some(request_url)) => request_url,
+        (UrlPrecedence::Config, Some(request_url)) if configured.is_empty() => request_url,
+        _ => configured.clone(),
+    }
+}
+
+/// Reconstructs the public base URL from `X-Forwarded-Proto`/`-Host`/`-Prefix`,
+/// or the `Host` header when not behind a proxy.
+fn request_url(headers: &HeaderMap) -> Option<String> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next())
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+    };
+
+    let host = header("x-forwarded-host").or_else(|| header(header::HOST.as_str()))?;
+    let scheme = header("x-forwarded-proto").unwrap_or("http");
+    let prefix = header("x-forwarded-prefix").unwrap_or("").trim_end_matches('/');
+    Some(format!("{}://{}{}", scheme, host, prefix))
+}
+
+fn url_host_matches(a: &str, b: &str) -> bool {
+    let host = |url: &str| {
+        let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+        rest.split('/').next().unwrap_or(rest).to_ascii_lowercase()
+    };
+    host(a) == host(b)
+}
+
+/// The client's address, from `client_ip_header` when configured and valid.
+fn client_ip(config: &Config, headers: &HeaderMap, addr: SocketAddr) -> IpAddr {
+    config
+        .client_ip_header
+        .as_deref()
+        .and_then(|name| headers.get(name))
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .and_then(|ip| ip.trim().parse().ok())
+        .unwrap_or(addr.ip())
+}
+
+/// The secret of an `Authorization: Bearer <key>` or `Authorization: Basic`
+/// header. Basic credentials use the password as the key; the username is ignored.
+fn header_key(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, credentials) = value.trim().split_once(' ')?;
+    let credentials = credentials.trim();
+
+    if scheme.eq_ignore_ascii_case("Bearer") {
+        Some(credentials.to_string())
+    } else if scheme.eq_ignore_ascii_case("Basic") {
+        let decoded = BASE64_STANDARD.decode(credentials).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (_, password) = decoded.split_once(':')?;
+        Some(password.to_string())
+    } else {
+        None
+    }
+}
+
+/// Uses of each deprecated key since startup, so operators can tell when a
+/// rotated-out key is safe to remove.
+struct KeyUsage {
+    /// Use count and time of last use, by key name.
+    keys: Mutex<BTreeMap<String, (u64, Option<Instant>)>>,
+}
+
+impl KeyUsage {
+    fn new(config: &Config) -> Self {
+        let keys = config
+            .keys
+            .iter()
+            .filter(|access_key| access_key.deprecated)
+            .map(|access_key| (access_key.name.clone(), (0, None)))
+            .collect();
+        Self {
+            keys: Mutex::new(keys),
+        }
+    }
+
+    fn record(&self, name: &str) {
+        if let Some((uses, last_used)) = self.keys.lock().unwrap().get_mut(name) {
+            *uses += 1;
+            *last_used = Some(Instant::now());
+        }
+    }
+
+    fn stats(&self) -> BTreeMap<String, KeyUseStats> {
+        self.keys
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, &(uses, last_used))| {
+                let stats = KeyUseStats {
+                    uses,
+                    last_used_seconds_ago: last_used.map(|at| at.elapsed().as_secs()),
+                };
+                (name.clone(), stats)
+            })
+            .collect()
+    }
+}
+
+/// Restores the config from `backup`, or from the newest backup. A backup that
+/// doesn't load is refused, so a rollback can't swap one broken config for
+/// another.
+fn rollback(backup: Option<String>) -> anyhow::Result<()> {
+    let path = std::path::Path::new(config_path());
+    let backup = match backup {
+        Some(backup) => std::path::PathBuf::from(backup),
+        None => config_store::backups(path)?
+            .pop()
+            .ok_or_else(|| anyhow::anyhow!("No backups of {} to roll back to", config_path()))?,
+    };
+    let restored = Config::load(&backup.to_string_lossy()).map_err(|e| {
+        anyhow::anyhow!(
+            "Refusing to restore {}: {}",
+            backup.display(),
+            logging::error_chain(&e)
+        )
+    })?;
+    config_store::rollback(path, &backup, restored.config_backups)?;
+    tracing::info!("Restored {} from {}", config_path(), backup.display());
+    Ok(())
+}
+
+/// Appends a source to `group` in the config file, through `config_store` so
+/// the file is replaced atomically and the old one backed up. Only JSON configs
+/// are rewritten, since YAML and TOML would lose their comments, and the result
+/// must load before it's written.
+fn add_source(group: &str, name: &str, url: &str, description: Option<&str>) -> anyhow::Result<()> {
+    let path = config_path();
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path, e))?;
+    if config::Format::of(path) != config::Format::Json {
+        anyhow::bail!(
+            "{} isn't JSON, and rewriting it would drop its comments; add the source by hand",
+            path
+        );
+    }
+    let mut value: serde_json::Value = serde_json::from_str(&content).map_err(|e| {
+        anyhow::anyhow!("{} isn't plain JSON, so it can't be rewritten: {}", path, e)
+    })?;
+    let sources = value
+        .get_mut("calendars")
+        .and_then(serde_json::Value::as_array_mut)
+        .and_then(|groups| {
+            groups
+                .iter_mut()
+                .find(|g| g.get("name").and_then(serde_json::Value::as_str) == Some(group))
+        })
+        .and_then(|g| g.get_mut("calendars"))
+        .and_then(serde_json::Value::as_array_mut)
+        .ok_or_else(|| anyhow::anyhow!("{} has no group '{}' in calendars", path, group))?;
+    if sources
+        .iter()
+        .any(|source| source.get("name").and_then(serde_json::Value::as_str) == Some(name))
+    {
+        anyhow::bail!("Group '{}' already has a source named '{}'", group, name);
+    }
+    sources.push(serde_json::json!({
+        "name": name,
+        "description": description.unwrap_or(name),
+        "url": url,
+    }));
+
+    let content = format!("{}\n", serde_json::to_string_pretty(&value)?);
+    let config = Config::parse(path, &content)
+        .map_err(|e| anyhow::anyhow!("Refusing to write {}: {}", path, logging::error_chain(&e)))?;
+    config_store::write(std::path::Path::new(path), &content, config.config_backups)?;
+    tracing::info!("Added source '{}' to '{}' in {}", name, group, path);
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() {
     // Initialize tracing
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "webcal_combiner=info,tower_http=info".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+    logging::init();
+
+    let args = cli::Args::parse();
+    if let Some(ref path) = args.config {
+        CONFIG_PATH.set(path.clone()).expect("set only here");
+    }
+    if args.check_config {
+        let checked = match config_source() {
+            Ok(source) => cli::check_config(source, args.probe).await,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                false
+            }
+        };
+        if !checked {
+            std::process::exit(1);
+        }
+        return;
+    }
+    match args.command {
+        None => {}
+        Some(cli::Command::Rollback { ref backup }) => {
+            if let Err(e) = rollback(backup.clone()) {
+                tracing::error!("Rollback failed: {:?}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(cli::Command::AddSource {
+            ref group,
+            ref name,
+            ref url,
+            ref description,
+        }) => {
+            if let Err(e) = add_source(group, name, url, description.as_deref()) {
+                tracing::error!("Adding the source failed: {:?}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+    }
 
     // Load configuration
-    let config = match Config::load("config.json") {
-        Ok(config) => {
-            tracing::info!("Configuration loaded successfully");
+    let (mut source, refresh) =
+        match config_source().and_then(|source| Ok((source, config_refresh_interval()?))) {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                tracing::error!("Invalid config source: {:?}", e);
+                std::process::exit(1);
+            }
+        };
+    let config = match source.load().await {
+        Ok(mut config) => {
+            tracing::info!("Configuration loaded from {}", source);
+            if let Some(port) = args.port {
+                config.server_port = port;
+            }
+            if let Some(bind) = args.bind {
+                config.bind_address = Some(bind);
+            }
             Arc::new(config)
         }
         Err(e) => {
-            tracing::error!("Failed to load config.json: {:?}", e);
+            tracing::error!("Failed to load {}: {:?}", source, e);
             std::process::exit(1);
         }
     };
 
+    // Bring persisted state up to date before anything reads it
+    if let Some(ref state_dir) = config.state_dir {
+        if let Err(e) = migrate::run(std::path::Path::new(state_dir)) {
+            tracing::error!("Failed to migrate state directory: {:?}", e);
+            std::process::exit(1);
+        }
+    }
+
     // Check if caching is enabled
-    let enable_cache = std::env::var("ENABLE_CACHE")
-        .unwrap_or_else(|_| "false".to_string())
-        .parse::<bool>()
-        .unwrap_or(false);
+    let enable_cache = args.enable_cache();
 
     if enable_cache {
         tracing::info!("Calendar caching is ENABLED (5 minute TTL)");
+        let limited = config.cache_max_entries.is_some() || config.cache_max_bytes.is_some();
+        if limited && config.redis_cache.is_some() {
+            tracing::warn!(
+                "cache_max_entries and cache_max_bytes don't apply to redis_cache; limit Redis with maxmemory instead"
+            );
+        }
     } else {
         tracing::info!("Calendar caching is DISABLED");
+        if config.adaptive_refresh.is_some() {
+            tracing::warn!("adaptive_refresh has no effect without --enable-cache");
+        }
     }
 
-    // Create calendar service
-    let calendar_service = CalendarService::new(enable_cache, Arc::clone(&config));
+    warn_insecure_sources(&config);
 
     // Create shared state
-    let state = AppState {
-        config,
-        calendar_service,
-    };
+    let state = AppState::new(config, enable_cache, Arc::new(UsageTracker::new()));
 
-    // Get server port
-    let server_port = state.config.server_port;
+    // Where to listen; --port or --bind choose TCP over the config's unix_socket
+    let config = &state.config;
+    let addr = SocketAddr::new(
+        config.bind_address.unwrap_or(IpAddr::from([0, 0, 0, 0])),
+        config.server_port,
+    );
+    let unix_socket = (args.port.is_none() && args.bind.is_none())
+        .then(|| config.unix_socket.clone())
+        .flatten();
+    let unix_socket_mode = config.unix_socket_mode;
 
-    // Build router
-    let app = Router::new()
-        .route("/", get(health_check))
-        .route("/listing", get(listing))
-        .route("/calendar/{key}/{cal_name}", get(get_calendar))
-        .layer(SetResponseHeaderLayer::if_not_present(
-            header::SERVER,
-            header::HeaderValue::from_static("webcal-combiner-rust"),
-        ))
-        .with_state(state);
+    // Requests go to the current router, which a refreshed config replaces
+    let current = Arc::new(std::sync::RwLock::new(router(state.clone())));
+    if let (ConfigSource::Url(remote), Some(interval)) = (source, refresh) {
+        tokio::spawn(refresh_config(
+            remote,
+            interval,
+            state,
+            enable_cache,
+            args.port,
+            args.bind,
+            Arc::clone(&current),
+        ));
+    }
+    let app = Router::new().fallback(move |request: Request| {
+        let router = current.read().expect("router lock poisoned").clone();
+        router.oneshot(request)
+    });
 
     // Start server
-    let addr = format!("0.0.0.0:{}", server_port);
+    #[cfg(unix)]
+    match systemd::take_listener() {
+        Ok(Some(systemd::Listener::Tcp(listener))) => return serve_tcp(app, listener).await,
+        Ok(Some(systemd::Listener::Unix(listener))) => return serve_unix(app, listener).await,
+        Ok(None) => {}
+        Err(e) => {
+            tracing::error!("Failed to use the socket passed by systemd: {:?}", e);
+            std::process::exit(1);
+        }
+    }
+    #[cfg(unix)]
+    if let Some(path) = unix_socket {
+        return serve_unix(app, bind_unix(&path, unix_socket_mode)).await;
+    }
     let listener = match tokio::net::TcpListener::bind(&addr).await {
         Ok(listener) => listener,
         Err(e) => {
-            tracing::error!("Failed to bind to port {}: {:?}", server_port, e);
+            tracing::error!("Failed to bind to {}: {:?}", addr, e);
+            std::process::exit(1);
+        }
+    };
+    serve_tcp(app, listener).await;
+}
+
+async fn serve_tcp(app: Router, listener: tokio::net::TcpListener) {
+    if let Ok(addr) = listener.local_addr() {
+        tracing::info!("Server listening on {}", addr);
+    }
+    #[cfg(unix)]
+    systemd::ready();
+
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    if let Err(e) = axum::serve(listener, app).await {
+        tracing::error!("Server failed to start: {:?}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Binds a Unix domain socket at `path`, replacing a socket left behind by an
+/// earlier run.
+#[cfg(unix)]
+fn bind_unix(path: &str, mode: Option<u32>) -> tokio::net::UnixListener {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    let metadata = std::fs::symlink_metadata(path);
+    if metadata.is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            tracing::error!("{} is in use by another server", path);
+            std::process::exit(1);
+        }
+        if let Err(e) = std::fs::remove_file(path) {
+            tracing::error!("Failed to remove the stale socket {}: {:?}", path, e);
+            std::process::exit(1);
+        }
+    }
+    let listener = match tokio::net::UnixListener::bind(path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            tracing::error!("Failed to bind to {}: {:?}", path, e);
             std::process::exit(1);
         }
     };
+    if let Some(mode) = mode {
+        let permissions = std::fs::Permissions::from_mode(mode);
+        if let Err(e) = std::fs::set_permissions(path, permissions) {
+            tracing::error!("Failed to set the permissions of {}: {:?}", path, e);
+            std::process::exit(1);
+        }
+    }
+    listener
+}
 
-    tracing::info!("Server listening on {}", addr);
+/// Serves `app` on a Unix domain socket. Such connections have no client IP, so
+/// requests count as coming from 127.0.0.1 unless `client_ip_header` names the
+/// proxy's header.
+#[cfg(unix)]
+async fn serve_unix(app: Router, listener: tokio::net::UnixListener) {
+    let path = listener
+        .local_addr()
+        .ok()
+        .and_then(|addr| addr.as_pathname().map(|path| path.display().to_string()));
+    match path {
+        Some(path) => tracing::info!("Server listening on {}", path),
+        None => tracing::info!("Server listening on an unnamed Unix socket"),
+    }
+    systemd::ready();
 
+    let local = SocketAddr::from(([127, 0, 0, 1], 0));
+    let app = app.layer(Extension(ConnectInfo(local)));
     if let Err(e) = axum::serve(listener, app).await {
         tracing::error!("Server failed to start: {:?}", e);
         std::process::exit(1);
     }
 }
 
+fn warn_insecure_sources(config: &Config) {
+    for group in &config.calendars {
+        for cal in &group.calendars {
+            if cal.insecure_skip_verify() && cal.spki_sha256().is_empty() {
+                tracing::warn!(
+                    "Group '{}': source '{}' skips certificate verification without a spki_sha256 pin",
+                    group.name,
+                    cal.name
+                );
+            }
+        }
+    }
+}
+
+/// Fetches the config from `remote` every `interval` and, when it changed and
+/// is valid, swaps in a router serving it. A failed fetch or an invalid config
+/// is logged and the current config stays in use. Usage statistics carry over,
+/// while the cache, rate limits and lockouts start afresh.
+async fn refresh_config(
+    mut remote: RemoteConfig,
+    interval: std::time::Duration,
+    mut state: AppState,
+    enable_cache: bool,
+    port: Option<u16>,
+    bind: Option<IpAddr>,
+    current: Arc<std::sync::RwLock<Router>>,
+) {
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        let mut config = match remote.refresh().await {
+            Ok(Some(config)) => config,
+            Ok(None) => continue,
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to refresh the config, keeping the current one: {}",
+                    logging::error_chain(&e)
+                );
+                continue;
+            }
+        };
+        if let Some(port) = port {
+            config.server_port = port;
+        }
+        if let Some(bind) = bind {
+            config.bind_address = Some(bind);
+        }
+        let listen = |config: &Config| {
+            (
+                config.server_port,
+                config.bind_address,
+                config.unix_socket.clone(),
+                config.unix_socket_mode,
+            )
+        };
+        if listen(&config) != listen(&state.config) {
+            tracing::warn!(
+                "The refreshed config changes where the server listens, which takes effect on restart"
+            );
+        }
+        warn_insecure_sources(&config);
+        state = AppState::new(Arc::new(config), enable_cache, Arc::clone(&state.usage));
+        *current.write().expect("router lock poisoned") = router(state.clone());
+        tracing::info!("Configuration refreshed");
+    }
+}
+
+/// The routes, serving `state`.
+fn router(state: AppState) -> Router {
+    let mut app = Router::new();
+    for path in config::ROUTES {
+        app = app.route(path, handler(path));
+    }
+    for route in &state.config.legacy_routes {
+        let legacy = Arc::new(route.clone());
+        app = app.route(
+            &route.route_path(),
+            get(
+                move |State(state): State<AppState>,
+                      Path(params): Path<HashMap<String, String>>,
+                      Query(query): Query<HashMap<String, String>>,
+                      headers: HeaderMap| async move {
+                    legacy_route(&state, &legacy, params, query, &headers).await
+                },
+            ),
+        );
+    }
+    app = app
+        .route_layer(middleware::from_fn_with_state(state.clone(), generation_timeout))
+        .route_layer(middleware::from_fn_with_state(state.clone(), audit_auth))
+        .route_layer(
+            TraceLayer::new_for_http()
+                .make_span_with(logging::request_span)
+                .on_response(logging::log_response)
+                .on_failure(()),
+        )
+        .route_layer(middleware::from_fn(logging::scope_request_id))
+        // Outside the audit and trace layers, so a banned address can't fail
+        // health checks and probes don't flood the log
+        .route(config::PROBE_ROUTES[0], get(health_check))
+        .route(config::PROBE_ROUTES[1], get(readiness))
+        .layer(PropagateRequestIdLayer::new(
+            header::HeaderName::from_static(logging::REQUEST_ID_HEADER),
+        ))
+        .layer(SetRequestIdLayer::new(
+            header::HeaderName::from_static(logging::REQUEST_ID_HEADER),
+            MakeRequestUuid,
+        ))
+        .layer(SetResponseHeaderLayer::if_not_present(
+            header::SERVER,
+            header::HeaderValue::from_static("webcal-combiner-rust"),
+        ));
+    app.with_state(state)
+}
+
+/// The handler of one of `config::ROUTES`.
+fn handler(path: &str) -> MethodRouter<AppState> {
+    match path {
+        "/listing" => get(listing),
+        "/status/{key}" => get(status),
+        "/calendar/{key}/{cal_name}" => get(get_calendar),
+        "/calendar/signed/{cal_name}" => get(get_signed_calendar),
+        "/attachment/{cal_name}/{id}" => get(get_attachment),
+        "/sign/{key}/{cal_name}" => get(sign_calendar),
+        "/calendar/{key}/{cal_name}/feed.xml" => get(get_feed),
+        "/calendar/{key}/{cal_name}/changes" => get(get_changes),
+        "/calendar/{key}/{cal_name}/sync" => get(get_sync),
+        "/source/{key}/{cal_name}/{source}" => get(get_source),
+        "/export/{key}" => get(get_export),
+        "/freebusy/{key}/{cal_name}" => get(get_freebusy),
+        "/view/{key}/{cal_name}" => get(get_view),
+        "/digest/{key}/{cal_name}" => get(get_digest),
+        "/combine/{key}" => post(post_combine),
+        "/caldav/{key}" | "/caldav/{key}/" => any(caldav_home),
+        "/caldav/{key}/{cal_name}" | "/caldav/{key}/{cal_name}/" => any(caldav_calendar),
+        "/caldav/{key}/{cal_name}/{object}" => any(caldav_object),
+        "/admin/explain/{key}/{cal_name}" => get(get_explain),
+        "/admin/usage/{key}" => get(get_usage),
+        "/admin/cache/stats/{key}" => get(get_cache_stats),
+        "/admin/cache/purge/{key}" => post(purge_cache),
+        "/metrics/{key}" => get(get_metrics),
+        _ => unreachable!("route {} has no handler", path),
+    }
+}
+
 async fn health_check() -> impl IntoResponse {
     ""
 }
 
-async fn listing(State(state): State<AppState>) -> impl IntoResponse {
+/// Readiness for Kubernetes: the config must still load, and with
+/// `readiness_probe`, the next few sources in turn are probed. It only reports
+/// not ready when the config is broken or every probed source failed, since one
+/// broken upstream doesn't stop the other sources from being served.
+async fn readiness(State(state): State<AppState>) -> Response {
+    static NEXT_SOURCE: AtomicUsize = AtomicUsize::new(0);
+
+    // A config from a URL isn't fetched again here: while the server is down,
+    // the last one that loaded keeps being served
+    let loaded = match config_source() {
+        Ok(ConfigSource::Url(_)) => Ok(()),
+        Ok(mut source) => source.load().await.map(drop),
+        Err(e) => Err(e),
+    };
+    let config = match loaded {
+        Ok(()) => ConfigCheck {
+            valid: true,
+            error: None,
+        },
+        Err(e) => ConfigCheck {
+            valid: false,
+            error: Some(logging::error_chain(&e)),
+        },
+    };
+
+    let mut probes = JoinSet::new();
+    if let Some(ref probe) = state.config.readiness_probe {
+        let sources: Vec<_> = state
+            .config
+            .calendars
+            .iter()
+            .flat_map(|group| group.calendars.iter().map(move |cal| (group, cal)))
+            .collect();
+        let count = probe.sources.min(sources.len());
+        let first = NEXT_SOURCE.fetch_add(count, Ordering::Relaxed);
+        for i in 0..count {
+            let (group, cal) = sources[(first + i) % sources.len()];
+            let service = state.calendar_service.clone();
+            let url = cal.url.clone();
+            let seconds = probe.timeout_seconds;
+            let (group, source) = (group.name.clone(), cal.name.clone());
+            logging::spawn_in(&mut probes, async move {
+                let started = Instant::now();
+                let result = service.probe(&url, seconds).await;
+                (i, group, source, result, started.elapsed())
+            });
+        }
+    }
+
+    let mut sources = Vec::new();
+    while let Some(joined) = probes.join_next().await {
+        let (i, group, source, result, elapsed) = match joined {
+            Ok(probed) => probed,
+            Err(e) => {
+                tracing::error!("Readiness probe failed: {:?}", e);
+                continue;
+            }
+        };
+        let status = match result {
+            Ok(status) | Err(FetchError::Status { status, .. }) => Some(status.as_u16()),
+            Err(_) => None,
+        };
+        let probe = SourceProbe {
+            group,
+            source,
+            reachable: result.is_ok(),
+            status,
+            duration_ms: u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX),
+            error: result.err().map(|e| logging::error_chain(&e)),
+        };
+        sources.push((i, probe));
+    }
+    sources.sort_by_key(|(i, _)| *i);
+    let sources: Vec<SourceProbe> = sources.into_iter().map(|(_, probe)| probe).collect();
+
+    let ready = config.valid && (sources.is_empty() || sources.iter().any(|s| s.reachable));
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(Readiness {
+            ready,
+            config,
+            sources,
+        }),
+    )
+        .into_response()
+}
+
+async fn listing(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let base_url = base_url(&state, &headers);
     let mut output = String::new();
 
+    // The listing is public, so source URLs are redacted like in logs
     for group in &state.config.calendars {
         output.push_str(&format!(
             "{}: {}/calendar/{{key}}/{}\n",
-            group.name, state.config.url, group.name
+            group.name,
+            base_url,
+            group.path_name()
         ));
 
         for cal in &group.calendars {
             output.push_str(&format!(
                 "  - {} ({}): {}\n",
-                cal.name, cal.description, cal.url
+                cal.name,
+                cal.description,
+                logging::redact_url(&cal.url)
+            ));
+        }
+        for cal in &group.disabled_calendars {
+            output.push_str(&format!(
+                "  - {} ({}): {} [disabled]\n",
+                cal.name,
+                cal.description,
+                logging::redact_url(&cal.url)
             ));
         }
 
         output.push('\n');
     }
 
+    for group in &state.config.disabled_groups {
+        output.push_str(&format!("{}: [disabled]\n", group.name));
+        for cal in group.calendars.iter().chain(&group.disabled_calendars) {
+            output.push_str(&format!(
+                "  - {} ({}): {}\n",
+                cal.name,
+                cal.description,
+                logging::redact_url(&cal.url)
+            ));
+        }
+        output.push('\n');
+    }
+
     (
         StatusCode::OK,
         [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
@@ -127,87 +1088,1459 @@ async fn listing(State(state): State<AppState>) -> impl IntoResponse {
     )
 }
 
-async fn get_calendar(
+async fn status(
     State(state): State<AppState>,
-    Path((key, cal_name)): Path<(String, String)>,
+    Path(key): Path<String>,
+    headers: HeaderMap,
 ) -> Response {
-    // Constant-time comparison for key validation
-    let key_valid: bool = key.as_bytes().ct_eq(state.config.key.as_bytes()).into();
-
-    if !key_valid {
-        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    if let Err(e) = state.authorize(&headers, &key, "*") {
+        return auth_rejection(&state, e);
     }
 
-    // Handle "all-calendars" special case
-    if cal_name == "all-calendars" {
-        let all_calendars = state.config.get_all_calendars();
+    Json(StatusResponse {
+        cache: state.calendar_service.cache_stats().await,
+        pipeline: state.calendar_service.pipeline_stats(),
+        errors: state.calendar_service.error_stats(),
+        deprecated_keys: state.key_usage.stats(),
+        shadow: state.calendar_service.shadow_stats(),
+        sources: state.calendar_service.source_status(),
+    })
+    .into_response()
+}
 
-        match state
-            .calendar_service
-            .combine_all_calendars(&all_calendars)
-            .await
-        {
-            Ok(calendar_data) => {
-                let mut headers = HeaderMap::new();
+/// Maps an auth failure to its response. The scope is checked before the group
+/// is looked up, so keys can't probe for groups they aren't allowed to read.
+/// With `header_auth`, a 401 also challenges for Basic credentials, which is
+/// what prompts calendar apps to ask for a password.
+fn auth_rejection(state: &AppState, error: AuthError) -> Response {
+    let mut headers = HeaderMap::new();
+    let status = match error {
+        AuthError::UnknownKey => {
+            if state.config.header_auth {
                 headers.insert(
-                    header::CONTENT_TYPE,
-                    "text/calendar; charset=utf-8".parse().unwrap(),
+                    header::WWW_AUTHENTICATE,
+                    header::HeaderValue::from_static("Basic realm=\"webcal-combiner\""),
                 );
-                headers.insert(
-                    header::CONTENT_DISPOSITION,
-                    "attachment; filename=all-calendars.ics".parse().unwrap(),
-                );
-
-                (StatusCode::OK, headers, calendar_data).into_response()
-            }
-            Err(e) => {
-                tracing::error!("Failed to generate all-calendars: {:?}", e);
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("Failed to generate calendar: {}", e),
-                )
-                    .into_response()
             }
+            StatusCode::UNAUTHORIZED
+        }
+        AuthError::OutOfScope => StatusCode::FORBIDDEN,
+        AuthError::RateLimited { retry_after } => {
+            // Round up so clients never retry before a token is available
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            headers.insert(header::RETRY_AFTER, seconds.into());
+            StatusCode::TOO_MANY_REQUESTS
         }
+    };
+    (status, headers, error.to_string()).into_response()
+}
+
+/// The status reported for a service error: upstream failures are gateway
+/// errors so alerts can tell them apart from bugs in the combiner.
+fn error_status(error: &CalendarError) -> StatusCode {
+    match error.kind() {
+        ErrorKind::UpstreamTimeout => StatusCode::GATEWAY_TIMEOUT,
+        ErrorKind::UpstreamError | ErrorKind::PinMismatch => StatusCode::BAD_GATEWAY,
+        ErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Looks up a group by name, including the "all-calendars" pseudo-group. Empty
+/// groups are hidden when `empty_groups` is `not_found`.
+fn resolve_group(config: &Config, cal_name: &str) -> Option<CalendarGroup> {
+    let group = if cal_name == "all-calendars" {
+        config.all_calendars_group()
     } else {
-        // Get specific calendar
-        let calendar_map = state.config.get_calendar_map();
-
-        match calendar_map.get(&cal_name) {
-            Some(calendars) => {
-                match state
-                    .calendar_service
-                    .generate_combined_calendar(&cal_name, calendars)
-                    .await
-                {
-                    Ok(calendar_data) => {
-                        let mut headers = HeaderMap::new();
-                        headers.insert(
-                            header::CONTENT_TYPE,
-                            "text/calendar; charset=utf-8".parse().unwrap(),
-                        );
-                        headers.insert(
-                            header::CONTENT_DISPOSITION,
-                            "attachment; filename=calendar.ics".parse().unwrap(),
-                        );
-
-                        (StatusCode::OK, headers, calendar_data).into_response()
-                    }
-                    Err(e) => {
-                        tracing::error!("Failed to generate calendar '{}': {:?}", cal_name, e);
-                        (
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                            format!("Failed to generate calendar: {}", e),
-                        )
-                            .into_response()
-                    }
-                }
-            }
-            None => (
-                StatusCode::NOT_FOUND,
-                format!("Calendar '{}' not found", cal_name),
-            )
-                .into_response(),
+        config.get_calendar_map().get(cal_name)?.clone()
+    };
+
+    if group.calendars.is_empty() && config.empty_groups == EmptyGroupPolicy::NotFound {
+        return None;
+    }
+    Some(group)
+}
+
+/// Headers for a combined calendar download. Sources that couldn't meet their
+/// group's `max_staleness_seconds`, or were left out while their circuit is
+/// open, are listed in `X-Stale-Sources`.
+fn calendar_headers(filename: &str, combined: &CombinedCalendar) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        "text/calendar; charset=utf-8".parse().unwrap(),
+    );
+    headers.insert(
+        header::CONTENT_DISPOSITION,
+        format!("attachment; filename={}", filename).parse().unwrap(),
+    );
+
+    if !combined.stale_sources.is_empty() {
+        if let Ok(value) = combined.stale_sources.join(", ").parse() {
+            headers.insert("x-stale-sources", value);
         }
     }
+
+    headers
+}
+
+#[derive(Deserialize)]
+struct CalendarQuery {
+    tz: Option<String>,
+    /// Comma-separated names of the sources to merge, instead of all of them.
+    sources: Option<String>,
+}
+
+async fn get_calendar(
+    State(state): State<AppState>,
+    Path((key, cal_name)): Path<(String, String)>,
+    Query(query): Query<CalendarQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let cal_name = state.config.group_name(&cal_name).to_string();
+    if let Err(e) = state.authorize(&headers, &key, &cal_name) {
+        return auth_rejection(&state, e);
+    }
+
+    let base = base_url(&state, &headers);
+    serve_calendar(&state, &cal_name, query, &base).await
+}
+
+/// Answers a URL shape from `legacy_routes`, by redirecting to the group's
+/// calendar URL here or by serving it in place. The group and key come from the
+/// path's `{group}` and `{key}` segments, or the route's `group` and `key_param`.
+async fn legacy_route(
+    state: &AppState,
+    legacy: &LegacyRoute,
+    params: HashMap<String, String>,
+    mut query: HashMap<String, String>,
+    headers: &HeaderMap,
+) -> Response {
+    let Some(cal_name) = params.get("cal_name").or(legacy.group.as_ref()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let cal_name = state.config.group_name(cal_name);
+    let key = params
+        .get("key")
+        .cloned()
+        .or_else(|| query.remove(&legacy.key_param));
+    let query = CalendarQuery {
+        tz: query.remove("tz"),
+        sources: query.remove("sources"),
+    };
+
+    match legacy.action {
+        LegacyAction::Redirect => {
+            let Some(key) = key else {
+                return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+            };
+            let mut location = format!(
+                "{}/calendar/{}/{}",
+                base_url(state, headers),
+                percent_encoding::utf8_percent_encode(&key, config::PATH_SEGMENT),
+                state.config.group_path(cal_name)
+            );
+            let params: Vec<String> = [("tz", query.tz), ("sources", query.sources)]
+                .into_iter()
+                .filter_map(|(name, value)| {
+                    let value = value?;
+                    let value = percent_encoding::utf8_percent_encode(&value, config::PATH_SEGMENT);
+                    Some(format!("{}={}", name, value))
+                })
+                .collect();
+            if !params.is_empty() {
+                location.push_str(&format!("?{}", params.join("&")));
+            }
+            match header::HeaderValue::from_str(&location) {
+                Ok(location) => (
+                    StatusCode::MOVED_PERMANENTLY,
+                    [(header::LOCATION, location)],
+                )
+                    .into_response(),
+                Err(_) => StatusCode::BAD_REQUEST.into_response(),
+            }
+        }
+        LegacyAction::Serve => {
+            let key = key.unwrap_or_default();
+            if let Err(e) = state.authorize(headers, &key, cal_name) {
+                return auth_rejection(state, e);
+            }
+            serve_calendar(state, cal_name, query, &base_url(state, headers)).await
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SignedQuery {
+    exp: i64,
+    sig: String,
+    tz: Option<String>,
+    sources: Option<String>,
+}
+
+/// Serves a group through a URL minted by `/sign`, in place of a key.
+async fn get_signed_calendar(
+    State(state): State<AppState>,
+    Path(cal_name): Path<String>,
+    Query(query): Query<SignedQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let cal_name = state.config.group_name(&cal_name).to_string();
+    let Some(ref secret) = state.config.signing_secret else {
+        return (StatusCode::NOT_FOUND, "Signed URLs are not enabled").into_response();
+    };
+
+    let path = signing::signed_path(&cal_name);
+    if !signing::verify(secret, &path, query.exp, &query.sig) {
+        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    }
+    if query.exp <= Utc::now().timestamp() {
+        return (StatusCode::GONE, "Link expired").into_response();
+    }
+    if let Err(e) = state.throttle(&format!("signed:{}", query.sig)) {
+        return auth_rejection(&state, e);
+    }
+    let token = query.sig.get(..8).unwrap_or(&query.sig);
+    state.usage.record(&format!("signed:{}", token), &cal_name);
+
+    let base = base_url(&state, &headers);
+    let calendar_query = CalendarQuery {
+        tz: query.tz,
+        sources: query.sources,
+    };
+    serve_calendar(&state, &cal_name, calendar_query, &base).await
+}
+
+#[derive(Deserialize)]
+struct AttachmentQuery {
+    #[serde(default)]
+    sig: String,
+}
+
+/// Serves an inline attachment that a group with `attachments: "link"`
+/// replaced by a link. The link's signature stands in for a key, as a signed
+/// URL's does. An attachment no longer kept is found again by regenerating the
+/// group.
+async fn get_attachment(
+    State(state): State<AppState>,
+    Path((cal_name, id)): Path<(String, String)>,
+    Query(query): Query<AttachmentQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let not_found = || (StatusCode::NOT_FOUND, "Attachment not found").into_response();
+    let cal_name = state.config.group_name(&cal_name).to_string();
+    let Some(group) = resolve_group(&state.config, &cal_name) else {
+        return not_found();
+    };
+    if group.attachments != AttachmentPolicy::Link {
+        return not_found();
+    }
+    let service = &state.calendar_service;
+    if !service.verify_attachment(&group.name, &id, &query.sig) {
+        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    }
+    if let Err(e) = state.throttle(&format!("attachment:{}", query.sig)) {
+        return auth_rejection(&state, e);
+    }
+
+    let attachment = match service.attachment(&id).await {
+        Some(attachment) => attachment,
+        None => {
+            let base = base_url(&state, &headers);
+            if let Err(e) = service
+                .generate_combined_calendar(&group, None, &base)
+                .await
+            {
+                tracing::error!("Failed to generate calendar '{}': {:?}", cal_name, e);
+                return (error_status(&e), "Failed to look up the attachment").into_response();
+            }
+            match service.attachment(&id).await {
+                Some(attachment) => attachment,
+                None => return not_found(),
+            }
+        }
+    };
+
+    let content_type = attachment
+        .content_type
+        .as_deref()
+        .and_then(|value| header::HeaderValue::from_str(value).ok())
+        .unwrap_or(header::HeaderValue::from_static("application/octet-stream"));
+    // Content-addressed, so it never changes; served as a download so an HTML
+    // attachment can't run as this site
+    (
+        [
+            (header::CONTENT_TYPE, content_type),
+            (
+                header::CONTENT_DISPOSITION,
+                header::HeaderValue::from_static("attachment"),
+            ),
+            (
+                header::X_CONTENT_TYPE_OPTIONS,
+                header::HeaderValue::from_static("nosniff"),
+            ),
+            (
+                header::CACHE_CONTROL,
+                header::HeaderValue::from_static("public, max-age=31536000, immutable"),
+            ),
+        ],
+        attachment.bytes.clone(),
+    )
+        .into_response()
+}
+
+#[derive(Deserialize)]
+struct SignQuery {
+    ttl: Option<i64>,
+}
+
+/// Default lifetime of a minted subscription URL: 30 days.
+const DEFAULT_SIGNED_TTL: i64 = 30 * 24 * 60 * 60;
+/// Longest lifetime a minted subscription URL may have: 366 days.
+const MAX_SIGNED_TTL: i64 = 366 * 24 * 60 * 60;
+
+/// Mints a signed, expiring subscription URL for a group the key may read.
+async fn sign_calendar(
+    State(state): State<AppState>,
+    Path((key, cal_name)): Path<(String, String)>,
+    Query(query): Query<SignQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let cal_name = state.config.group_name(&cal_name).to_string();
+    if let Err(e) = state.authorize(&headers, &key, &cal_name) {
+        return auth_rejection(&state, e);
+    }
+    let Some(ref secret) = state.config.signing_secret else {
+        return (StatusCode::NOT_FOUND, "Signed URLs are not enabled").into_response();
+    };
+    if resolve_group(&state.config, &cal_name).is_none() {
+        return (
+            StatusCode::NOT_FOUND,
+            format!("Calendar '{}' not found", cal_name),
+        )
+            .into_response();
+    }
+
+    let ttl = query.ttl.unwrap_or(DEFAULT_SIGNED_TTL).clamp(1, MAX_SIGNED_TTL);
+    let expires = Utc::now().timestamp() + ttl;
+    // Signed by name, so the signature outlives a change of slug
+    let signature = signing::sign(secret, &signing::signed_path(&cal_name), expires);
+
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        format!(
+            "{}{}?exp={}&sig={}\n",
+            base_url(&state, &headers),
+            signing::signed_path(&state.config.group_path(&cal_name)),
+            expires,
+            signature
+        ),
+    )
+        .into_response()
+}
+
+/// Generates a group's combined calendar once the request is authorized.
+async fn serve_calendar(
+    state: &AppState,
+    cal_name: &str,
+    query: CalendarQuery,
+    base_url: &str,
+) -> Response {
+    let Some(mut group) = resolve_group(&state.config, cal_name) else {
+        return (
+            StatusCode::NOT_FOUND,
+            format!("Calendar '{}' not found", cal_name),
+        )
+            .into_response();
+    };
+
+    if let Some(ref sources) = query.sources {
+        let selected: Vec<&str> = sources
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .collect();
+        if selected.is_empty() {
+            return (StatusCode::BAD_REQUEST, "No sources selected").into_response();
+        }
+        let known = |name: &&str| group.calendars.iter().any(|cal| cal.name == *name);
+        if let Some(unknown) = selected.iter().find(|name| !known(name)) {
+            let message = format!("Unknown source: {}", unknown);
+            return (StatusCode::BAD_REQUEST, message).into_response();
+        }
+        group
+            .calendars
+            .retain(|cal| selected.contains(&cal.name.as_str()));
+    }
+
+    let filename = if cal_name == "all-calendars" {
+        "all-calendars.ics"
+    } else {
+        "calendar.ics"
+    };
+    render_calendar(state, &group, query.tz.as_deref(), filename, base_url).await
+}
+
+/// Generates `group`'s combined calendar in zone `tz`, or else the group's
+/// default.
+async fn render_calendar(
+    state: &AppState,
+    group: &CalendarGroup,
+    tz: Option<&str>,
+    filename: &str,
+    base_url: &str,
+) -> Response {
+    // The request's zone takes precedence over the group default
+    let tz = match tz.or(group.default_timezone()) {
+        Some(tzid) => match ics::resolve_tz(tzid) {
+            Some(tz) => Some(tz),
+            None => {
+                return (StatusCode::BAD_REQUEST, format!("Unknown timezone: {}", tzid))
+                    .into_response()
+            }
+        },
+        None => None,
+    };
+
+    match state
+        .calendar_service
+        .generate_combined_calendar(group, tz, base_url)
+        .await
+    {
+        Ok(calendar_data) => {
+            let headers = calendar_headers(filename, &calendar_data);
+            (StatusCode::OK, headers, calendar_data.data).into_response()
+        }
+        Err(e) => {
+            tracing::error!("Failed to generate calendar '{}': {:?}", group.name, e);
+            (
+                error_status(&e),
+                format!("Failed to generate calendar: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SourceQuery {
+    #[serde(default)]
+    filtered: bool,
+    tz: Option<String>,
+}
+
+/// Serves one source of a group behind the combiner's key and cache, so its
+/// upstream URL stays private: as upstream served it, or with `?filtered=true`
+/// run through the group's filters and transforms on its own.
+async fn get_source(
+    State(state): State<AppState>,
+    Path((key, cal_name, source)): Path<(String, String, String)>,
+    Query(query): Query<SourceQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let cal_name = state.config.group_name(&cal_name).to_string();
+    if let Err(e) = state.authorize(&headers, &key, &cal_name) {
+        return auth_rejection(&state, e);
+    }
+
+    let Some(mut group) = resolve_group(&state.config, &cal_name) else {
+        return (
+            StatusCode::NOT_FOUND,
+            format!("Calendar '{}' not found", cal_name),
+        )
+            .into_response();
+    };
+    group.calendars.retain(|cal| cal.name == source);
+    if group.calendars.is_empty() {
+        return (
+            StatusCode::NOT_FOUND,
+            format!("Source '{}' not found in '{}'", source, cal_name),
+        )
+            .into_response();
+    }
+
+    if query.filtered {
+        let base = base_url(&state, &headers);
+        return render_calendar(&state, &group, query.tz.as_deref(), "source.ics", &base).await;
+    }
+    let cal = &group.calendars[0];
+    match state.calendar_service.source_body(&group, cal).await {
+        Ok(fetched) => {
+            let calendar = CombinedCalendar {
+                data: fetched.body,
+                stale_sources: fetched.stale.then_some(fetched.name).into_iter().collect(),
+            };
+            let headers = calendar_headers("source.ics", &calendar);
+            (StatusCode::OK, headers, calendar.data).into_response()
+        }
+        Err(e) => {
+            tracing::error!(
+                "Failed to fetch source '{}' of '{}': {:?}",
+                source,
+                cal_name,
+                e
+            );
+            (error_status(&e), format!("Failed to fetch source: {}", e)).into_response()
+        }
+    }
+}
+
+/// A zip archive of the combined calendars of every group the key may read, as
+/// `{group}.ics`, for offline backups. The path segment is the key followed by
+/// `.zip`. A group that fails to generate fails the whole export, so a backup
+/// is never silently missing one.
+async fn get_export(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let key = key.strip_suffix(".zip").unwrap_or(&key);
+    let groups = match state.readable_groups(&headers, key) {
+        Ok(groups) => groups,
+        Err(e) => return auth_rejection(&state, e),
+    };
+
+    let base = base_url(&state, &headers);
+    let now = Utc::now();
+    let mut archive = zip::ZipWriter::new();
+    for group in groups {
+        if group.calendars.is_empty() && state.config.empty_groups == EmptyGroupPolicy::NotFound {
+            continue;
+        }
+        let tz = group.default_timezone().and_then(ics::resolve_tz);
+        let combined = match state
+            .calendar_service
+            .generate_combined_calendar(group, tz, &base)
+            .await
+        {
+            Ok(combined) => combined,
+            Err(e) => {
+                tracing::error!("Failed to export calendar '{}': {:?}", group.name, e);
+                let message = format!("Failed to generate calendar '{}': {}", group.name, e);
+                return (error_status(&e), message).into_response();
+            }
+        };
+        let name = format!("{}.ics", group.path_name());
+        if let Err(e) = archive.add(&name, combined.data.as_bytes(), now.naive_utc()) {
+            tracing::error!("Failed to export calendar '{}': {:?}", group.name, e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    }
+    let data = match archive.finish() {
+        Ok(data) => data,
+        Err(e) => {
+            tracing::error!("Failed to finish the export: {:?}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let disposition = format!(
+        "attachment; filename=calendars-{}.zip",
+        now.format("%Y-%m-%d")
+    );
+    (
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        data,
+    )
+        .into_response()
+}
+
+/// Merges the sources named in the request body, which need no config entry.
+/// The key must be allowed every group.
+async fn post_combine(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    headers: HeaderMap,
+    Json(request): Json<CombineRequest>,
+) -> Response {
+    if !state.config.combine_endpoint {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    if let Err(e) = state.authorize(&headers, &key, "*") {
+        return auth_rejection(&state, e);
+    }
+
+    let group = match state.config.adhoc_group(request) {
+        Ok(group) => group,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+    let tz = group.default_timezone().and_then(ics::resolve_tz);
+    let base = base_url(&state, &headers);
+    match state
+        .calendar_service
+        .generate_combined_calendar(&group, tz, &base)
+        .await
+    {
+        Ok(calendar_data) => {
+            let headers = calendar_headers("combined.ics", &calendar_data);
+            (StatusCode::OK, headers, calendar_data.data).into_response()
+        }
+        Err(e) => {
+            tracing::warn!("Failed to combine the requested sources: {:?}", e);
+            (
+                error_status(&e),
+                format!("Failed to generate calendar: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct FreeBusyQuery {
+    start: Option<String>,
+    end: Option<String>,
+}
+
+async fn get_freebusy(
+    State(state): State<AppState>,
+    Path((key, cal_name)): Path<(String, String)>,
+    Query(query): Query<FreeBusyQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let cal_name = state.config.group_name(&cal_name).to_string();
+    if let Err(e) = state.authorize(&headers, &key, &cal_name) {
+        return auth_rejection(&state, e);
+    }
+
+    let Some(group) = resolve_group(&state.config, &cal_name) else {
+        return (
+            StatusCode::NOT_FOUND,
+            format!("Calendar '{}' not found", cal_name),
+        )
+            .into_response();
+    };
+
+    let start = match query.start.as_deref() {
+        Some(value) => match freebusy::parse_bound(value) {
+            Some(start) => start,
+            None => {
+                return (StatusCode::BAD_REQUEST, format!("Invalid start: {}", value))
+                    .into_response()
+            }
+        },
+        None => Utc::now()
+            .date_naive()
+            .and_hms_opt(0, 0, 0)
+            .unwrap()
+            .and_utc(),
+    };
+    let end = match query.end.as_deref() {
+        Some(value) => match freebusy::parse_bound(value) {
+            Some(end) => end,
+            None => {
+                return (StatusCode::BAD_REQUEST, format!("Invalid end: {}", value))
+                    .into_response()
+            }
+        },
+        None => start + Duration::days(DEFAULT_FREEBUSY_DAYS),
+    };
+
+    if end <= start {
+        return (StatusCode::BAD_REQUEST, "end must be after start").into_response();
+    }
+    if end - start > Duration::days(MAX_FREEBUSY_DAYS) {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("Range must not exceed {} days", MAX_FREEBUSY_DAYS),
+        )
+            .into_response();
+    }
+
+    match state
+        .calendar_service
+        .generate_freebusy(&group, start, end)
+        .await
+    {
+        Ok(freebusy_data) => {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                header::CONTENT_TYPE,
+                "text/calendar; charset=utf-8".parse().unwrap(),
+            );
+            headers.insert(
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=freebusy.ics".parse().unwrap(),
+            );
+
+            (StatusCode::OK, headers, freebusy_data).into_response()
+        }
+        Err(e) => {
+            tracing::error!("Failed to generate free/busy for '{}': {:?}", cal_name, e);
+            (
+                error_status(&e),
+                format!("Failed to generate free/busy: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ViewQuery {
+    weeks: Option<u32>,
+    tz: Option<String>,
+}
+
+async fn get_view(
+    State(state): State<AppState>,
+    Path((key, cal_name)): Path<(String, String)>,
+    Query(query): Query<ViewQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let cal_name = state.config.group_name(&cal_name).to_string();
+    if let Err(e) = state.authorize(&headers, &key, &cal_name) {
+        return auth_rejection(&state, e);
+    }
+
+    let Some(group) = resolve_group(&state.config, &cal_name) else {
+        return (
+            StatusCode::NOT_FOUND,
+            format!("Calendar '{}' not found", cal_name),
+        )
+            .into_response();
+    };
+
+    let weeks = query.weeks.unwrap_or(DEFAULT_VIEW_WEEKS).clamp(1, MAX_VIEW_WEEKS);
+    let tz = match query.tz.as_deref() {
+        Some(tzid) => match ics::resolve_tz(tzid) {
+            Some(tz) => tz,
+            None => {
+                return (StatusCode::BAD_REQUEST, format!("Unknown timezone: {}", tzid))
+                    .into_response()
+            }
+        },
+        None => chrono_tz::UTC,
+    };
+
+    // Start at local midnight so events earlier today are still shown
+    let today = Utc::now().with_timezone(&tz).date_naive();
+    let from = tz
+        .from_local_datetime(&today.and_hms_opt(0, 0, 0).unwrap())
+        .earliest()
+        .map(|midnight| midnight.with_timezone(&Utc))
+        .unwrap_or_else(Utc::now);
+    let to = from + Duration::weeks(weeks.into());
+    let locale = Locale::negotiate(
+        headers
+            .get(header::ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok()),
+        state.config.default_locale,
+    );
+
+    match state.calendar_service.agenda(&group, from, to).await {
+        Ok(items) => (
+            [
+                (header::CONTENT_LANGUAGE, locale.tag()),
+                (header::VARY, "Accept-Language"),
+            ],
+            Html(view::render(group.display_name(), &items, tz, weeks, locale)),
+        )
+            .into_response(),
+        Err(e) => {
+            tracing::error!("Failed to generate view for '{}': {:?}", cal_name, e);
+            (
+                error_status(&e),
+                format!("Failed to generate view: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DigestQuery {
+    #[serde(default)]
+    period: digest::Period,
+    #[serde(default)]
+    format: digest::Format,
+    tz: Option<String>,
+}
+
+async fn get_digest(
+    State(state): State<AppState>,
+    Path((key, cal_name)): Path<(String, String)>,
+    Query(query): Query<DigestQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let cal_name = state.config.group_name(&cal_name).to_string();
+    if let Err(e) = state.authorize(&headers, &key, &cal_name) {
+        return auth_rejection(&state, e);
+    }
+
+    let Some(group) = resolve_group(&state.config, &cal_name) else {
+        return (
+            StatusCode::NOT_FOUND,
+            format!("Calendar '{}' not found", cal_name),
+        )
+            .into_response();
+    };
+
+    let tz = match query.tz.as_deref() {
+        Some(tzid) => match ics::resolve_tz(tzid) {
+            Some(tz) => tz,
+            None => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    format!("Unknown timezone: {}", tzid),
+                )
+                    .into_response()
+            }
+        },
+        None => chrono_tz::UTC,
+    };
+
+    // Today, or the week starting today, from local midnight
+    let today = Utc::now().with_timezone(&tz).date_naive();
+    let from = tz
+        .from_local_datetime(&today.and_hms_opt(0, 0, 0).unwrap())
+        .earliest()
+        .map(|midnight| midnight.with_timezone(&Utc))
+        .unwrap_or_else(Utc::now);
+    let to = from + Duration::days(query.period.days());
+    let locale = Locale::negotiate(
+        headers
+            .get(header::ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok()),
+        state.config.default_locale,
+    );
+
+    match state.calendar_service.agenda(&group, from, to).await {
+        Ok(items) => {
+            let content_type = match query.format {
+                digest::Format::Text => "text/plain; charset=utf-8",
+                digest::Format::Html => "text/html; charset=utf-8",
+            };
+            let body = digest::render(
+                query.format,
+                group.display_name(),
+                &items,
+                today,
+                query.period,
+                tz,
+                locale,
+            );
+            (
+                [
+                    (header::CONTENT_TYPE, content_type),
+                    (header::CONTENT_LANGUAGE, locale.tag()),
+                    (header::VARY, "Accept-Language"),
+                ],
+                body,
+            )
+                .into_response()
+        }
+        Err(e) => {
+            tracing::error!("Failed to generate digest for '{}': {:?}", cal_name, e);
+            (
+                error_status(&e),
+                format!("Failed to generate digest: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Methods of the read-only CalDAV facade.
+const CALDAV_METHODS: &str = "OPTIONS, GET, HEAD, PROPFIND, REPORT";
+
+/// Capabilities, for clients probing with OPTIONS before authenticating.
+fn caldav_options() -> Response {
+    (
+        [
+            (header::ALLOW, CALDAV_METHODS),
+            (
+                header::HeaderName::from_static("dav"),
+                "1, 3, calendar-access",
+            ),
+        ],
+        StatusCode::OK,
+    )
+        .into_response()
+}
+
+fn caldav_method_not_allowed() -> Response {
+    (
+        StatusCode::METHOD_NOT_ALLOWED,
+        [(header::ALLOW, CALDAV_METHODS)],
+        "The CalDAV interface is read-only",
+    )
+        .into_response()
+}
+
+fn multistatus(body: String) -> Response {
+    (
+        StatusCode::MULTI_STATUS,
+        [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+        body,
+    )
+        .into_response()
+}
+
+/// The path of a key's CalDAV home, under any `X-Forwarded-Prefix`.
+fn caldav_home_path(state: &AppState, headers: &HeaderMap, key: &str) -> String {
+    let base_url = base_url(state, headers);
+    let prefix = base_url
+        .split_once("://")
+        .and_then(|(_, rest)| rest.find('/').map(|i| &rest[i..]))
+        .unwrap_or("");
+    format!(
+        "{}/caldav/{}/",
+        prefix,
+        percent_encoding::utf8_percent_encode(key, config::PATH_SEGMENT)
+    )
+}
+
+/// `PROPFIND` on a key's home, which lists the groups the key may read as
+/// calendars.
+async fn caldav_home(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    method: Method,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    if method == Method::OPTIONS {
+        return caldav_options();
+    }
+    if method.as_str() != "PROPFIND" {
+        return caldav_method_not_allowed();
+    }
+    let groups = match state.readable_groups(&headers, &key) {
+        Ok(groups) => groups,
+        Err(e) => return auth_rejection(&state, e),
+    };
+    let props = match caldav::propfind(&body) {
+        Ok(props) => props,
+        Err(_) => return (StatusCode::BAD_REQUEST, "Malformed PROPFIND").into_response(),
+    };
+
+    let home = caldav_home_path(&state, &headers, &key);
+    let mut response = caldav::Multistatus::new(&home);
+    response.add(&home, &caldav::Target::Home, &props);
+    if depth(&headers) != "0" {
+        for group in groups {
+            // The CTag needs the merged events, which a client asks for on
+            // the calendar itself
+            let target = caldav::Target::Calendar {
+                display_name: group.display_name(),
+                ctag: None,
+            };
+            let href = format!("{}{}/", home, group.path_name());
+            response.add(&href, &target, &props);
+        }
+    }
+    multistatus(response.finish())
+}
+
+/// A group as a CalDAV calendar: `PROPFIND` for its events and CTag, the
+/// calendar-query and calendar-multiget `REPORT`s, and `GET` for the whole
+/// calendar.
+async fn caldav_calendar(
+    State(state): State<AppState>,
+    Path((key, cal_name)): Path<(String, String)>,
+    method: Method,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    if method == Method::OPTIONS {
+        return caldav_options();
+    }
+    if !matches!(method.as_str(), "GET" | "HEAD" | "PROPFIND" | "REPORT") {
+        return caldav_method_not_allowed();
+    }
+    let (group, combined) = match caldav_group(&state, &headers, &key, &cal_name).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+    let collection = caldav::Collection::new(&combined.data);
+    let home = caldav_home_path(&state, &headers, &key);
+    let href = format!("{}{}/", home, group.path_name());
+    let target = caldav::Target::Calendar {
+        display_name: group.display_name(),
+        ctag: Some(&collection.ctag),
+    };
+
+    let mut response = caldav::Multistatus::new(&home);
+    match method.as_str() {
+        "GET" | "HEAD" => {
+            let headers = calendar_headers("calendar.ics", &combined);
+            return (StatusCode::OK, headers, combined.data).into_response();
+        }
+        "PROPFIND" => {
+            let props = match caldav::propfind(&body) {
+                Ok(props) => props,
+                Err(_) => return (StatusCode::BAD_REQUEST, "Malformed PROPFIND").into_response(),
+            };
+            response.add(&href, &target, &props);
+            if depth(&headers) != "0" {
+                for resource in &collection.resources {
+                    let object = caldav::Target::Object(resource);
+                    response.add(&format!("{}{}", href, resource.name), &object, &props);
+                }
+            }
+        }
+        "REPORT" => match caldav::report(&body) {
+            Ok(caldav::Report::Query { props, range }) => {
+                let matching = collection.resources.iter().filter(|resource| {
+                    range.is_none_or(|(start, end)| caldav::in_range(resource, start, end))
+                });
+                for resource in matching {
+                    let object = caldav::Target::Object(resource);
+                    response.add(&format!("{}{}", href, resource.name), &object, &props);
+                }
+            }
+            Ok(caldav::Report::Multiget { props, hrefs }) => {
+                for requested in hrefs {
+                    let name = requested.rsplit('/').next().unwrap_or_default();
+                    let name = percent_decode_str(name).decode_utf8_lossy();
+                    match collection.get(&name) {
+                        Some(resource) => {
+                            let object = caldav::Target::Object(resource);
+                            response.add(&requested, &object, &props);
+                        }
+                        None => response.add_missing(&requested),
+                    }
+                }
+            }
+            Err(caldav::RequestError::UnsupportedReport(report)) => {
+                tracing::debug!("Unsupported CalDAV report '{}'", report);
+                return (
+                    StatusCode::FORBIDDEN,
+                    [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+                    caldav::unsupported_report(),
+                )
+                    .into_response();
+            }
+            Err(caldav::RequestError::Malformed) => {
+                return (StatusCode::BAD_REQUEST, "Malformed REPORT").into_response()
+            }
+        },
+        _ => return caldav_method_not_allowed(),
+    }
+    multistatus(response.finish())
+}
+
+/// One event series of a group: `GET` for its data and `PROPFIND` for its
+/// ETag.
+async fn caldav_object(
+    State(state): State<AppState>,
+    Path((key, cal_name, object)): Path<(String, String, String)>,
+    method: Method,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    if method == Method::OPTIONS {
+        return caldav_options();
+    }
+    if !matches!(method.as_str(), "GET" | "HEAD" | "PROPFIND") {
+        return caldav_method_not_allowed();
+    }
+    let (group, combined) = match caldav_group(&state, &headers, &key, &cal_name).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+    let collection = caldav::Collection::new(&combined.data);
+    let Some(resource) = collection.get(&object) else {
+        return (StatusCode::NOT_FOUND, "Event not found").into_response();
+    };
+
+    match method.as_str() {
+        "GET" | "HEAD" => (
+            [
+                (header::CONTENT_TYPE, caldav::CONTENT_TYPE),
+                (header::ETAG, resource.etag.as_str()),
+            ],
+            resource.data.clone(),
+        )
+            .into_response(),
+        "PROPFIND" => {
+            let props = match caldav::propfind(&body) {
+                Ok(props) => props,
+                Err(_) => return (StatusCode::BAD_REQUEST, "Malformed PROPFIND").into_response(),
+            };
+            let home = caldav_home_path(&state, &headers, &key);
+            let href = format!("{}{}/{}", home, group.path_name(), resource.name);
+            let mut response = caldav::Multistatus::new(&home);
+            response.add(&href, &caldav::Target::Object(resource), &props);
+            multistatus(response.finish())
+        }
+        _ => caldav_method_not_allowed(),
+    }
+}
+
+/// Authorizes a CalDAV request for a group and merges its events, as a
+/// subscription to it would.
+async fn caldav_group(
+    state: &AppState,
+    headers: &HeaderMap,
+    key: &str,
+    cal_name: &str,
+) -> Result<(CalendarGroup, CombinedCalendar), Response> {
+    let cal_name = state.config.group_name(cal_name).to_string();
+    if let Err(e) = state.authorize(headers, key, &cal_name) {
+        return Err(auth_rejection(state, e));
+    }
+    let Some(group) = resolve_group(&state.config, &cal_name) else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("Calendar '{}' not found", cal_name),
+        )
+            .into_response());
+    };
+    let tz = group.default_timezone().and_then(ics::resolve_tz);
+    let base_url = base_url(state, headers);
+    match state
+        .calendar_service
+        .generate_combined_calendar(&group, tz, &base_url)
+        .await
+    {
+        Ok(combined) => Ok((group, combined)),
+        Err(e) => {
+            tracing::error!("Failed to generate calendar '{}': {:?}", cal_name, e);
+            Err((
+                error_status(&e),
+                format!("Failed to generate calendar: {}", e),
+            )
+                .into_response())
+        }
+    }
+}
+
+/// A WebDAV `Depth` header; "infinity" is its default and is answered as "1".
+fn depth(headers: &HeaderMap) -> &str {
+    headers
+        .get("depth")
+        .and_then(|value| value.to_str().ok())
+        .map_or("infinity", str::trim)
+}
+
+#[derive(Deserialize)]
+struct FeedQuery {
+    days: Option<i64>,
+}
+
+async fn get_feed(
+    State(state): State<AppState>,
+    Path((key, cal_name)): Path<(String, String)>,
+    Query(query): Query<FeedQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let cal_name = state.config.group_name(&cal_name).to_string();
+    if let Err(e) = state.authorize(&headers, &key, &cal_name) {
+        return auth_rejection(&state, e);
+    }
+
+    let Some(group) = resolve_group(&state.config, &cal_name) else {
+        return (
+            StatusCode::NOT_FOUND,
+            format!("Calendar '{}' not found", cal_name),
+        )
+            .into_response();
+    };
+
+    let days = query.days.unwrap_or(DEFAULT_FEED_DAYS).clamp(1, MAX_FEED_DAYS);
+    let now = Utc::now();
+
+    match state
+        .calendar_service
+        .agenda(&group, now, now + Duration::days(days))
+        .await
+    {
+        Ok(items) => {
+            let feed_url = format!(
+                "{}/calendar/{}/{}/feed.xml",
+                base_url(&state, &headers),
+                percent_encoding::utf8_percent_encode(&key, config::PATH_SEGMENT),
+                group.path_name()
+            );
+            (
+                StatusCode::OK,
+                [(header::CONTENT_TYPE, "application/atom+xml; charset=utf-8")],
+                feed::render(group.display_name(), &feed_url, &items, now),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            tracing::error!("Failed to generate feed for '{}': {:?}", cal_name, e);
+            (
+                error_status(&e),
+                format!("Failed to generate feed: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ExplainQuery {
+    uid: String,
+}
+
+async fn get_explain(
+    State(state): State<AppState>,
+    Path((key, cal_name)): Path<(String, String)>,
+    Query(query): Query<ExplainQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let cal_name = state.config.group_name(&cal_name).to_string();
+    if let Err(e) = state.authorize(&headers, &key, &cal_name) {
+        return auth_rejection(&state, e);
+    }
+
+    let Some(group) = resolve_group(&state.config, &cal_name) else {
+        return (
+            StatusCode::NOT_FOUND,
+            format!("Calendar '{}' not found", cal_name),
+        )
+            .into_response();
+    };
+    if !group.explain {
+        return (
+            StatusCode::FORBIDDEN,
+            format!("Explain mode is not enabled for '{}'", cal_name),
+        )
+            .into_response();
+    }
+
+    match state.calendar_service.explain(&group, &query.uid).await {
+        Ok(explanation) => Json(explanation).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to explain '{}' in '{}': {:?}", query.uid, cal_name, e);
+            (
+                error_status(&e),
+                format!("Failed to explain event: {}", e),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ChangesQuery {
+    since: Option<String>,
+}
+
+/// The changes to a group's events recorded by its `history`, oldest first.
+async fn get_changes(
+    State(state): State<AppState>,
+    Path((key, cal_name)): Path<(String, String)>,
+    Query(query): Query<ChangesQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let cal_name = state.config.group_name(&cal_name).to_string();
+    if let Err(e) = state.authorize(&headers, &key, &cal_name) {
+        return auth_rejection(&state, e);
+    }
+
+    let Some(group) = resolve_group(&state.config, &cal_name) else {
+        return (
+            StatusCode::NOT_FOUND,
+            format!("Calendar '{}' not found", cal_name),
+        )
+            .into_response();
+    };
+    let Some(state_dir) = state.config.state_dir.as_deref().filter(|_| group.history) else {
+        return (
+            StatusCode::FORBIDDEN,
+            format!("History is not enabled for '{}'", cal_name),
+        )
+            .into_response();
+    };
+    let since = match query.since.as_deref() {
+        Some(value) => match freebusy::parse_bound(value) {
+            Some(since) => Some(since),
+            None => {
+                return (StatusCode::BAD_REQUEST, format!("Invalid since: {}", value))
+                    .into_response()
+            }
+        },
+        None => None,
+    };
+
+    match Store::new(state_dir).changes(&group.name, since) {
+        Ok(changes) => Json(ChangeLog {
+            group: group.name.clone(),
+            changes,
+        })
+        .into_response(),
+        Err(e) => {
+            tracing::error!("Failed to read the history of '{}': {:?}", cal_name, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to read the history",
+            )
+                .into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SyncQuery {
+    token: Option<String>,
+}
+
+/// The events of a group that changed since `token`, or all of them without
+/// one, from its `history`.
+async fn get_sync(
+    State(state): State<AppState>,
+    Path((key, cal_name)): Path<(String, String)>,
+    Query(query): Query<SyncQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let cal_name = state.config.group_name(&cal_name).to_string();
+    if let Err(e) = state.authorize(&headers, &key, &cal_name) {
+        return auth_rejection(&state, e);
+    }
+
+    let Some(group) = resolve_group(&state.config, &cal_name) else {
+        return (
+            StatusCode::NOT_FOUND,
+            format!("Calendar '{}' not found", cal_name),
+        )
+            .into_response();
+    };
+    let Some(state_dir) = state.config.state_dir.as_deref().filter(|_| group.history) else {
+        return (
+            StatusCode::FORBIDDEN,
+            format!("History is not enabled for '{}'", cal_name),
+        )
+            .into_response();
+    };
+    // The token is the id of the last log entry the client has seen
+    let position = match query.token.as_deref().filter(|token| !token.is_empty()) {
+        Some(token) => match token.parse::<i64>() {
+            Ok(position) if position >= 0 => Some(position),
+            _ => {
+                return (StatusCode::BAD_REQUEST, format!("Invalid token: {}", token))
+                    .into_response()
+            }
+        },
+        None => None,
+    };
+
+    match Store::new(state_dir).sync(&group.name, position) {
+        Ok(Some(delta)) => Json(SyncResponse {
+            group: group.name.clone(),
+            token: delta.position.to_string(),
+            full: position.is_none(),
+            updated: delta.updated,
+            removed: delta.removed,
+        })
+        .into_response(),
+        Ok(None) => (
+            StatusCode::GONE,
+            "The token is no longer valid; sync again without one",
+        )
+            .into_response(),
+        Err(e) => {
+            tracing::error!("Failed to read the history of '{}': {:?}", cal_name, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to read the history",
+            )
+                .into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct UsageQuery {
+    format: Option<String>,
+}
+
+/// Request heatmaps by group and by key, as JSON or with `?format=html` as a chart.
+async fn get_usage(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Query(query): Query<UsageQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = state.authorize(&headers, &key, "*") {
+        return auth_rejection(&state, e);
+    }
+
+    let report = state.usage.report();
+    match query.format.as_deref() {
+        None | Some("json") => Json(report).into_response(),
+        Some("html") => Html(usage::render(&report)).into_response(),
+        Some(other) => (StatusCode::BAD_REQUEST, format!("Unknown format: {}", other)).into_response(),
+    }
+}
+
+/// Cache counters, approximate memory use and the age of every entry; the
+/// `cache` section of `/status` on its own.
+async fn get_cache_stats(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = state.authorize(&headers, &key, "*") {
+        return auth_rejection(&state, e);
+    }
+    Json(state.calendar_service.cache_stats().await).into_response()
+}
+
+/// Cache statistics for Prometheus to scrape.
+async fn get_metrics(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = state.authorize(&headers, &key, "*") {
+        return auth_rejection(&state, e);
+    }
+    let stats = state.calendar_service.cache_stats().await;
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")],
+        metrics::render(&state.config, &stats),
+    )
+        .into_response()
+}
+
+#[derive(Deserialize)]
+struct PurgeQuery {
+    group: Option<String>,
+    url: Option<String>,
+}
+
+/// Evicts cached source bodies, so fixed upstream calendars are picked up
+/// without a restart: those of one group with `?group=`, one source URL with
+/// `?url=`, or everything.
+async fn purge_cache(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Query(query): Query<PurgeQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = state.authorize(&headers, &key, "*") {
+        return auth_rejection(&state, e);
+    }
+
+    let urls = match (query.group, query.url) {
+        (Some(_), Some(_)) => {
+            let message = "Pass either group or url, not both";
+            return (StatusCode::BAD_REQUEST, message).into_response();
+        }
+        (Some(cal_name), None) => {
+            let cal_name = state.config.group_name(&cal_name);
+            let Some(group) = resolve_group(&state.config, cal_name) else {
+                return (
+                    StatusCode::NOT_FOUND,
+                    format!("Calendar '{}' not found", cal_name),
+                )
+                    .into_response();
+            };
+            Some(group.calendars.into_iter().map(|cal| cal.url).collect())
+        }
+        (None, Some(url)) => Some(vec![url]),
+        (None, None) => None,
+    };
+
+    let purged = state.calendar_service.purge_cache(urls.as_deref()).await;
+    tracing::info!("Purged {} cached source(s)", purged.len());
+    Json(CachePurge { purged }).into_response()
 }
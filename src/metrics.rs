@@ -0,0 +1,126 @@
+//! Cache statistics in the Prometheus text exposition format, for `/metrics/{key}`.
+
+use crate::calendar::CacheStats;
+use crate::config::Config;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// Renders `stats` as Prometheus metrics. Per-entry gauges are labelled with the
+/// group and source names rather than URLs, which often embed access tokens.
+pub fn render(config: &Config, stats: &CacheStats) -> String {
+    let mut output = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, f64)]| {
+        let _ = writeln!(output, "# HELP {} {}", name, help);
+        let _ = writeln!(output, "# TYPE {} {}", name, kind);
+        for (labels, value) in samples {
+            let _ = writeln!(output, "{}{} {}", name, labels, value);
+        }
+    };
+
+    metric(
+        "webcal_cache_enabled",
+        "gauge",
+        "Whether source caching is enabled.",
+        &[(String::new(), u8::from(stats.enabled).into())],
+    );
+    metric(
+        "webcal_cache_entries",
+        "gauge",
+        "Cached source bodies.",
+        &[(String::new(), stats.entry_count as f64)],
+    );
+    metric(
+        "webcal_cache_bytes",
+        "gauge",
+        "Approximate memory held by the cache.",
+        &[(String::new(), stats.bytes as f64)],
+    );
+    metric(
+        "webcal_cache_hits_total",
+        "counter",
+        "Source lookups served from the cache.",
+        &[(String::new(), stats.hits as f64)],
+    );
+    metric(
+        "webcal_cache_misses_total",
+        "counter",
+        "Source lookups that had to fetch upstream.",
+        &[(String::new(), stats.misses as f64)],
+    );
+    let evictions = &stats.evictions;
+    metric(
+        "webcal_cache_evictions_total",
+        "counter",
+        "Cache entries removed, by cause.",
+        &[
+            ("expired", evictions.expired),
+            ("size", evictions.size),
+            ("explicit", evictions.explicit),
+            ("replaced", evictions.replaced),
+        ]
+        .map(|(cause, count)| (labels(&[("cause", cause)]), count as f64)),
+    );
+
+    let entries: HashMap<(&str, &str), _> = stats
+        .entries
+        .iter()
+        .flat_map(|entry| {
+            let sources = entry.sources.iter();
+            sources.map(move |source| ((source.group.as_str(), source.source.as_str()), entry))
+        })
+        .collect();
+    let sources: Vec<_> = config
+        .calendars
+        .iter()
+        .flat_map(|group| group.calendars.iter().map(move |cal| (group, cal)))
+        .filter_map(|(group, cal)| {
+            let entry = entries.get(&(group.name.as_str(), cal.name.as_str()))?;
+            let labels = labels(&[("group", &group.name), ("source", &cal.name)]);
+            Some((labels, *entry))
+        })
+        .collect();
+    metric(
+        "webcal_cache_entry_age_seconds",
+        "gauge",
+        "Age of each source's cached body.",
+        &sources
+            .iter()
+            .map(|(labels, entry)| (labels.clone(), entry.age_seconds as f64))
+            .collect::<Vec<_>>(),
+    );
+    metric(
+        "webcal_cache_entry_ttl_seconds",
+        "gauge",
+        "Lifetime of each source's cached body.",
+        &sources
+            .iter()
+            .map(|(labels, entry)| (labels.clone(), entry.ttl_seconds as f64))
+            .collect::<Vec<_>>(),
+    );
+    metric(
+        "webcal_cache_entry_bytes",
+        "gauge",
+        "Size of each source's cached body.",
+        &sources
+            .iter()
+            .map(|(labels, entry)| (labels.clone(), entry.bytes as f64))
+            .collect::<Vec<_>>(),
+    );
+
+    output
+}
+
+/// A label set such as `{group="family",source="school"}`.
+fn labels(pairs: &[(&str, &str)]) -> String {
+    let pairs: Vec<String> = pairs
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
@@ -0,0 +1,98 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::Path;
+
+/// Format version of the state directory written by this binary.
+pub const STATE_VERSION: u32 = 3;
+/// File inside the state directory recording its format version.
+const VERSION_FILE: &str = "VERSION";
+
+/// A single upgrade of the state directory from `from` to `from + 1`.
+struct Migration {
+    from: u32,
+    description: &'static str,
+    run: fn(&Path) -> Result<()>,
+}
+
+/// Every migration, in order. Version 0 is a directory without a VERSION file.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 0,
+        description: "initialize state directory",
+        run: |dir| fs::create_dir_all(dir).context("Failed to create state directory"),
+    },
+    Migration {
+        from: 1,
+        description: "add event history directory",
+        run: |dir| {
+            fs::create_dir_all(dir.join(crate::store::HISTORY_DIR))
+                .context("Failed to create history directory")
+        },
+    },
+    Migration {
+        from: 2,
+        description: "move event history into SQLite",
+        run: crate::store::import_files,
+    },
+];
+
+/// Upgrades the state directory to `STATE_VERSION`, one migration at a time.
+///
+/// The version is recorded after every step, so an interrupted upgrade resumes
+/// where it stopped. A directory written by a newer binary is rejected rather
+/// than risk misreading it.
+pub fn run(dir: &Path) -> Result<()> {
+    let mut version = read_version(dir)?;
+    if version > STATE_VERSION {
+        anyhow::bail!(
+            "State directory {} has format version {}, but this binary only supports up to {}; \
+             upgrade webcal-combiner or point state_dir elsewhere",
+            dir.display(),
+            version,
+            STATE_VERSION
+        );
+    }
+
+    while version < STATE_VERSION {
+        let migration = MIGRATIONS
+            .iter()
+            .find(|migration| migration.from == version)
+            .context(format!("No migration from state version {}", version))?;
+
+        tracing::info!(
+            "Migrating state directory {} from version {} to {}: {}",
+            dir.display(),
+            version,
+            version + 1,
+            migration.description
+        );
+        (migration.run)(dir)
+            .context(format!("Migration from state version {} failed", version))?;
+
+        version += 1;
+        write_version(dir, version)?;
+    }
+
+    Ok(())
+}
+
+fn read_version(dir: &Path) -> Result<u32> {
+    let path = dir.join(VERSION_FILE);
+    match fs::read_to_string(&path) {
+        Ok(content) => content
+            .trim()
+            .parse()
+            .context(format!("Invalid state version in {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e).context(format!("Failed to read {}", path.display())),
+    }
+}
+
+fn write_version(dir: &Path, version: u32) -> Result<()> {
+    // Write then rename so a crash never leaves a truncated VERSION file
+    let path = dir.join(VERSION_FILE);
+    let tmp = dir.join(format!("{}.tmp", VERSION_FILE));
+    fs::write(&tmp, format!("{}\n", version))
+        .context(format!("Failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, &path).context(format!("Failed to write {}", path.display()))
+}
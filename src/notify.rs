@@ -0,0 +1,286 @@
+//! Checks for changed events. Every `notify_interval_seconds`, the merged
+//! events of each group with `webhooks` or `history` are compared with those
+//! of the last check, and any added, changed or removed are POSTed to the
+//! group's webhooks as JSON and recorded in its history.
+
+use crate::calendar::CalendarService;
+use crate::config::{CalendarGroup, Config, Webhook};
+use crate::ics::{self, DateValue, Property};
+use crate::logging;
+use crate::store::Store;
+use crate::templates;
+use chrono::{SecondsFormat, Utc};
+use reqwest::header::CONTENT_TYPE;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use webcal_combiner::api::{ChangeKind, ChangeRecord, EventSummary};
+
+/// The events added, changed and removed since a group's last check.
+#[derive(Debug)]
+pub struct Changes {
+    pub group: String,
+    pub added: Vec<EventSummary>,
+    pub changed: Vec<EventSummary>,
+    pub removed: Vec<EventSummary>,
+}
+
+/// The body POSTed to a group's webhooks.
+#[derive(Serialize)]
+struct Notification<'a> {
+    group: &'a str,
+    /// The changes as a short message from the webhook's templates, which
+    /// Slack and similar incoming webhooks show as is.
+    text: String,
+    added: &'a [EventSummary],
+    changed: &'a [EventSummary],
+    removed: &'a [EventSummary],
+}
+
+/// A group's events at one check, by UID and RECURRENCE-ID, with their text.
+pub type Snapshot = BTreeMap<(String, Option<String>), (EventSummary, String)>;
+
+/// Starts checking for changes, if any group has webhooks or history.
+pub fn spawn(config: Arc<Config>, service: CalendarService) -> Option<JoinHandle<()>> {
+    if !config.calendars.iter().any(is_checked) {
+        return None;
+    }
+    Some(tokio::spawn(run(config, service)))
+}
+
+async fn run(config: Arc<Config>, service: CalendarService) {
+    let timeout = Duration::from_secs(config.request_timeout_seconds);
+    let client = match reqwest::Client::builder().timeout(timeout).build() {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::error!("Failed to set up webhook notifications: {:?}", e);
+            return;
+        }
+    };
+    let store = config.state_dir.as_deref().map(Store::new);
+    let mut snapshots: HashMap<String, Snapshot> = HashMap::new();
+    let mut ticks = tokio::time::interval(Duration::from_secs(config.notify_interval_seconds));
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        for group in config.calendars.iter().filter(|group| is_checked(group)) {
+            // A failed check is skipped rather than reporting every event of
+            // the failed source as removed
+            let events = match service.merged_events(group).await {
+                Ok(events) => events,
+                Err(e) => {
+                    tracing::warn!(
+                        "Failed to check group '{}' for changes: {}",
+                        group.name,
+                        logging::error_chain(&e)
+                    );
+                    continue;
+                }
+            };
+            let current = snapshot(&events);
+            let history = store.as_ref().filter(|_| group.history);
+            // History picks up where it left off before a restart; otherwise
+            // the first check only records where things stand
+            let last = match (snapshots.remove(&group.name), history) {
+                (Some(last), _) => Some(last),
+                (None, Some(store)) => store.snapshot(&group.name).unwrap_or_else(|e| {
+                    tracing::warn!("Failed to load the history of '{}': {:?}", group.name, e);
+                    None
+                }),
+                (None, None) => None,
+            };
+            let changes = last.and_then(|last| diff(group, &last, &current));
+
+            if let Some(store) = history {
+                let records = changes.as_ref().map(records).unwrap_or_default();
+                if let Err(e) = store.record(&group.name, &current, &records) {
+                    tracing::warn!("Failed to record the history of '{}': {:?}", group.name, e);
+                }
+            }
+            if let Some(ref changes) = changes {
+                for webhook in &group.webhooks {
+                    post(&client, group, webhook, changes).await;
+                }
+            }
+            snapshots.insert(group.name.clone(), current);
+        }
+    }
+}
+
+fn is_checked(group: &CalendarGroup) -> bool {
+    !group.webhooks.is_empty() || group.history
+}
+
+fn snapshot(events: &[String]) -> Snapshot {
+    let mut snapshot = Snapshot::new();
+    for text in events {
+        let event = ics::Event::parse(text);
+        let Some(uid) = event.uid else {
+            continue;
+        };
+        let recurrence_id = event.recurrence_id.as_ref().map(format_date);
+        let summary = EventSummary {
+            uid: uid.clone(),
+            recurrence_id: recurrence_id.clone(),
+            summary: event.summary.unwrap_or_default(),
+            start: event.start.as_ref().map(format_date),
+            properties: Vec::new(),
+        };
+        snapshot.insert((uid, recurrence_id), (summary, text.clone()));
+    }
+    snapshot
+}
+
+/// The text of an event as it is compared between checks. DTSTAMP is when the
+/// source generated its feed, which changes without the event doing so.
+fn compared(text: &str) -> String {
+    text.lines()
+        .filter(|line| Property::parse(line).is_none_or(|property| property.name != "DTSTAMP"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The changes from `last` to `current`, if there are any.
+fn diff(group: &CalendarGroup, last: &Snapshot, current: &Snapshot) -> Option<Changes> {
+    let mut added = Vec::new();
+    let mut changed = Vec::new();
+    for (key, (summary, text)) in current {
+        let Some((_, last_text)) = last.get(key) else {
+            added.push(summary.clone());
+            continue;
+        };
+        let (last_text, text) = (compared(last_text), compared(text));
+        if last_text != text {
+            changed.push(EventSummary {
+                properties: changed_properties(&last_text, &text),
+                ..summary.clone()
+            });
+        }
+    }
+    let removed: Vec<EventSummary> = last
+        .iter()
+        .filter(|(key, _)| !current.contains_key(*key))
+        .map(|(_, (summary, _))| summary.clone())
+        .collect();
+    if added.is_empty() && changed.is_empty() && removed.is_empty() {
+        return None;
+    }
+    Some(Changes {
+        group: group.name.clone(),
+        added,
+        changed,
+        removed,
+    })
+}
+
+/// `changes` as a short message, rendered from `webhook`'s template, or the
+/// default one should it fail.
+fn text(group: &CalendarGroup, webhook: &Webhook, changes: &Changes) -> String {
+    let context = templates::Changes::new(
+        group.display_name(),
+        &changes.added,
+        &changes.changed,
+        &changes.removed,
+    );
+    let source = webhook.message.source(templates::DEFAULT_CHANGES);
+    templates::render(source, &context).unwrap_or_else(|e| {
+        tracing::warn!(
+            "Failed to render a webhook template of '{}': {}",
+            group.name,
+            e
+        );
+        templates::render(templates::DEFAULT_CHANGES, &context).unwrap_or_default()
+    })
+}
+
+/// The names of the properties that differ between two texts of an event.
+fn changed_properties(last: &str, current: &str) -> Vec<String> {
+    let last_lines: HashSet<&str> = last.lines().collect();
+    let current_lines: HashSet<&str> = current.lines().collect();
+    let names: BTreeSet<String> = last_lines
+        .symmetric_difference(&current_lines)
+        .filter_map(|line| Property::parse(line))
+        .map(|property| property.name)
+        .collect();
+    names.into_iter().collect()
+}
+
+/// `changes` as entries of a change log.
+fn records(changes: &Changes) -> Vec<ChangeRecord> {
+    let at = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+    let kinds = [
+        (ChangeKind::Added, &changes.added),
+        (ChangeKind::Changed, &changes.changed),
+        (ChangeKind::Removed, &changes.removed),
+    ];
+    kinds
+        .into_iter()
+        .flat_map(|(change, events)| events.iter().map(move |event| (change, event)))
+        .map(|(change, event)| ChangeRecord {
+            at: at.clone(),
+            change,
+            event: event.clone(),
+        })
+        .collect()
+}
+
+/// A date as `2024-05-01`, or a time as RFC 3339 in its own zone.
+fn format_date(value: &DateValue) -> String {
+    if value.all_day {
+        value.instant.format("%Y-%m-%d").to_string()
+    } else {
+        value.instant.to_rfc3339()
+    }
+}
+
+/// Sends `changes` to `webhook`, logging failures; a missed notification
+/// isn't retried.
+async fn post(
+    client: &reqwest::Client,
+    group: &CalendarGroup,
+    webhook: &Webhook,
+    changes: &Changes,
+) {
+    let notification = Notification {
+        group: &changes.group,
+        text: text(group, webhook, changes),
+        added: &changes.added,
+        changed: &changes.changed,
+        removed: &changes.removed,
+    };
+    let body = match serde_json::to_vec(&notification) {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::error!("Failed to encode changes of '{}': {:?}", changes.group, e);
+            return;
+        }
+    };
+    let url = &webhook.url;
+    let sent = client
+        .post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+    // Webhook URLs often end in their token, so only the host is logged
+    let host = reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default();
+    match sent {
+        Ok(_) => tracing::info!(
+            "Notified the webhook at {} of changes to '{}'",
+            host,
+            changes.group
+        ),
+        Err(e) => tracing::warn!(
+            "Failed to notify the webhook at {} of changes to '{}': {}",
+            host,
+            changes.group,
+            logging::error_chain(&e.without_url())
+        ),
+    }
+}
@@ -0,0 +1,244 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ring::digest;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{CertificateError, DigitallySignedStruct, OtherError, RootCertStore, SignatureScheme};
+use std::error::Error;
+use std::sync::Arc;
+
+/// Length in bytes of a SHA-256 pin once decoded.
+pub const PIN_LENGTH: usize = 32;
+
+/// The base64 SHA-256 of a DER certificate's SubjectPublicKeyInfo, the same
+/// value `openssl x509 -pubkey | openssl pkey -pubin -outform der | openssl dgst
+/// -sha256 -binary | base64` prints. `None` if the certificate can't be parsed.
+pub fn spki_sha256(cert_der: &[u8]) -> Option<String> {
+    let spki = subject_public_key_info(cert_der)?;
+    Some(STANDARD.encode(digest::digest(&digest::SHA256, spki)))
+}
+
+/// Checks that `pin` is a base64-encoded SHA-256 digest.
+pub fn is_valid_pin(pin: &str) -> bool {
+    STANDARD
+        .decode(pin)
+        .is_ok_and(|bytes| bytes.len() == PIN_LENGTH)
+}
+
+/// A server key that matches none of a source's pins.
+#[derive(Debug, thiserror::Error)]
+#[error("certificate key {found} matches none of the pinned keys")]
+pub struct Mismatch {
+    pub found: String,
+}
+
+/// The TLS config of a source with `pins`. The server's key is checked against
+/// them during the handshake, so nothing is sent to a server that doesn't
+/// match. The chain is validated against the system roots and `ca_bundle` as
+/// usual, unless `insecure`, when the pin alone authenticates the server.
+/// `identity` is a PEM certificate and key for mutual TLS.
+pub fn tls_config(
+    pins: &[String],
+    insecure: bool,
+    ca_bundle: Option<&[u8]>,
+    identity: Option<(&[u8], &[u8])>,
+) -> Result<rustls::ClientConfig, rustls::Error> {
+    let provider = Arc::new(crypto::ring::default_provider());
+    let validator = if insecure {
+        None
+    } else {
+        let mut roots = RootCertStore::empty();
+        roots.add_parsable_certificates(system_roots());
+        if let Some(pem) = ca_bundle {
+            roots.add_parsable_certificates(CertificateDer::pem_slice_iter(pem).flatten());
+        }
+        let validator =
+            WebPkiServerVerifier::builder_with_provider(Arc::new(roots), Arc::clone(&provider))
+                .build()
+                .map_err(|e| rustls::Error::General(e.to_string()))?;
+        Some(validator)
+    };
+    let verifier = PinVerifier {
+        pins: pins.to_vec(),
+        validator,
+        provider: Arc::clone(&provider),
+    };
+
+    let builder = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier));
+    let mut config = match identity {
+        Some((cert, key)) => {
+            let chain = CertificateDer::pem_slice_iter(cert)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| rustls::Error::General(e.to_string()))?;
+            let key = PrivateKeyDer::from_pem_slice(key)
+                .map_err(|e| rustls::Error::General(e.to_string()))?;
+            builder.with_client_auth_cert(chain, key)?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// The pin in a failed request's chain of errors, if a pin mismatch is what
+/// failed it.
+pub fn mismatch(error: &(dyn Error + 'static)) -> Option<String> {
+    let mut next = Some(error);
+    while let Some(error) = next {
+        if let Some(rustls::Error::InvalidCertificate(CertificateError::Other(other))) =
+            error.downcast_ref::<rustls::Error>()
+        {
+            if let Some(mismatch) = other.0.downcast_ref::<Mismatch>() {
+                return Some(mismatch.found.clone());
+            }
+        }
+        // An io::Error's source is what its wrapped error names as source, not
+        // the wrapped error itself
+        next = match error
+            .downcast_ref::<std::io::Error>()
+            .and_then(|e| e.get_ref())
+        {
+            Some(inner) => Some(inner as &(dyn Error + 'static)),
+            None => error.source(),
+        };
+    }
+    None
+}
+
+/// The certificates in the system's OpenSSL bundle, the roots the default
+/// client trusts.
+fn system_roots() -> Vec<CertificateDer<'static>> {
+    let probed = openssl_probe::probe();
+    let mut files: Vec<_> = probed.cert_file.into_iter().collect();
+    if files.is_empty() {
+        if let Some(dir) = probed.cert_dir {
+            let entries = std::fs::read_dir(dir).into_iter().flatten().flatten();
+            files.extend(entries.map(|entry| entry.path()));
+        }
+    }
+    files
+        .iter()
+        .filter_map(|file| CertificateDer::pem_file_iter(file).ok())
+        .flat_map(|certificates| certificates.flatten())
+        .collect()
+}
+
+/// Checks the server's key against the pins before validating its chain.
+#[derive(Debug)]
+struct PinVerifier {
+    pins: Vec<String>,
+    /// Validates the chain, unless the source is `insecure_skip_verify`.
+    validator: Option<Arc<WebPkiServerVerifier>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let found = spki_sha256(end_entity).unwrap_or_else(|| "unparsable".to_string());
+        if !self.pins.contains(&found) {
+            let mismatch = OtherError(Arc::new(Mismatch { found }));
+            return Err(rustls::Error::InvalidCertificate(CertificateError::Other(
+                mismatch,
+            )));
+        }
+        match self.validator {
+            Some(ref validator) => validator.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            ),
+            None => Ok(ServerCertVerified::assertion()),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        let algorithms = &self.provider.signature_verification_algorithms;
+        crypto::verify_tls12_signature(message, cert, dss, algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        let algorithms = &self.provider.signature_verification_algorithms;
+        crypto::verify_tls13_signature(message, cert, dss, algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// Finds the SubjectPublicKeyInfo inside a DER certificate:
+///
+/// ```text
+/// Certificate ::= SEQUENCE { tbsCertificate, signatureAlgorithm, signature }
+/// TBSCertificate ::= SEQUENCE { [0] version OPTIONAL, serialNumber, signature,
+///                               issuer, validity, subject, subjectPublicKeyInfo, ... }
+/// ```
+fn subject_public_key_info(cert_der: &[u8]) -> Option<&[u8]> {
+    let (_, certificate, _) = read_tlv(cert_der)?;
+    let (_, mut tbs, _) = read_tlv(certificate)?;
+
+    // Skip the optional explicit version tag
+    if tbs.first() == Some(&0xA0) {
+        tbs = read_tlv(tbs)?.2;
+    }
+    // serialNumber, signature, issuer, validity, subject
+    for _ in 0..5 {
+        tbs = read_tlv(tbs)?.2;
+    }
+
+    let rest = read_tlv(tbs)?.2;
+    Some(&tbs[..tbs.len() - rest.len()])
+}
+
+/// Reads one DER tag-length-value, returning the tag, the value and what follows.
+fn read_tlv(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&first, mut input) = input.split_first()?;
+
+    let length = if first & 0x80 == 0 {
+        usize::from(first)
+    } else {
+        let octets = usize::from(first & 0x7F);
+        if octets == 0 || octets > std::mem::size_of::<usize>() || input.len() < octets {
+            return None;
+        }
+        let (bytes, rest) = input.split_at(octets);
+        input = rest;
+        bytes
+            .iter()
+            .fold(0usize, |length, &byte| (length << 8) | usize::from(byte))
+    };
+
+    if input.len() < length {
+        return None;
+    }
+    let (value, rest) = input.split_at(length);
+    Some((tag, value, rest))
+}
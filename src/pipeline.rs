@@ -0,0 +1,1160 @@
+use crate::config::{Attribution, CalendarGroup, LocationRewrite, NameProperties, SourceCalendar};
+use crate::ics::{self, Property};
+use crate::sanitize;
+use crate::timezone::{self, TimezoneConverter};
+use crate::windows_zones;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use regex::Regex;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Instant, SystemTime};
+pub use webcal_combiner::api::{Stage, StageStats};
+
+/// Run counts and cumulative time of every stage, indexed by `Stage`.
+#[derive(Default)]
+pub struct PipelineMetrics {
+    runs: [AtomicU64; Stage::ALL.len()],
+    micros: [AtomicU64; Stage::ALL.len()],
+}
+
+impl PipelineMetrics {
+    /// Records one run of `stage` that began at `started`.
+    pub fn record(&self, stage: Stage, started: Instant) {
+        let micros = u64::try_from(started.elapsed().as_micros()).unwrap_or(u64::MAX);
+        self.runs[stage as usize].fetch_add(1, Ordering::Relaxed);
+        self.micros[stage as usize].fetch_add(micros, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> Vec<StageStats> {
+        Stage::ALL
+            .iter()
+            .map(|&stage| {
+                let runs = self.runs[stage as usize].load(Ordering::Relaxed);
+                let total_ms = self.micros[stage as usize].load(Ordering::Relaxed) as f64 / 1000.0;
+                StageStats {
+                    stage,
+                    runs,
+                    total_ms,
+                    mean_ms: (runs > 0).then(|| total_ms / runs as f64),
+                }
+            })
+            .collect()
+    }
+}
+
+/// A source body as returned by the fetch stage. `stale` is set when the group's
+/// `max_staleness_seconds` couldn't be met, or when the source is `missing`: its
+/// circuit is open and nothing is cached, so its body is empty.
+pub struct FetchedCalendar {
+    pub name: String,
+    pub body: String,
+    pub stale: bool,
+    pub missing: bool,
+    pub fetched_at: SystemTime,
+}
+
+/// A source with folded lines joined and line endings normalized to `\n`.
+pub struct DecodedCalendar {
+    pub name: String,
+    pub text: String,
+    pub stale: bool,
+    pub missing: bool,
+    pub fetched_at: SystemTime,
+}
+
+/// The components of a decoded source, each still as raw text.
+#[derive(Clone)]
+pub struct ParsedCalendar {
+    pub name: String,
+    pub stale: bool,
+    pub missing: bool,
+    /// When the body was fetched from the source, which is earlier than the
+    /// request when it came from the cache.
+    pub fetched_at: SystemTime,
+    /// Top-level properties of the source's VCALENDAR.
+    pub properties: Vec<Property>,
+    /// VTIMEZONE blocks as `(TZID, text)`, in source order.
+    pub timezones: Vec<(String, String)>,
+    pub events: Vec<String>,
+}
+
+pub fn decode(fetched: Vec<FetchedCalendar>) -> Vec<DecodedCalendar> {
+    fetched
+        .into_iter()
+        .map(|fetched| DecodedCalendar {
+            text: ics::unfold(&fetched.body),
+            name: fetched.name,
+            stale: fetched.stale,
+            missing: fetched.missing,
+            fetched_at: fetched.fetched_at,
+        })
+        .collect()
+}
+
+pub fn parse(decoded: Vec<DecodedCalendar>) -> Vec<ParsedCalendar> {
+    decoded
+        .into_iter()
+        .map(|decoded| {
+            let text = decoded.text.as_str();
+            let properties = ics::components(text, "VCALENDAR")
+                .into_iter()
+                .flat_map(ics::top_level_properties)
+                .collect();
+            let timezones = ics::components(text, "VTIMEZONE")
+                .into_iter()
+                .filter_map(|block| {
+                    let tzid = ics::top_level_properties(block)
+                        .into_iter()
+                        .find(|property| property.name == "TZID")?;
+                    Some((tzid.value.trim().to_string(), block.to_string()))
+                })
+                .collect();
+            let events = ics::components(text, "VEVENT")
+                .into_iter()
+                .map(str::to_string)
+                .collect();
+
+            ParsedCalendar {
+                name: decoded.name,
+                stale: decoded.stale,
+                missing: decoded.missing,
+                fetched_at: decoded.fetched_at,
+                properties,
+                timezones,
+                events,
+            }
+        })
+        .collect()
+}
+
+/// Problems in a decoded source that parsing silently works around, such as
+/// unbalanced components or events without a UID, for `/status`.
+pub fn parse_warnings(decoded: &DecodedCalendar) -> Vec<String> {
+    let text = decoded.text.as_str();
+    let mut warnings = Vec::new();
+    if ics::components(text, "VCALENDAR").is_empty() {
+        warnings.push("no VCALENDAR component".to_string());
+    }
+
+    let mut open: Vec<String> = Vec::new();
+    let mut invalid_lines = 0;
+    for line in text
+        .lines()
+        .map(str::trim_end)
+        .filter(|line| !line.is_empty())
+    {
+        let Some(property) = Property::parse(line) else {
+            invalid_lines += 1;
+            continue;
+        };
+        let component = property.value.trim().to_ascii_uppercase();
+        match property.name.as_str() {
+            "BEGIN" => open.push(component),
+            "END" => match open.pop() {
+                Some(begun) if begun == component => {}
+                Some(begun) => warnings.push(format!("END:{} closes BEGIN:{}", component, begun)),
+                None => warnings.push(format!("END:{} without BEGIN", component)),
+            },
+            _ => {}
+        }
+    }
+    for component in open {
+        warnings.push(format!("BEGIN:{} is never closed", component));
+    }
+    if invalid_lines > 0 {
+        warnings.push(format!("{} line(s) aren't content lines", invalid_lines));
+    }
+
+    let events: Vec<ics::Event> = ics::components(text, "VEVENT")
+        .into_iter()
+        .map(ics::Event::parse)
+        .collect();
+    let without_uid = events.iter().filter(|e| e.uid.is_none()).count();
+    if without_uid > 0 {
+        warnings.push(format!("{} event(s) without UID", without_uid));
+    }
+    let without_start = events.iter().filter(|e| e.start.is_none()).count();
+    if without_start > 0 {
+        warnings.push(format!(
+            "{} event(s) without a valid DTSTART",
+            without_start
+        ));
+    }
+    warnings
+}
+
+/// Fixes source quirks that confuse clients. Windows zone IDs are renamed to their
+/// IANA names, both on VTIMEZONEs and in events' TZID parameters; then the
+/// group's `description_html` policy and each source's configured `quirks` are
+/// applied. Event times are moved by the source's `time_offset`, and floating
+/// ones given its `assume_timezone`. Last, descriptions are cut down to the
+/// group's limits. `parsed` is in the same order as `group.calendars`.
+pub fn repair(group: &CalendarGroup, parsed: &mut [ParsedCalendar]) {
+    for (cal, calendar) in group.calendars.iter().zip(parsed) {
+        for (tzid, text) in &mut calendar.timezones {
+            let Some(iana) = windows_zones::to_iana(tzid) else {
+                continue;
+            };
+            *tzid = iana.to_string();
+            *text = rename_vtimezone(text, iana);
+        }
+        for event in &mut calendar.events {
+            *event = windows_zones::rewrite_tzids(event);
+        }
+
+        sanitize::apply(group.description_html, calendar);
+        for quirk in cal.quirks() {
+            quirk.apply(calendar);
+        }
+        if let Some(offset) = cal.time_offset {
+            let offset = chrono::Duration::seconds(offset.local_minus_utc().into());
+            for event in &mut calendar.events {
+                *event = timezone::shift_event(event, offset);
+            }
+        }
+        if let Some(tz) = cal.assume_timezone.as_deref().and_then(ics::resolve_tz) {
+            for event in &mut calendar.events {
+                *event = timezone::assume_zone(event, tz.name());
+            }
+        }
+        if !cal.location_rewrites().is_empty() {
+            for event in &mut calendar.events {
+                *event = rewrite_location(event, cal.location_rewrites());
+            }
+        }
+        sanitize::limit(
+            group.drop_alt_description,
+            group.max_description_length,
+            calendar,
+        );
+        for event in &mut calendar.events {
+            if group.force_private {
+                *event = set_property(event, "CLASS", "PRIVATE");
+            }
+            if group.force_transparent {
+                *event = set_property(event, "TRANSP", "TRANSPARENT");
+            }
+        }
+    }
+}
+
+/// Replaces the TZID of a `\n`-separated VTIMEZONE.
+pub fn rename_vtimezone(text: &str, tzid: &str) -> String {
+    text.lines()
+        .map(|line| match Property::parse(line) {
+            Some(property) if property.name == "TZID" => format!("TZID:{}", tzid),
+            _ => line.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Drops the sources whose `include_when` rule isn't met, then the events each
+/// source's `exclude_all_day` or `all_day_only` rejects, then the events their
+/// owners opted out with the `opt_out` category, then those the group's keyword
+/// filters reject, then the events over its `max_events_per_source`. `parsed`
+/// is in the same order as `group.calendars`.
+pub fn filter(
+    group: &CalendarGroup,
+    parsed: Vec<ParsedCalendar>,
+    opt_out: Option<&str>,
+    now: DateTime<Utc>,
+) -> Vec<ParsedCalendar> {
+    let excluded: Vec<bool> = group
+        .calendars
+        .iter()
+        .map(|cal| match exclusion_reason(cal, &parsed, now) {
+            // Shadowed rules are only evaluated by `shadow`
+            Some(_) if cal.include_when.as_ref().is_some_and(|c| c.shadow) => false,
+            Some(reason) => {
+                tracing::debug!("Excluding '{}' from '{}': {}", cal.name, group.name, reason);
+                true
+            }
+            None => false,
+        })
+        .collect();
+
+    let mut kept: Vec<ParsedCalendar> = parsed
+        .into_iter()
+        .zip(excluded)
+        .zip(&group.calendars)
+        .filter(|((_, excluded), _)| !excluded)
+        .map(|((mut calendar, _), cal)| {
+            if cal.exclude_all_day() || cal.all_day_only() {
+                let uids = drop_events(&mut calendar, |event| all_day_reason(cal, event).is_some());
+                if !uids.is_empty() {
+                    tracing::debug!(
+                        "Dropped events of '{}' from '{}' by all-day setting: {}",
+                        calendar.name,
+                        group.name,
+                        uids.join(", ")
+                    );
+                }
+            }
+            calendar
+        })
+        .collect();
+
+    if let Some(category) = opt_out {
+        for calendar in &mut kept {
+            let uids = opt_out_events(calendar, category);
+            if !uids.is_empty() {
+                tracing::debug!(
+                    "Dropped events opted out of '{}' from '{}': {}",
+                    group.name,
+                    calendar.name,
+                    uids.join(", ")
+                );
+            }
+        }
+    }
+    if !group.exclude_keywords.is_empty() || !group.include_keywords.is_empty() {
+        for calendar in &mut kept {
+            let uids = drop_events(calendar, |event| keyword_reason(group, event).is_some());
+            if !uids.is_empty() {
+                tracing::debug!(
+                    "Dropped events of '{}' from '{}' by keyword: {}",
+                    calendar.name,
+                    group.name,
+                    uids.join(", ")
+                );
+            }
+        }
+    }
+    if let Some(max) = group.max_events_per_source {
+        for calendar in &mut kept {
+            let capped = cap_events(calendar, max, now);
+            if capped > 0 {
+                tracing::debug!(
+                    "Dropped {} event(s) of '{}' from '{}' over max_events_per_source",
+                    capped,
+                    calendar.name,
+                    group.name
+                );
+            }
+        }
+    }
+    kept
+}
+
+/// How far ahead a recurring event is looked at to find its next occurrence.
+const NEXT_OCCURRENCE_DAYS: i64 = 366;
+
+/// Keeps the `max` series of a calendar nearest to `now`: upcoming and ongoing
+/// ones by their next occurrence, then past ones, latest first. A master and
+/// its overrides are one series, kept or dropped together. Returns the number of
+/// events dropped.
+pub fn cap_events(calendar: &mut ParsedCalendar, max: usize, now: DateTime<Utc>) -> usize {
+    let events: Vec<ics::Event> = calendar
+        .events
+        .iter()
+        .map(|e| ics::Event::parse(e))
+        .collect();
+    let series = series(&events);
+    if series.len() <= max {
+        return 0;
+    }
+
+    let until = now + chrono::Duration::days(NEXT_OCCURRENCE_DAYS);
+    // Upcoming series sort before past ones (`Ok` before `Err`), past ones
+    // latest first, and those without a start last
+    let rank = |members: &Vec<usize>| {
+        let members: Vec<ics::Event> = members.iter().map(|&idx| events[idx].clone()).collect();
+        let next = ics::occurrences(&members, now, until)
+            .into_iter()
+            .map(|(_, occurrence)| occurrence.start)
+            .min();
+        let latest = members
+            .iter()
+            .filter_map(|e| e.start)
+            .map(|s| s.utc())
+            .max();
+        next.ok_or(Reverse(latest))
+    };
+    let mut ranked: Vec<_> = series
+        .iter()
+        .enumerate()
+        .map(|(i, members)| (rank(members), i))
+        .collect();
+    ranked.sort();
+
+    let mut keep = vec![false; events.len()];
+    for &(_, i) in ranked.iter().take(max) {
+        for &idx in &series[i] {
+            keep[idx] = true;
+        }
+    }
+    let before = calendar.events.len();
+    let texts = std::mem::take(&mut calendar.events);
+    calendar.events = texts
+        .into_iter()
+        .zip(keep)
+        .filter(|(_, keep)| *keep)
+        .map(|(text, _)| text)
+        .collect();
+    before - calendar.events.len()
+}
+
+/// Why the source's `exclude_all_day` or `all_day_only` rejects the event, if it
+/// does.
+pub fn all_day_reason(cal: &SourceCalendar, event: &ics::Event) -> Option<String> {
+    let all_day = event.start.is_some_and(|start| start.all_day);
+    if cal.exclude_all_day() && all_day {
+        return Some("all-day event dropped by exclude_all_day".to_string());
+    }
+    if cal.all_day_only() && !all_day {
+        return Some("timed event dropped by all_day_only".to_string());
+    }
+    None
+}
+
+/// Why the group's `exclude_keywords` or `include_keywords` reject the event, if
+/// they do. Keywords are matched case-insensitively within SUMMARY and
+/// DESCRIPTION.
+pub fn keyword_reason(group: &CalendarGroup, event: &ics::Event) -> Option<String> {
+    let text = [&event.summary, &event.description]
+        .into_iter()
+        .flatten()
+        .map(|text| text.to_lowercase())
+        .collect::<Vec<_>>()
+        .join("\n");
+    let contains = |keyword: &&String| text.contains(&keyword.to_lowercase());
+    if let Some(keyword) = group.exclude_keywords.iter().find(contains) {
+        return Some(format!("matches exclude_keywords '{}'", keyword));
+    }
+    if !group.include_keywords.is_empty() && !group.include_keywords.iter().any(|k| contains(&k)) {
+        return Some("matches none of include_keywords".to_string());
+    }
+    None
+}
+
+/// Whether the event's owner opted it out of combined calendars by listing
+/// `category` in its CATEGORIES.
+pub fn is_opted_out(event: &ics::Event, category: &str) -> bool {
+    event
+        .categories
+        .iter()
+        .any(|c| c.eq_ignore_ascii_case(category))
+}
+
+/// Drops the opted-out events of a calendar, returning their UIDs.
+pub fn opt_out_events(calendar: &mut ParsedCalendar, category: &str) -> Vec<String> {
+    drop_events(calendar, |event| is_opted_out(event, category))
+}
+
+/// Drops the events of a calendar matching `drop`, returning their UIDs. A
+/// recurrence master and its RECURRENCE-ID overrides go together: dropping the
+/// master drops its overrides, while a dropped override becomes an EXDATE on its
+/// master, so the original occurrence doesn't come back. Filters that remove
+/// events go through this, so clients never see orphaned instances.
+pub fn drop_events(
+    calendar: &mut ParsedCalendar,
+    drop: impl Fn(&ics::Event) -> bool,
+) -> Vec<String> {
+    let events: Vec<ics::Event> = calendar
+        .events
+        .iter()
+        .map(|e| ics::Event::parse(e))
+        .collect();
+    let dropped_series: HashSet<&str> = events
+        .iter()
+        .filter(|e| e.recurrence_id.is_none() && drop(e))
+        .filter_map(|e| e.uid.as_deref())
+        .collect();
+
+    let mut dropped = vec![false; events.len()];
+    let mut exdates: HashMap<String, Vec<Property>> = HashMap::new();
+    let mut uids = Vec::new();
+    for (idx, (text, event)) in calendar.events.iter().zip(&events).enumerate() {
+        let in_dropped_series = event
+            .uid
+            .as_deref()
+            .is_some_and(|uid| dropped_series.contains(uid));
+        if !in_dropped_series && !drop(event) {
+            continue;
+        }
+        dropped[idx] = true;
+        let uid = event.uid.clone().unwrap_or_default();
+        if !in_dropped_series {
+            if let Some(recurrence_id) = ics::top_level_properties(text)
+                .into_iter()
+                .find(|p| p.name == "RECURRENCE-ID")
+            {
+                exdates.entry(uid.clone()).or_default().push(recurrence_id);
+            }
+        }
+        if !uids.contains(&uid) {
+            uids.push(uid);
+        }
+    }
+
+    let texts = std::mem::take(&mut calendar.events);
+    calendar.events = texts
+        .into_iter()
+        .zip(events)
+        .zip(dropped)
+        .filter(|(_, dropped)| !dropped)
+        .map(|((text, event), _)| {
+            let recurrence_ids = event
+                .uid
+                .as_ref()
+                .filter(|_| event.recurrence_id.is_none())
+                .and_then(|uid| exdates.get(uid));
+            match recurrence_ids {
+                Some(recurrence_ids) => add_exdates(&text, recurrence_ids),
+                None => text,
+            }
+        })
+        .collect();
+    uids
+}
+
+/// Excludes the occurrences named by `recurrence_ids` from a recurring event,
+/// with an EXDATE for each. Only the TZID and VALUE parameters carry over;
+/// RANGE has no meaning on EXDATE.
+pub fn add_exdates(master: &str, recurrence_ids: &[Property]) -> String {
+    let lines: Vec<&str> = master.lines().collect();
+    let Some((end, body)) = lines.split_last() else {
+        return master.to_string();
+    };
+    let mut output: Vec<String> = body.iter().map(|line| line.to_string()).collect();
+    for recurrence_id in recurrence_ids {
+        let exdate = Property {
+            name: "EXDATE".to_string(),
+            params: recurrence_id
+                .params
+                .iter()
+                .filter(|(name, _)| name == "TZID" || name == "VALUE")
+                .cloned()
+                .collect(),
+            value: recurrence_id.value.clone(),
+        };
+        output.push(exdate.to_string());
+    }
+    output.push(end.to_string());
+    output.join("\n")
+}
+
+/// Checks a source's `include_when` rule against the other sources of its group,
+/// returning why the source is excluded or `None` if it should be included.
+pub fn exclusion_reason(
+    cal: &SourceCalendar,
+    parsed: &[ParsedCalendar],
+    now: DateTime<Utc>,
+) -> Option<String> {
+    let condition = cal.include_when.as_ref()?;
+    let Some(other) = parsed
+        .iter()
+        .find(|calendar| calendar.name == condition.source_empty)
+    else {
+        return Some(format!("'{}' is unavailable", condition.source_empty));
+    };
+
+    let events: Vec<ics::Event> = other.events.iter().map(|e| ics::Event::parse(e)).collect();
+    let upcoming = ics::occurrences(&events, now, now + chrono::Duration::days(condition.days))
+        .into_iter()
+        .filter(|(idx, _)| !events[*idx].is_cancelled())
+        .count();
+
+    (upcoming > 0).then(|| {
+        format!(
+            "'{}' has {} event(s) in the next {} days",
+            condition.source_empty, upcoming, condition.days
+        )
+    })
+}
+
+/// What a rule in shadow mode would have done to a source had it been applied.
+pub struct ShadowEffect {
+    pub source: String,
+    /// `include_when`, or the name of a quirk.
+    pub rule: String,
+    /// Events the rule would remove.
+    pub dropped: usize,
+    /// Events the rule would rewrite.
+    pub changed: usize,
+    /// UIDs of the events that would be removed or rewritten.
+    pub uids: Vec<String>,
+    /// Why the rule would fire, where there's more to say than the counts.
+    pub reason: Option<String>,
+}
+
+/// Evaluates the rules in shadow mode, `shadow_quirks` and `include_when` rules
+/// with `shadow` set, without touching `parsed`. Shadow quirks are tried as if
+/// they ran after the source's other quirks. `parsed` is in the same order as
+/// `group.calendars`.
+pub fn shadow(
+    group: &CalendarGroup,
+    parsed: &[ParsedCalendar],
+    now: DateTime<Utc>,
+) -> Vec<ShadowEffect> {
+    let mut effects = Vec::new();
+    for (cal, calendar) in group.calendars.iter().zip(parsed) {
+        if cal.include_when.as_ref().is_some_and(|c| c.shadow) {
+            let reason = exclusion_reason(cal, parsed, now);
+            let excluded = if reason.is_some() {
+                &calendar.events[..]
+            } else {
+                &[]
+            };
+            effects.push(ShadowEffect {
+                source: cal.name.clone(),
+                rule: "include_when".to_string(),
+                dropped: excluded.len(),
+                changed: 0,
+                uids: unique_uids(excluded.iter()),
+                reason,
+            });
+        }
+
+        for quirk in cal.shadow_quirks() {
+            let mut trial = calendar.clone();
+            quirk.apply(&mut trial);
+            let (removed, added) = event_changes(&calendar.events, &trial.events);
+            effects.push(ShadowEffect {
+                source: cal.name.clone(),
+                rule: quirk.name().to_string(),
+                // A rewritten event shows up as one removed and one added version
+                dropped: removed.len().saturating_sub(added),
+                changed: added.min(removed.len()),
+                uids: unique_uids(removed.into_iter()),
+                reason: None,
+            });
+        }
+    }
+    effects
+}
+
+/// The distinct UIDs of `events`, in order.
+fn unique_uids<'a>(events: impl Iterator<Item = &'a String>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    events
+        .filter_map(|event| ics::Event::parse(event).uid)
+        .filter(|uid| seen.insert(uid.clone()))
+        .collect()
+}
+
+/// Compares two versions of a source's events as multisets of their text,
+/// returning the events only in `before` and the number only in `after`.
+fn event_changes<'a>(before: &'a [String], after: &[String]) -> (Vec<&'a String>, usize) {
+    let mut unmatched: HashMap<&str, usize> = HashMap::new();
+    for event in after {
+        *unmatched.entry(event).or_insert(0) += 1;
+    }
+    let removed: Vec<&String> = before
+        .iter()
+        .filter(|event| match unmatched.get_mut(event.as_str()) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                false
+            }
+            _ => true,
+        })
+        .collect();
+    let added = unmatched.values().sum();
+    (removed, added)
+}
+
+/// The per-event rewrites of the transform stage: the SUMMARY or DESCRIPTION is
+/// tagged with the source name and, with a target zone, times are moved into
+/// that zone.
+pub struct Transform {
+    re_summary: Regex,
+    attribution: Attribution,
+    converter: Option<TimezoneConverter>,
+}
+
+impl Transform {
+    pub fn new(tz: Option<Tz>, attribution: Attribution) -> Self {
+        Self {
+            re_summary: Regex::new(r"SUMMARY:(.*)").unwrap(),
+            attribution,
+            converter: tz.map(TimezoneConverter::new),
+        }
+    }
+
+    pub fn converter(&self) -> Option<&TimezoneConverter> {
+        self.converter.as_ref()
+    }
+
+    /// Rewrites a single event. When explaining, each change is recorded in `trace`
+    /// as `(step, detail)`.
+    pub fn event(
+        &mut self,
+        event_text: &str,
+        source: &ParsedCalendar,
+        mut trace: Option<&mut Vec<(&'static str, String)>>,
+    ) -> String {
+        let source_name = source.name.as_str();
+        let new_event_text = if self.attribution == Attribution::Description {
+            let fetched: DateTime<Utc> = source.fetched_at.into();
+            let line = format!(
+                "Source: {} (fetched {})",
+                source_name,
+                fetched.format("%Y-%m-%d")
+            );
+            if let Some(ref mut trace) = trace {
+                trace.push(("description", format!("'{}' appended", line)));
+            }
+            append_description(event_text, &line)
+        } else if let Some(summary_match) = self.re_summary.captures(event_text) {
+            let original_summary = summary_match.get(1).unwrap().as_str().trim();
+            let new_summary = format!(
+                "SUMMARY:{} [{}]",
+                original_summary,
+                ics::escape_text(source_name)
+            );
+            if let Some(ref mut trace) = trace {
+                trace.push((
+                    "summary",
+                    format!("'{}' tagged with [{}]", original_summary, source_name),
+                ));
+            }
+            event_text.replacen(summary_match.get(0).unwrap().as_str(), &new_summary, 1)
+        } else {
+            if let Some(ref mut trace) = trace {
+                trace.push(("summary", "no SUMMARY to tag".to_string()));
+            }
+            event_text.to_string()
+        };
+
+        let Some(ref mut converter) = self.converter else {
+            return new_event_text;
+        };
+        let converted = converter.convert_event(&new_event_text);
+        if let Some(trace) = trace {
+            let changed = changed_lines(&new_event_text, &converted);
+            trace.push((
+                "timezone",
+                if changed.is_empty() {
+                    format!("nothing to convert into {}", converter.tz().name())
+                } else {
+                    changed.join("; ")
+                },
+            ));
+        }
+        converted
+    }
+}
+
+/// Ends the DESCRIPTION of an unfolded, `\n`-separated VEVENT with a paragraph
+/// holding `text`, adding a DESCRIPTION when there is none. Alarms'
+/// descriptions are left alone.
+fn append_description(event_text: &str, text: &str) -> String {
+    let text = ics::escape_text(text);
+    let mut depth = 0;
+    let mut appended = false;
+    let mut lines: Vec<String> = event_text
+        .lines()
+        .map(|line| {
+            if line.starts_with("BEGIN:") {
+                depth += 1;
+            } else if line.starts_with("END:") {
+                depth -= 1;
+            }
+            match Property::parse(line) {
+                Some(mut property) if depth == 1 && property.name == "DESCRIPTION" && !appended => {
+                    appended = true;
+                    property.value = match property.value.trim() {
+                        "" => text.clone(),
+                        value => format!("{}\\n\\n{}", value, text),
+                    };
+                    property.to_string()
+                }
+                _ => line.to_string(),
+            }
+        })
+        .collect();
+    if !appended {
+        let end = lines.len().saturating_sub(1);
+        lines.insert(end, format!("DESCRIPTION:{}", text));
+    }
+    lines.join("\n")
+}
+
+/// Applies `rules` to the LOCATION of an unfolded, `\n`-separated VEVENT.
+fn rewrite_location(event_text: &str, rules: &[LocationRewrite]) -> String {
+    event_text
+        .lines()
+        .map(|line| match Property::parse(line) {
+            Some(mut property) if property.name == "LOCATION" => {
+                let mut location = ics::unescape_text(&property.value);
+                for rule in rules {
+                    location = rule
+                        .pattern
+                        .replace_all(&location, rule.replacement.as_str())
+                        .into_owned();
+                }
+                property.value = ics::escape_text(&location);
+                property.to_string()
+            }
+            _ => line.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Sets the property `name` of an unfolded, `\n`-separated VEVENT to `value`,
+/// replacing any it has. Alarms are left alone.
+fn set_property(event_text: &str, name: &str, value: &str) -> String {
+    let mut depth = 0;
+    let mut lines: Vec<String> = Vec::new();
+    for line in event_text.lines() {
+        if line.starts_with("BEGIN:") {
+            depth += 1;
+        } else if line.starts_with("END:") {
+            depth -= 1;
+        }
+        let is_name = |property: Property| property.name == name;
+        if depth == 1 && Property::parse(line).is_some_and(is_name) {
+            continue;
+        }
+        lines.push(line.to_string());
+    }
+    let end = lines.len().saturating_sub(1);
+    lines.insert(end, format!("{}:{}", name, value));
+    lines.join("\n")
+}
+
+pub fn transform(parsed: &mut [ParsedCalendar], transform: &mut Transform) {
+    for calendar in parsed {
+        let mut events = std::mem::take(&mut calendar.events);
+        for event in &mut events {
+            *event = transform.event(event, calendar, None);
+        }
+        calendar.events = events;
+    }
+}
+
+/// Drops each source's copy of a series that another source serves, see
+/// `series_owners`, since mixing one source's master with another's overrides
+/// shows ghost occurrences.
+pub fn dedup(parsed: &mut [ParsedCalendar]) {
+    let events: Vec<Vec<ics::Event>> = parsed
+        .iter()
+        .map(|calendar| {
+            calendar
+                .events
+                .iter()
+                .map(|e| ics::Event::parse(e))
+                .collect()
+        })
+        .collect();
+    let owners = series_owners(
+        events
+            .iter()
+            .enumerate()
+            .flat_map(|(i, events)| events.iter().map(move |event| (i, event))),
+    );
+
+    let keep: Vec<Vec<bool>> = events
+        .iter()
+        .enumerate()
+        .map(|(i, events)| {
+            events
+                .iter()
+                .map(|event| {
+                    let Some(ref uid) = event.uid else {
+                        return true;
+                    };
+                    let owner = owners[uid.as_str()];
+                    if owner != i {
+                        tracing::debug!(
+                            "Dropping event {} of '{}': '{}' serves its series",
+                            uid,
+                            parsed[i].name,
+                            parsed[owner].name
+                        );
+                    }
+                    owner == i
+                })
+                .collect()
+        })
+        .collect();
+    for (calendar, keep) in parsed.iter_mut().zip(keep) {
+        let mut keep = keep.into_iter();
+        calendar.events.retain(|_| keep.next().unwrap_or(true));
+    }
+}
+
+/// Drops the series of each source without an instance inside the group's
+/// `window` around `now`. A master and its overrides are one series, kept or
+/// dropped together; events without a DTSTART are always kept.
+pub fn window(group: &CalendarGroup, parsed: &mut [ParsedCalendar], now: DateTime<Utc>) {
+    let Some(ref window) = group.window else {
+        return;
+    };
+    let from = now - chrono::Duration::days(window.past_days);
+    let to = now + chrono::Duration::days(window.future_days);
+
+    for calendar in parsed {
+        let events: Vec<ics::Event> = calendar
+            .events
+            .iter()
+            .map(|e| ics::Event::parse(e))
+            .collect();
+        let mut keep = vec![true; events.len()];
+        for members in series(&events) {
+            let members_events: Vec<ics::Event> =
+                members.iter().map(|&idx| events[idx].clone()).collect();
+            let undated = members_events.iter().all(|event| event.start.is_none());
+            if undated || !ics::occurrences(&members_events, from, to).is_empty() {
+                continue;
+            }
+            for idx in members {
+                keep[idx] = false;
+            }
+        }
+
+        let before = calendar.events.len();
+        let mut keep = keep.into_iter();
+        calendar.events.retain(|_| keep.next().unwrap_or(true));
+        let dropped = before - calendar.events.len();
+        if dropped > 0 {
+            tracing::debug!(
+                "Dropped {} event(s) of '{}' from '{}' outside the window",
+                dropped,
+                calendar.name,
+                group.name
+            );
+        }
+    }
+}
+
+/// The events of every source in one list, each series kept together, so
+/// identical sources always give identical output. Series are sorted by DTSTART
+/// then UID; within one, the master comes first and its overrides follow by
+/// RECURRENCE-ID. Events without a UID stand alone.
+pub fn sort(parsed: &[ParsedCalendar]) -> Vec<String> {
+    let mut series: Vec<Vec<(&String, ics::Event)>> = Vec::new();
+    let mut slots: HashMap<String, usize> = HashMap::new();
+    for text in parsed.iter().flat_map(|calendar| &calendar.events) {
+        let event = ics::Event::parse(text);
+        let Some(uid) = event.uid.clone() else {
+            series.push(vec![(text, event)]);
+            continue;
+        };
+        let slot = *slots.entry(uid).or_insert_with(|| {
+            series.push(Vec::new());
+            series.len() - 1
+        });
+        series[slot].push((text, event));
+    }
+
+    // The text breaks ties, e.g. between copies of a master
+    let utc = |value: Option<ics::DateValue>| value.map(|value| value.utc());
+    for members in &mut series {
+        members.sort_by(|(a_text, a), (b_text, b)| {
+            let key = |event: &ics::Event| {
+                (
+                    event.recurrence_id.is_some(),
+                    utc(event.recurrence_id.or(event.start)),
+                )
+            };
+            key(a).cmp(&key(b)).then_with(|| a_text.cmp(b_text))
+        });
+    }
+    series.sort_by_cached_key(|members| {
+        let (text, ref first) = members[0];
+        (utc(first.start), first.uid.clone(), text.clone())
+    });
+    series
+        .into_iter()
+        .flatten()
+        .map(|(text, _)| text.clone())
+        .collect()
+}
+
+/// Indices of a source's events grouped into series: a master and its
+/// overrides share a UID, and events without a UID are a series of their own.
+fn series(events: &[ics::Event]) -> Vec<Vec<usize>> {
+    let mut series: Vec<Vec<usize>> = Vec::new();
+    let mut slots: HashMap<&str, usize> = HashMap::new();
+    for (idx, event) in events.iter().enumerate() {
+        match event.uid.as_deref() {
+            Some(uid) => {
+                let slot = *slots.entry(uid).or_insert_with(|| {
+                    series.push(Vec::new());
+                    series.len() - 1
+                });
+                series[slot].push(idx);
+            }
+            None => series.push(vec![idx]),
+        }
+    }
+    series
+}
+
+/// For each UID, the index of the source whose copy of the series is served:
+/// the first with a master for it, or else the first with an override.
+pub fn series_owners<'a>(
+    events: impl Iterator<Item = (usize, &'a ics::Event)>,
+) -> HashMap<&'a str, usize> {
+    let mut owners = HashMap::new();
+    let mut with_master = HashSet::new();
+    for (i, event) in events {
+        let Some(ref uid) = event.uid else {
+            continue;
+        };
+        if event.recurrence_id.is_none() && with_master.insert(uid.as_str()) {
+            owners.insert(uid.as_str(), i);
+        } else {
+            owners.entry(uid.as_str()).or_insert(i);
+        }
+    }
+    owners
+}
+
+/// Writes the combined VCALENDAR of `events`. The header is always controlled
+/// by the combiner; source-level properties only appear when passed through,
+/// taken from the first source that defines them. The group's display name goes
+/// into whichever naming properties `name_properties` selects.
+///
+/// With a target zone its canonical VTIMEZONE is the only one. Otherwise only
+/// the VTIMEZONEs some event references are kept, the first source's copy of
+/// each, and missing ones are generated from tzdb since strict clients reject
+/// TZIDs without a definition.
+pub fn serialize(
+    group: &CalendarGroup,
+    name_properties: NameProperties,
+    parsed: &[ParsedCalendar],
+    events: &[String],
+    converter: Option<&TimezoneConverter>,
+    now: DateTime<Utc>,
+) -> String {
+    let mut properties = passthrough_properties(group, parsed);
+    let timezones = match converter {
+        Some(converter) => {
+            // A converted calendar's zone is fixed by the combiner
+            properties.retain(|property| property.name != "X-WR-TIMEZONE");
+            converter.vtimezone(now).into_iter().collect()
+        }
+        None => referenced_timezones(parsed, events, now),
+    };
+
+    let display_name = ics::escape_text(group.display_name());
+    let mut output = String::new();
+    output.push_str("BEGIN:VCALENDAR\r\n");
+    output.push_str(&format!("PRODID:{}\r\n", group.name));
+    output.push_str("VERSION:2.0\r\n");
+    if name_properties.name() {
+        output.push_str(&format!("NAME:{}\r\n", display_name));
+    }
+    if name_properties.x_wr_calname() {
+        output.push_str(&format!("X-WR-CALNAME:{}\r\n", display_name));
+    }
+    if let Some(converter) = converter {
+        output.push_str(&format!("X-WR-TIMEZONE:{}\r\n", converter.tz().name()));
+    }
+    for property in &properties {
+        let mut property = property.clone();
+        property.canonicalize();
+        output.push_str(&format!("{}\r\n", property));
+    }
+
+    // Components are `\n`-separated until here. Lines are canonicalized so the
+    // output only changes when the calendar does, not when a source reorders
+    // parameters between refreshes.
+    for block in timezones.iter().chain(events) {
+        let block = ics::canonicalize_lines(block.trim());
+        output.push_str(&block.replace('\n', "\r\n"));
+        output.push_str("\r\n");
+    }
+
+    output.push_str("END:VCALENDAR\r\n");
+    ics::fold_lines(&output)
+}
+
+/// The VTIMEZONEs `events` reference: the first source's copy of each, or else
+/// one generated from tzdb.
+fn referenced_timezones(
+    parsed: &[ParsedCalendar],
+    events: &[String],
+    now: DateTime<Utc>,
+) -> Vec<String> {
+    let mut defined: HashMap<&str, &str> = HashMap::new();
+    for (tzid, text) in parsed.iter().flat_map(|calendar| &calendar.timezones) {
+        defined.entry(tzid).or_insert(text);
+    }
+
+    let mut referenced = BTreeMap::new();
+    for event in events {
+        timezone::referenced_tzids(event, &mut referenced);
+    }
+
+    referenced
+        .into_iter()
+        .filter_map(|(tzid, first_year)| {
+            if let Some(text) = defined.get(tzid.as_str()) {
+                return Some(text.to_string());
+            }
+            match ics::resolve_tz(&tzid) {
+                Some(tz) => Some(timezone::vtimezone(&tzid, tz, first_year, now)),
+                None => {
+                    tracing::debug!("No VTIMEZONE available for TZID '{}'", tzid);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// A single all-day event marking a group that has no sources yet.
+pub fn placeholder_event(name: &str, now: DateTime<Utc>) -> String {
+    [
+        "BEGIN:VEVENT".to_string(),
+        format!("UID:{}-placeholder", name),
+        // Midnight rather than now, so the output stays the same all day
+        format!("DTSTAMP:{}", now.format("%Y%m%dT000000Z")),
+        format!("DTSTART;VALUE=DATE:{}", now.format("%Y%m%d")),
+        "SUMMARY:No events configured yet".to_string(),
+        "END:VEVENT".to_string(),
+    ]
+    .join("\n")
+}
+
+/// Collects the calendar-level properties a group passes through from its sources.
+/// The first source to define a property wins; conflicting values are dropped.
+fn passthrough_properties(group: &CalendarGroup, parsed: &[ParsedCalendar]) -> Vec<Property> {
+    let mut selected: Vec<Property> = Vec::new();
+    if group.passthrough_properties.is_empty() {
+        return selected;
+    }
+
+    for calendar in parsed {
+        for property in &calendar.properties {
+            let allowed = group
+                .passthrough_properties
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(&property.name));
+            if !allowed {
+                continue;
+            }
+
+            match selected.iter().find(|p| p.name == property.name) {
+                Some(existing) if existing.value != property.value => tracing::debug!(
+                    "Dropping conflicting {} from '{}' in group '{}'",
+                    property.name,
+                    calendar.name,
+                    group.name
+                ),
+                Some(_) => {}
+                None => selected.push(property.clone()),
+            }
+        }
+    }
+
+    selected
+}
+
+/// Describes each line a line-by-line rewrite changed as `before -> after`.
+pub fn changed_lines(before: &str, after: &str) -> Vec<String> {
+    before
+        .lines()
+        .zip(after.lines())
+        .filter(|(before, after)| before != after)
+        .map(|(before, after)| format!("{} -> {}", before, after))
+        .collect()
+}
@@ -0,0 +1,266 @@
+use crate::ics::{self, Property};
+use crate::pipeline::{self, ParsedCalendar};
+use crate::sanitize;
+use crate::windows_zones;
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::LazyLock;
+
+static RE_UNQUOTED_TZONE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(?i)TZID=(tzone://microsoft/[^:;"]*)"#).unwrap());
+
+/// Named workarounds for provider-specific feed bugs, enabled per source with
+/// `quirks`. Each one runs in the repair stage, after the generic repairs.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Quirk {
+    /// Exchange and Office 365 prefix zones with `tzone://Microsoft/`, e.g.
+    /// `TZID=tzone://Microsoft/Utc`, which no client resolves.
+    Office365Tzid,
+    /// Google exports can repeat an event, or reuse a UID for unrelated events,
+    /// which clients collapse into one. Exact copies are dropped and the other
+    /// events get unique UIDs.
+    GoogleDuplicateUid,
+    /// Meetup puts HTML markup in DESCRIPTION; it's reduced to plain text.
+    MeetupHtmlDescription,
+}
+
+impl Quirk {
+    /// The name used in config.json.
+    pub fn name(self) -> &'static str {
+        match self {
+            Quirk::Office365Tzid => "office365-tzid",
+            Quirk::GoogleDuplicateUid => "google-duplicate-uid",
+            Quirk::MeetupHtmlDescription => "meetup-html-description",
+        }
+    }
+
+    pub fn apply(self, calendar: &mut ParsedCalendar) {
+        match self {
+            Quirk::Office365Tzid => office365_tzid(calendar),
+            Quirk::GoogleDuplicateUid => google_duplicate_uid(calendar),
+            Quirk::MeetupHtmlDescription => meetup_html_description(calendar),
+        }
+    }
+}
+
+/// Applies `rewrite` to every property of an unfolded component, leaving lines
+/// it returns `None` for untouched.
+fn rewrite_properties(
+    component: &str,
+    mut rewrite: impl FnMut(Property) -> Option<Property>,
+) -> String {
+    component
+        .lines()
+        .map(|line| {
+            Property::parse(line)
+                .and_then(&mut rewrite)
+                .map_or_else(|| line.to_string(), |property| property.to_string())
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn office365_tzid(calendar: &mut ParsedCalendar) {
+    const PREFIX: &str = "tzone://microsoft/";
+    let strip = |tzid: &str| -> Option<String> {
+        if !tzid.get(..PREFIX.len())?.eq_ignore_ascii_case(PREFIX) {
+            return None;
+        }
+        let name = &tzid[PREFIX.len()..];
+        if name.eq_ignore_ascii_case("Utc") {
+            Some("UTC".to_string())
+        } else {
+            windows_zones::to_iana(name).map(str::to_string)
+        }
+    };
+
+    for (tzid, text) in &mut calendar.timezones {
+        if let Some(renamed) = strip(tzid) {
+            *text = pipeline::rename_vtimezone(text, &renamed);
+            *tzid = renamed;
+        }
+    }
+    for event in &mut calendar.events {
+        // Unquoted, the colon in the scheme would end the parameter list early
+        let quoted = RE_UNQUOTED_TZONE.replace_all(event, "TZID=\"$1\"");
+        *event = rewrite_properties(&quoted, |mut property| {
+            let renamed = strip(property.param("TZID")?)?;
+            if renamed == "UTC" {
+                // UTC is written as a Z suffix rather than a TZID
+                property.params.retain(|(key, _)| key != "TZID");
+                property.value = property
+                    .value
+                    .split(',')
+                    .map(|value| {
+                        if value.contains('T') && !value.ends_with('Z') {
+                            format!("{}Z", value)
+                        } else {
+                            value.to_string()
+                        }
+                    })
+                    .collect::<Vec<_>>()
+                    .join(",");
+            } else {
+                for (key, value) in &mut property.params {
+                    if key == "TZID" {
+                        *value = renamed.clone();
+                    }
+                }
+            }
+            Some(property)
+        });
+    }
+}
+
+fn google_duplicate_uid(calendar: &mut ParsedCalendar) {
+    let mut seen_texts = HashSet::new();
+    calendar.events.retain(|event| seen_texts.insert(event.clone()));
+
+    // Overrides share their master's UID on purpose; only masters are renamed
+    let mut masters: HashMap<String, usize> = HashMap::new();
+    for event in &mut calendar.events {
+        let parsed = ics::Event::parse(event);
+        let Some(uid) = parsed.uid else {
+            continue;
+        };
+        if parsed.recurrence_id.is_some() {
+            continue;
+        }
+        let count = masters.entry(uid.clone()).or_insert(0);
+        *count += 1;
+        if *count == 1 {
+            continue;
+        }
+
+        let unique = format!("{}-{}", uid, count);
+        tracing::debug!(
+            "Renaming duplicate UID {} to {} in '{}'",
+            uid,
+            unique,
+            calendar.name
+        );
+        *event = rewrite_properties(event, |mut property| {
+            if property.name != "UID" {
+                return None;
+            }
+            property.value = unique.clone();
+            Some(property)
+        });
+    }
+}
+
+fn meetup_html_description(calendar: &mut ParsedCalendar) {
+    for event in &mut calendar.events {
+        *event = rewrite_properties(event, |mut property| {
+            if property.name != "DESCRIPTION" || !property.value.contains('<') {
+                return None;
+            }
+            property.value = sanitize::html_to_text(&property.value);
+            Some(property)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    fn calendar(timezones: &[(&str, &str)], events: &[&str]) -> ParsedCalendar {
+        ParsedCalendar {
+            name: "Source".to_string(),
+            stale: false,
+            missing: false,
+            fetched_at: SystemTime::UNIX_EPOCH,
+            properties: Vec::new(),
+            timezones: timezones
+                .iter()
+                .map(|(tzid, text)| (tzid.to_string(), text.to_string()))
+                .collect(),
+            events: events.iter().map(|event| event.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn office365_tzid_maps_windows_zones() {
+        let mut cal = calendar(
+            &[(
+                "tzone://Microsoft/W. Europe Standard Time",
+                "BEGIN:VTIMEZONE\nTZID:tzone://Microsoft/W. Europe Standard Time\nEND:VTIMEZONE",
+            )],
+            &["BEGIN:VEVENT\nUID:a\nDTSTART;TZID=tzone://Microsoft/W. Europe Standard Time:20240501T090000\nEND:VEVENT"],
+        );
+        Quirk::Office365Tzid.apply(&mut cal);
+
+        assert_eq!(cal.timezones[0].0, "Europe/Berlin");
+        assert!(cal.timezones[0].1.contains("TZID:Europe/Berlin"));
+        assert!(cal.events[0].contains("DTSTART;TZID=Europe/Berlin:20240501T090000"));
+    }
+
+    #[test]
+    fn office365_tzid_writes_utc_as_z() {
+        let mut cal = calendar(
+            &[],
+            &["BEGIN:VEVENT\nUID:a\nDTSTART;TZID=tzone://Microsoft/Utc:20240501T090000\nEXDATE;TZID=tzone://Microsoft/Utc:20240502T090000,20240503T090000\nEND:VEVENT"],
+        );
+        Quirk::Office365Tzid.apply(&mut cal);
+
+        assert!(cal.events[0].contains("\nDTSTART:20240501T090000Z\n"));
+        assert!(cal.events[0].contains("\nEXDATE:20240502T090000Z,20240503T090000Z\n"));
+    }
+
+    #[test]
+    fn office365_tzid_leaves_other_zones() {
+        let event = "BEGIN:VEVENT\nUID:a\nDTSTART;TZID=Europe/Paris:20240501T090000\nEND:VEVENT";
+        let mut cal = calendar(&[], &[event]);
+        Quirk::Office365Tzid.apply(&mut cal);
+
+        assert_eq!(cal.events[0], event);
+    }
+
+    #[test]
+    fn google_duplicate_uid_drops_copies_and_renames_reused_uids() {
+        let copy = "BEGIN:VEVENT\nUID:a\nSUMMARY:Standup\nEND:VEVENT";
+        let reused = "BEGIN:VEVENT\nUID:a\nSUMMARY:Lunch\nEND:VEVENT";
+        let mut cal = calendar(&[], &[copy, copy, reused]);
+        Quirk::GoogleDuplicateUid.apply(&mut cal);
+
+        assert_eq!(
+            cal.events,
+            [copy, "BEGIN:VEVENT\nUID:a-2\nSUMMARY:Lunch\nEND:VEVENT"]
+        );
+    }
+
+    #[test]
+    fn google_duplicate_uid_keeps_overrides() {
+        let master = "BEGIN:VEVENT\nUID:a\nRRULE:FREQ=DAILY\nDTSTART:20240501T090000Z\nEND:VEVENT";
+        let over = "BEGIN:VEVENT\nUID:a\nRECURRENCE-ID:20240502T090000Z\nDTSTART:20240502T100000Z\nEND:VEVENT";
+        let mut cal = calendar(&[], &[master, over]);
+        Quirk::GoogleDuplicateUid.apply(&mut cal);
+
+        assert_eq!(cal.events, [master, over]);
+    }
+
+    #[test]
+    fn meetup_html_description_strips_markup() {
+        let mut cal = calendar(
+            &[],
+            &["BEGIN:VEVENT\nUID:a\nDESCRIPTION:<p>Bring <b>snacks</b> &amp\\; water</p>\nEND:VEVENT"],
+        );
+        Quirk::MeetupHtmlDescription.apply(&mut cal);
+
+        let event = ics::Event::parse(&cal.events[0]);
+        assert_eq!(event.description.as_deref(), Some("Bring snacks & water"));
+    }
+
+    #[test]
+    fn meetup_html_description_leaves_plain_text() {
+        let event = "BEGIN:VEVENT\nUID:a\nDESCRIPTION:Bring snacks\\, water\nEND:VEVENT";
+        let mut cal = calendar(&[], &[event]);
+        Quirk::MeetupHtmlDescription.apply(&mut cal);
+
+        assert_eq!(cal.events[0], event);
+    }
+}
@@ -0,0 +1,60 @@
+use crate::config::RateLimit;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Buckets kept before full ones are pruned.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Token-bucket limiter: each client may make `burst` requests at once, refilled
+/// at `per_minute` requests per minute.
+pub struct RateLimiter {
+    burst: f64,
+    per_second: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limit: &RateLimit) -> Self {
+        Self {
+            burst: limit.burst.into(),
+            per_second: f64::from(limit.per_minute) / 60.0,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token for `client`, or returns how long until one is available.
+    pub fn check(&self, client: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= PRUNE_THRESHOLD {
+            // A bucket that has refilled completely is the same as no bucket
+            buckets.retain(|_, bucket| self.refilled(bucket, now) < self.burst);
+        }
+
+        let bucket = buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.per_second))
+        }
+    }
+
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.per_second).min(self.burst)
+    }
+}
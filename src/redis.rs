@@ -0,0 +1,215 @@
+//! A minimal Redis client speaking RESP2, covering the handful of commands the
+//! shared cache needs. One connection is shared by all requests and reopened
+//! after any error.
+
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RedisError {
+    #[error("Invalid Redis URL {0}")]
+    Url(String),
+    #[error("Redis I/O failed")]
+    Io(#[from] std::io::Error),
+    #[error("Redis didn't answer within {0:?}")]
+    Timeout(Duration),
+    #[error("Unexpected reply from Redis: {0}")]
+    Protocol(String),
+    #[error("Redis error: {0}")]
+    Server(String),
+}
+
+/// A decoded RESP reply.
+#[derive(Debug)]
+enum Reply {
+    /// A status such as `OK`.
+    Simple,
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+pub struct Client {
+    address: String,
+    password: Option<String>,
+    database: u32,
+    timeout: Duration,
+    connection: Mutex<Option<BufStream<TcpStream>>>,
+}
+
+impl Client {
+    /// Parses a `redis://[:password@]host[:port][/database]` URL. Nothing is
+    /// connected until the first command.
+    pub fn new(url: &str, timeout: Duration) -> Result<Self, RedisError> {
+        let invalid = || RedisError::Url(crate::logging::redact_url(url));
+        let parsed = reqwest::Url::parse(url).map_err(|_| invalid())?;
+        if parsed.scheme() != "redis" {
+            return Err(invalid());
+        }
+        let host = parsed.host_str().ok_or_else(invalid)?;
+        let database = match parsed.path().trim_start_matches('/') {
+            "" => 0,
+            database => database.parse().map_err(|_| invalid())?,
+        };
+        Ok(Self {
+            address: format!("{}:{}", host, parsed.port().unwrap_or(6379)),
+            password: parsed.password().map(str::to_string),
+            database,
+            timeout,
+            connection: Mutex::new(None),
+        })
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, RedisError> {
+        match self.command(&[b"GET", key.as_bytes()]).await? {
+            Reply::Bulk(value) => Ok(value),
+            other => Err(RedisError::Protocol(format!("{:?}", other))),
+        }
+    }
+
+    /// Stores `value` under `key`, expiring after `ttl`.
+    pub async fn set(&self, key: &str, value: &[u8], ttl: Duration) -> Result<(), RedisError> {
+        let millis = ttl.as_millis().max(1).to_string();
+        let args: [&[u8]; 5] = [b"SET", key.as_bytes(), value, b"PX", millis.as_bytes()];
+        self.command(&args).await.map(|_| ())
+    }
+
+    /// Deletes `key`, returning whether it existed.
+    pub async fn del(&self, key: &str) -> Result<bool, RedisError> {
+        match self.command(&[b"DEL", key.as_bytes()]).await? {
+            Reply::Integer(deleted) => Ok(deleted > 0),
+            other => Err(RedisError::Protocol(format!("{:?}", other))),
+        }
+    }
+
+    /// Every key starting with `prefix`, found with SCAN so Redis isn't blocked.
+    pub async fn keys(&self, prefix: &str) -> Result<Vec<String>, RedisError> {
+        let pattern = format!("{}*", glob_escape(prefix));
+        let mut cursor = "0".to_string();
+        let mut keys = Vec::new();
+        loop {
+            let reply = self
+                .command(&[b"SCAN", cursor.as_bytes(), b"MATCH", pattern.as_bytes()])
+                .await?;
+            let Reply::Array(mut parts) = reply else {
+                return Err(RedisError::Protocol(format!("{:?}", reply)));
+            };
+            let (Some(Reply::Array(batch)), Some(Reply::Bulk(Some(next)))) =
+                (parts.pop(), parts.pop())
+            else {
+                return Err(RedisError::Protocol("malformed SCAN reply".to_string()));
+            };
+            for key in batch {
+                if let Reply::Bulk(Some(key)) = key {
+                    keys.push(String::from_utf8_lossy(&key).into_owned());
+                }
+            }
+            cursor = String::from_utf8_lossy(&next).into_owned();
+            if cursor == "0" {
+                return Ok(keys);
+            }
+        }
+    }
+
+    /// Sends one command and reads its reply, connecting first if needed. Any
+    /// failure drops the connection, so the next command starts afresh.
+    async fn command(&self, args: &[&[u8]]) -> Result<Reply, RedisError> {
+        let mut connection = self.connection.lock().await;
+        let result = tokio::time::timeout(self.timeout, async {
+            if connection.is_none() {
+                *connection = Some(self.connect().await?);
+            }
+            let stream = connection.as_mut().expect("connected above");
+            send(stream, args).await?;
+            read_reply(stream).await
+        })
+        .await
+        .unwrap_or(Err(RedisError::Timeout(self.timeout)));
+        if matches!(result, Err(ref e) if !matches!(e, RedisError::Server(_))) {
+            *connection = None;
+        }
+        result
+    }
+
+    async fn connect(&self) -> Result<BufStream<TcpStream>, RedisError> {
+        let mut stream = BufStream::new(TcpStream::connect(&self.address).await?);
+        if let Some(ref password) = self.password {
+            send(&mut stream, &[b"AUTH", password.as_bytes()]).await?;
+            read_reply(&mut stream).await?;
+        }
+        if self.database != 0 {
+            let database = self.database.to_string();
+            send(&mut stream, &[b"SELECT", database.as_bytes()]).await?;
+            read_reply(&mut stream).await?;
+        }
+        Ok(stream)
+    }
+}
+
+async fn send(stream: &mut BufStream<TcpStream>, args: &[&[u8]]) -> Result<(), RedisError> {
+    let mut request = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        request.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        request.extend_from_slice(arg);
+        request.extend_from_slice(b"\r\n");
+    }
+    stream.write_all(&request).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+async fn read_reply(stream: &mut BufStream<TcpStream>) -> Result<Reply, RedisError> {
+    let line = read_line(stream).await?;
+    let (kind, rest) = line.split_at(1.min(line.len()));
+    let number = || {
+        rest.parse::<i64>()
+            .map_err(|_| RedisError::Protocol(line.clone()))
+    };
+    match kind {
+        "+" => Ok(Reply::Simple),
+        "-" => Err(RedisError::Server(rest.to_string())),
+        ":" => Ok(Reply::Integer(number()?)),
+        "$" => {
+            let Ok(len) = usize::try_from(number()?) else {
+                return Ok(Reply::Bulk(None));
+            };
+            let mut value = vec![0; len + 2];
+            stream.read_exact(&mut value).await?;
+            value.truncate(len);
+            Ok(Reply::Bulk(Some(value)))
+        }
+        "*" => {
+            let Ok(len) = usize::try_from(number()?) else {
+                return Ok(Reply::Array(Vec::new()));
+            };
+            let mut items = Vec::with_capacity(len);
+            for _ in 0..len {
+                items.push(Box::pin(read_reply(stream)).await?);
+            }
+            Ok(Reply::Array(items))
+        }
+        _ => Err(RedisError::Protocol(line.clone())),
+    }
+}
+
+async fn read_line(stream: &mut BufStream<TcpStream>) -> Result<String, RedisError> {
+    let mut line = String::new();
+    if stream.read_line(&mut line).await? == 0 {
+        return Err(RedisError::Protocol("connection closed".to_string()));
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Escapes the glob characters SCAN's MATCH would interpret.
+fn glob_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
@@ -0,0 +1,174 @@
+//! Cleaning up HTML that feeds embed in event descriptions.
+
+use crate::ics::{self, Property};
+use crate::pipeline::ParsedCalendar;
+use regex::Regex;
+use serde::Deserialize;
+use std::sync::LazyLock;
+
+static RE_HIDDEN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?is)<script\b.*?</script\s*>|<style\b.*?</style\s*>|<!--.*?-->").unwrap()
+});
+static RE_LINE_BREAK: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)<br\s*/?>|</p>|</div>|</li>").unwrap());
+static RE_TAG: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<[^>]*>").unwrap());
+static RE_ELEMENT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"<(/?)([a-zA-Z][a-zA-Z0-9]*)([^>]*)>").unwrap());
+static RE_HREF: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(?i)\bhref\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s>]+))"#).unwrap());
+static RE_NUMERIC_ENTITY: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"&#(?:([0-9]+)|[xX]([0-9a-fA-F]+));").unwrap());
+static RE_BLANK_LINES: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(\n\s*){3,}").unwrap());
+
+/// Formatting elements kept by `HtmlPolicy::Safe`.
+const SAFE_ELEMENTS: &[&str] = &[
+    "a", "b", "strong", "i", "em", "u", "p", "br", "ul", "ol", "li",
+];
+/// Link schemes kept by `HtmlPolicy::Safe`; other links lose their target.
+const SAFE_SCHEMES: &[&str] = &["http:", "https:", "mailto:"];
+
+/// How a group handles HTML in DESCRIPTION and X-ALT-DESC.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HtmlPolicy {
+    /// Passes descriptions through untouched.
+    #[default]
+    Keep,
+    /// Reduces DESCRIPTION to plain text and drops X-ALT-DESC, which only
+    /// repeats it as HTML.
+    Strip,
+    /// Keeps basic formatting and http(s)/mailto links. Scripts, styles, other
+    /// elements and all other attributes are removed.
+    Safe,
+}
+
+/// Applies `policy` to every event of `calendar`.
+pub fn apply(policy: HtmlPolicy, calendar: &mut ParsedCalendar) {
+    if policy == HtmlPolicy::Keep {
+        return;
+    }
+    for event in &mut calendar.events {
+        let mut lines = Vec::new();
+        for line in event.lines() {
+            let Some(mut property) = Property::parse(line) else {
+                lines.push(line.to_string());
+                continue;
+            };
+            let is_alt = property.name == "X-ALT-DESC";
+            if (property.name != "DESCRIPTION" && !is_alt) || !property.value.contains('<') {
+                lines.push(line.to_string());
+                continue;
+            }
+            match policy {
+                HtmlPolicy::Strip if is_alt => continue,
+                HtmlPolicy::Strip => property.value = html_to_text(&property.value),
+                _ => property.value = safe_html(&property.value),
+            }
+            lines.push(property.to_string());
+        }
+        *event = lines.join("\n");
+    }
+}
+
+/// Drops X-ALT-DESC when `drop_alt` is set and truncates DESCRIPTION values
+/// longer than `max_length` characters, for every event of `calendar`.
+pub fn limit(drop_alt: bool, max_length: Option<usize>, calendar: &mut ParsedCalendar) {
+    if !drop_alt && max_length.is_none() {
+        return;
+    }
+    for event in &mut calendar.events {
+        let mut lines = Vec::new();
+        for line in event.lines() {
+            let Some(mut property) = Property::parse(line) else {
+                lines.push(line.to_string());
+                continue;
+            };
+            let truncated = match property.name.as_str() {
+                "X-ALT-DESC" if drop_alt => continue,
+                "DESCRIPTION" => max_length.and_then(|max| truncate(&property.value, max)),
+                _ => None,
+            };
+            match truncated {
+                Some(value) => {
+                    property.value = value;
+                    lines.push(property.to_string());
+                }
+                None => lines.push(line.to_string()),
+            }
+        }
+        *event = lines.join("\n");
+    }
+}
+
+/// A TEXT value cut to `max` characters, counted unescaped, with "…" as the
+/// last one. `None` if it is short enough.
+fn truncate(value: &str, max: usize) -> Option<String> {
+    let text = ics::unescape_text(value);
+    if text.chars().count() <= max {
+        return None;
+    }
+    let mut truncated: String = text.chars().take(max - 1).collect();
+    truncated.truncate(truncated.trim_end().len());
+    truncated.push('…');
+    Some(ics::escape_text(&truncated))
+}
+
+/// Reduces an HTML TEXT value to plain text, turning block ends and `<br>` into
+/// line breaks.
+pub fn html_to_text(value: &str) -> String {
+    let html = ics::unescape_text(value);
+    let text = RE_HIDDEN.replace_all(&html, "");
+    let text = RE_LINE_BREAK.replace_all(&text, "\n");
+    let text = RE_TAG.replace_all(&text, "");
+    let text = decode_entities(&text);
+    ics::escape_text(&RE_BLANK_LINES.replace_all(text.trim(), "\n\n"))
+}
+
+/// Keeps only `SAFE_ELEMENTS` of an HTML TEXT value, without attributes except
+/// a link's `href`.
+fn safe_html(value: &str) -> String {
+    let html = ics::unescape_text(value);
+    let html = RE_HIDDEN.replace_all(&html, "");
+    let html = RE_ELEMENT.replace_all(&html, |caps: &regex::Captures| {
+        let name = caps[2].to_ascii_lowercase();
+        if !SAFE_ELEMENTS.contains(&name.as_str()) {
+            return String::new();
+        }
+        if &caps[1] == "/" {
+            return format!("</{}>", name);
+        }
+        if name != "a" {
+            return format!("<{}>", name);
+        }
+        let href = RE_HREF.captures(&caps[3]).and_then(|href| {
+            let href = href.get(1).or(href.get(2)).or(href.get(3))?.as_str().trim();
+            let lower = href.to_ascii_lowercase();
+            SAFE_SCHEMES
+                .iter()
+                .any(|scheme| lower.starts_with(scheme))
+                .then(|| href.replace('"', "&quot;"))
+        });
+        match href {
+            Some(href) => format!("<a href=\"{}\">", href),
+            None => "<a>".to_string(),
+        }
+    });
+    ics::escape_text(&html)
+}
+
+fn decode_entities(text: &str) -> String {
+    let text = RE_NUMERIC_ENTITY.replace_all(text, |caps: &regex::Captures| {
+        let code = match (caps.get(1), caps.get(2)) {
+            (Some(decimal), _) => decimal.as_str().parse().ok(),
+            (_, Some(hex)) => u32::from_str_radix(hex.as_str(), 16).ok(),
+            _ => None,
+        };
+        code.and_then(char::from_u32)
+            .map_or_else(String::new, String::from)
+    });
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&amp;", "&")
+}
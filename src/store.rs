@@ -0,0 +1,283 @@
+//! Persisted event history of groups with `history`, kept in `state_dir`: the
+//! events at the last check, and a log of every change since, in the SQLite
+//! database `history.sqlite3`.
+
+use crate::notify::Snapshot;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use percent_encoding::percent_decode_str;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use webcal_combiner::api::{ChangeKind, ChangeRecord, EventSummary, SyncedEvent};
+
+/// Directory inside the state directory the history was kept in as files,
+/// before state version 3.
+pub const HISTORY_DIR: &str = "history";
+
+/// Database inside the state directory holding the history.
+pub const DATABASE: &str = "history.sqlite3";
+
+/// The tables: the groups checked at least once, the events of each at its
+/// last check, and the change log, whose ids are the positions `sync` takes.
+/// An event without a RECURRENCE-ID has an empty `recurrence_id`.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS checks (
+        grp TEXT PRIMARY KEY
+    );
+    CREATE TABLE IF NOT EXISTS events (
+        grp TEXT NOT NULL,
+        uid TEXT NOT NULL,
+        recurrence_id TEXT NOT NULL,
+        event TEXT NOT NULL,
+        data TEXT NOT NULL,
+        PRIMARY KEY (grp, uid, recurrence_id)
+    );
+    CREATE TABLE IF NOT EXISTS changes (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        grp TEXT NOT NULL,
+        record TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS changes_by_group ON changes (grp, id);
+";
+
+/// How long a write waits for another to finish before failing.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A snapshot entry as written to disk before state version 3.
+#[derive(Deserialize)]
+struct StoredEvent {
+    #[serde(flatten)]
+    event: EventSummary,
+    text: String,
+}
+
+pub struct Store {
+    path: PathBuf,
+}
+
+/// The events of a group that changed after a position in its log.
+pub struct Delta {
+    /// The id of the last log entry covered, to continue from next time.
+    pub position: i64,
+    pub updated: Vec<SyncedEvent>,
+    pub removed: Vec<EventSummary>,
+}
+
+impl Store {
+    pub fn new(state_dir: impl AsRef<Path>) -> Self {
+        Self {
+            path: state_dir.as_ref().join(DATABASE),
+        }
+    }
+
+    /// The events of `group` at its last recorded check, if there was one.
+    pub fn snapshot(&self, group: &str) -> Result<Option<Snapshot>> {
+        read_snapshot(&self.open()?, group)
+    }
+
+    /// Appends `changes` to the log of `group` and makes `snapshot` its latest,
+    /// both or neither.
+    pub fn record(&self, group: &str, snapshot: &Snapshot, changes: &[ChangeRecord]) -> Result<()> {
+        let mut connection = self.open()?;
+        let transaction = connection.transaction()?;
+        write(&transaction, group, snapshot, changes)?;
+        transaction.commit().context("Failed to record the history")
+    }
+
+    /// The changes to `group` recorded after `since`, oldest first.
+    pub fn changes(&self, group: &str, since: Option<DateTime<Utc>>) -> Result<Vec<ChangeRecord>> {
+        let changes = read_changes(&self.open()?, group, 0)?;
+        Ok(changes
+            .into_iter()
+            .filter(|change| {
+                let at = DateTime::parse_from_rfc3339(&change.at).ok();
+                since.is_none_or(|since| at.is_some_and(|at| at > since))
+            })
+            .collect())
+    }
+
+    /// The events of `group` changed after the log entry with id `position`,
+    /// as they are in the latest snapshot; every event without a `position`.
+    /// `None` if the log doesn't reach `position`, e.g. after the history was
+    /// reset.
+    pub fn sync(&self, group: &str, position: Option<i64>) -> Result<Option<Delta>> {
+        let mut connection = self.open()?;
+        // One transaction, so a check recorded meanwhile is seen whole or not
+        // at all
+        let transaction = connection.transaction()?;
+        let last: i64 = transaction.query_row(
+            "SELECT COALESCE(MAX(id), 0) FROM changes WHERE grp = ?1",
+            [group],
+            |row| row.get(0),
+        )?;
+        let snapshot = read_snapshot(&transaction, group)?.unwrap_or_default();
+        let synced = |(event, text): &(EventSummary, String)| SyncedEvent {
+            event: event.clone(),
+            data: text.clone(),
+        };
+        let Some(position) = position else {
+            return Ok(Some(Delta {
+                position: last,
+                updated: snapshot.values().map(synced).collect(),
+                removed: Vec::new(),
+            }));
+        };
+        if position > last {
+            return Ok(None);
+        }
+        let changes = read_changes(&transaction, group, position)?;
+
+        // The latest change to each event, in the order they were first changed
+        let mut latest: Vec<&ChangeRecord> = Vec::new();
+        let mut index = HashMap::new();
+        for change in &changes {
+            let key = (change.event.uid.clone(), change.event.recurrence_id.clone());
+            match index.get(&key) {
+                Some(&i) => latest[i] = change,
+                None => {
+                    index.insert(key, latest.len());
+                    latest.push(change);
+                }
+            }
+        }
+        let mut delta = Delta {
+            position: last,
+            updated: Vec::new(),
+            removed: Vec::new(),
+        };
+        for change in latest {
+            let key = (change.event.uid.clone(), change.event.recurrence_id.clone());
+            match snapshot.get(&key) {
+                Some(current) if change.change != ChangeKind::Removed => {
+                    delta.updated.push(synced(current))
+                }
+                _ => delta.removed.push(change.event.clone()),
+            }
+        }
+        Ok(Some(delta))
+    }
+
+    fn open(&self) -> Result<Connection> {
+        let connection = Connection::open(&self.path)
+            .context(format!("Failed to open {}", self.path.display()))?;
+        connection.busy_timeout(BUSY_TIMEOUT)?;
+        connection
+            .execute_batch(SCHEMA)
+            .context(format!("Failed to set up {}", self.path.display()))?;
+        Ok(connection)
+    }
+}
+
+/// Moves a history kept as files under `history/`, as before state version 3,
+/// into the database. A group already in the database was moved by an earlier,
+/// interrupted run and is skipped.
+pub fn import_files(state_dir: &Path) -> Result<()> {
+    let dir = state_dir.join(HISTORY_DIR);
+    if !dir.exists() {
+        return Ok(());
+    }
+    let mut connection = Store::new(state_dir).open()?;
+    let entries = fs::read_dir(&dir).context(format!("Failed to read {}", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_none_or(|extension| extension != "json") {
+            continue;
+        }
+        let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        let group = percent_decode_str(stem).decode_utf8_lossy().into_owned();
+        let content =
+            fs::read_to_string(&path).context(format!("Failed to read {}", path.display()))?;
+        let stored: Vec<StoredEvent> = serde_json::from_str(&content)
+            .context(format!("Invalid history snapshot {}", path.display()))?;
+        let snapshot: Snapshot = stored
+            .into_iter()
+            .map(|stored| {
+                let key = (stored.event.uid.clone(), stored.event.recurrence_id.clone());
+                (key, (stored.event, stored.text))
+            })
+            .collect();
+        // A line cut short by a crash is skipped rather than failing the rest
+        let log = fs::read_to_string(path.with_extension("log")).unwrap_or_default();
+        let changes: Vec<ChangeRecord> = log
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect();
+
+        let transaction = connection.transaction()?;
+        if read_snapshot(&transaction, &group)?.is_none() {
+            write(&transaction, &group, &snapshot, &changes)?;
+        }
+        transaction.commit()?;
+    }
+    fs::remove_dir_all(&dir).context(format!("Failed to remove {}", dir.display()))
+}
+
+fn read_snapshot(connection: &Connection, group: &str) -> Result<Option<Snapshot>> {
+    let checked = connection
+        .query_row("SELECT 1 FROM checks WHERE grp = ?1", [group], |_| Ok(()))
+        .optional()?;
+    if checked.is_none() {
+        return Ok(None);
+    }
+    let mut statement = connection.prepare("SELECT event, data FROM events WHERE grp = ?1")?;
+    let rows = statement.query_map([group], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+    })?;
+    let mut snapshot = Snapshot::new();
+    for row in rows {
+        let (event, data) = row?;
+        let event: EventSummary =
+            serde_json::from_str(&event).context("Invalid event in the history")?;
+        let key = (event.uid.clone(), event.recurrence_id.clone());
+        snapshot.insert(key, (event, data));
+    }
+    Ok(Some(snapshot))
+}
+
+/// The changes to `group` after the log entry with id `after`, oldest first.
+fn read_changes(connection: &Connection, group: &str, after: i64) -> Result<Vec<ChangeRecord>> {
+    let mut statement =
+        connection.prepare("SELECT record FROM changes WHERE grp = ?1 AND id > ?2 ORDER BY id")?;
+    let rows = statement.query_map(params![group, after], |row| row.get::<_, String>(0))?;
+    let mut changes = Vec::new();
+    for record in rows {
+        let change = serde_json::from_str(&record?).context("Invalid change in the history")?;
+        changes.push(change);
+    }
+    Ok(changes)
+}
+
+fn write(
+    transaction: &Transaction,
+    group: &str,
+    snapshot: &Snapshot,
+    changes: &[ChangeRecord],
+) -> Result<()> {
+    for change in changes {
+        transaction.execute(
+            "INSERT INTO changes (grp, record) VALUES (?1, ?2)",
+            params![group, serde_json::to_string(change)?],
+        )?;
+    }
+    transaction.execute("INSERT OR IGNORE INTO checks (grp) VALUES (?1)", [group])?;
+    transaction.execute("DELETE FROM events WHERE grp = ?1", [group])?;
+    let mut insert = transaction.prepare(
+        "INSERT INTO events (grp, uid, recurrence_id, event, data) VALUES (?1, ?2, ?3, ?4, ?5)",
+    )?;
+    for (event, data) in snapshot.values() {
+        insert.execute(params![
+            group,
+            event.uid,
+            event.recurrence_id.as_deref().unwrap_or_default(),
+            serde_json::to_string(event)?,
+            data,
+        ])?;
+    }
+    Ok(())
+}
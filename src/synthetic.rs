@@ -0,0 +1,92 @@
+//! Built-in sources with generated content, fetched from `synthetic:` URLs. The
+//! `monitoring_group` is made of them, so uptime checks can exercise fetching,
+//! merging and serving without depending on any real upstream.
+
+use crate::config::{CalendarGroup, MonitoringGroup, SourceCalendar};
+use std::time::Duration;
+
+const SCHEME: &str = "synthetic:";
+
+/// The same events on every fetch.
+const FIXED: &str = "synthetic:fixed";
+/// Events served after `delay_ms`, e.g. `synthetic:slow?delay_ms=1500`.
+const SLOW: &str = "synthetic:slow";
+
+const FIXED_EVENTS: &str = "BEGIN:VEVENT
+UID:daily-heartbeat@webcal-combiner
+DTSTAMP:20240101T000000Z
+DTSTART:20240101T120000Z
+DTEND:20240101T121500Z
+RRULE:FREQ=DAILY
+SUMMARY:Daily heartbeat
+END:VEVENT
+BEGIN:VEVENT
+UID:fixed-event@webcal-combiner
+DTSTAMP:20240101T000000Z
+DTSTART;VALUE=DATE:20240101
+SUMMARY:Fixed event
+END:VEVENT
+";
+
+const SLOW_EVENTS: &str = "BEGIN:VEVENT
+UID:weekly-heartbeat@webcal-combiner
+DTSTAMP:20240101T000000Z
+DTSTART:20240101T180000Z
+DTEND:20240101T181500Z
+RRULE:FREQ=WEEKLY
+SUMMARY:Weekly heartbeat
+END:VEVENT
+";
+
+pub fn is_synthetic(url: &str) -> bool {
+    url.starts_with(SCHEME)
+}
+
+/// Whether `url` names one of the built-in generators.
+pub fn is_known(url: &str) -> bool {
+    reqwest::Url::parse(url).is_ok_and(|url| matches!(url.path(), "fixed" | "slow"))
+}
+
+/// The body of a synthetic source, after its delay; `None` for an unknown
+/// generator.
+pub async fn generate(url: &str) -> Option<String> {
+    let url = reqwest::Url::parse(url).ok()?;
+    let events = match url.path() {
+        "fixed" => FIXED_EVENTS,
+        "slow" => {
+            let delay_ms = url
+                .query_pairs()
+                .find(|(key, _)| key == "delay_ms")
+                .and_then(|(_, value)| value.parse().ok())
+                .unwrap_or(0);
+            tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+            SLOW_EVENTS
+        }
+        _ => return None,
+    };
+    Some(format!(
+        "BEGIN:VCALENDAR\nVERSION:2.0\nPRODID:-//webcal-combiner//synthetic//EN\n{}END:VCALENDAR\n",
+        events
+    ))
+}
+
+/// The group configured by `monitoring_group`: a fixed source and a slow one.
+pub fn monitoring_group(settings: &MonitoringGroup) -> CalendarGroup {
+    let source = |name: &str, url: String| SourceCalendar {
+        name: name.to_string(),
+        description: "Built-in source for monitoring".to_string(),
+        url,
+        ..Default::default()
+    };
+    CalendarGroup {
+        name: settings.name.clone(),
+        calendars: vec![
+            source("fixed", FIXED.to_string()),
+            source(
+                "slow",
+                format!("{}?delay_ms={}", SLOW, settings.slow_source_delay_ms),
+            ),
+        ],
+        ..Default::default()
+    }
+}
@@ -0,0 +1,97 @@
+//! systemd integration: a listening socket passed by socket activation
+//! (`LISTEN_FDS`), and readiness and watchdog notifications (`NOTIFY_SOCKET`).
+//! Outside systemd none of the variables are set and all of this is a no-op.
+
+use std::os::fd::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+use tokio::net::{TcpListener, UnixListener};
+
+/// The first file descriptor systemd passes, after stdin, stdout and stderr.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// A listening socket inherited from systemd.
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Takes the socket systemd passed to this process, if any. The variables are
+/// removed so child processes don't take it for theirs.
+pub fn take_listener() -> std::io::Result<Option<Listener>> {
+    let (Ok(pid), Ok(count)) = (std::env::var("LISTEN_PID"), std::env::var("LISTEN_FDS")) else {
+        return Ok(None);
+    };
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    if pid.parse() != Ok(std::process::id()) {
+        return Ok(None);
+    }
+    match count.parse::<u32>() {
+        Ok(0) | Err(_) => return Ok(None),
+        Ok(1) => {}
+        Ok(count) => tracing::warn!("systemd passed {} sockets, only the first is used", count),
+    }
+
+    // SAFETY: systemd hands this process ownership of the descriptor, and
+    // nothing else uses it since LISTEN_FDS was only read here
+    let tcp = unsafe { std::net::TcpListener::from_raw_fd(LISTEN_FDS_START) };
+    // A Unix socket has no IP address, so this tells the two apart
+    if tcp.local_addr().is_ok() {
+        tcp.set_nonblocking(true)?;
+        return Ok(Some(Listener::Tcp(TcpListener::from_std(tcp)?)));
+    }
+    // SAFETY: as above; the descriptor was released from `tcp`
+    let unix = unsafe { std::os::unix::net::UnixListener::from_raw_fd(tcp.into_raw_fd()) };
+    unix.set_nonblocking(true)?;
+    Ok(Some(Listener::Unix(UnixListener::from_std(unix)?)))
+}
+
+/// Tells systemd the server is ready, and starts the watchdog pings when the
+/// unit has `WatchdogSec=` set.
+pub fn ready() {
+    notify("READY=1");
+    let Some(interval) = watchdog_interval() else {
+        return;
+    };
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            notify("WATCHDOG=1");
+        }
+    });
+}
+
+/// Half the watchdog timeout, as systemd recommends, if it applies to this
+/// process.
+fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+/// Sends `state` to systemd's notification socket, if there is one. Failures
+/// are logged, since the server works without them.
+fn notify(state: &str) {
+    let Ok(path) = std::env::var("NOTIFY_SOCKET") else {
+        return;
+    };
+    let sent = UnixDatagram::unbound().and_then(|socket| match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &address)
+        }
+        _ => socket.send_to(state.as_bytes(), &path),
+    });
+    if let Err(e) = sent {
+        tracing::warn!("Failed to notify systemd of {}: {}", state, e);
+    }
+}
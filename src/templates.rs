@@ -0,0 +1,226 @@
+//! Message templates of change webhooks, daily digests and alerts, rendered
+//! with minijinja from each message's structured data. A target takes its
+//! template inline as `template` or from `template_file`, relative to the
+//! config's directory, and falls back to the defaults here.
+
+use crate::i18n::Locale;
+use minijinja::{Environment, UndefinedBehavior};
+use serde::Serialize;
+use webcal_combiner::api::EventSummary;
+
+/// The text of a change webhook's message, listing up to 10 events; the JSON
+/// lists them all.
+pub const DEFAULT_CHANGES: &str = "\
+{{ group }}: {{ added|length }} added, {{ changed|length }} changed, {{ removed|length }} removed
+{% for event in events[:10] %}
+{{ event.change }}: {{ event.summary }}{% if event.start %} ({{ event.start }}){% endif %}
+
+{% endfor %}
+{% if events|length > 10 %}
+…and {{ events|length - 10 }} more
+{% endif %}";
+
+/// A daily digest's message.
+pub const DEFAULT_DIGEST: &str = "\
+*{{ group }}*: {{ date }}
+{% for event in events %}
+• {{ event.time }} {{ event.summary }} ({{ event.source }})
+{% else %}
+{{ no_events }}
+{% endfor %}";
+
+/// The title of an alert.
+pub const DEFAULT_ALERT_TITLE: &str = "\
+Source '{{ source }}' of '{{ group }}' {% if error %}is failing{% else %}recovered{% endif %}";
+
+/// The message of an alert.
+pub const DEFAULT_ALERT: &str = "\
+{% if error %}
+Source '{{ source }}' of '{{ group }}' failed {{ failures }} checks in a row: {{ error }}
+{% else %}
+Source '{{ source }}' of '{{ group }}' answers again after {{ failures }} failed checks
+{% endif %}";
+
+/// What a change webhook's template is rendered with.
+#[derive(Serialize)]
+pub struct Changes<'a> {
+    pub group: &'a str,
+    pub added: &'a [EventSummary],
+    pub changed: &'a [EventSummary],
+    pub removed: &'a [EventSummary],
+    /// The added, changed and removed events in one list, in that order.
+    pub events: Vec<ChangedEvent<'a>>,
+}
+
+/// An event of `Changes::events`, with its `change`: "Added", "Changed" or
+/// "Removed".
+#[derive(Serialize)]
+pub struct ChangedEvent<'a> {
+    pub change: &'static str,
+    #[serde(flatten)]
+    pub event: &'a EventSummary,
+}
+
+impl<'a> Changes<'a> {
+    pub fn new(
+        group: &'a str,
+        added: &'a [EventSummary],
+        changed: &'a [EventSummary],
+        removed: &'a [EventSummary],
+    ) -> Self {
+        let events = [("Added", added), ("Changed", changed), ("Removed", removed)]
+            .into_iter()
+            .flat_map(|(change, events)| {
+                events
+                    .iter()
+                    .map(move |event| ChangedEvent { change, event })
+            })
+            .collect();
+        Changes {
+            group,
+            added,
+            changed,
+            removed,
+            events,
+        }
+    }
+}
+
+/// What a daily digest's template is rendered with.
+#[derive(Serialize)]
+pub struct Digest<'a> {
+    pub group: String,
+    /// The day, written out in the configured locale.
+    pub date: String,
+    pub count: usize,
+    pub events: Vec<DigestEvent>,
+    /// "No events" in the configured locale.
+    pub no_events: &'a str,
+}
+
+/// An event of a digest, its text escaped for the chat service.
+#[derive(Serialize)]
+pub struct DigestEvent {
+    /// The local start and end times, or "All day" in the configured locale.
+    pub time: String,
+    pub summary: String,
+    pub source: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    pub all_day: bool,
+}
+
+/// What an alert's title and message templates are rendered with.
+#[derive(Serialize)]
+pub struct Alert<'a> {
+    pub source: &'a str,
+    pub group: &'a str,
+    /// Checks failed in a row.
+    pub failures: u32,
+    /// Why the source is failing; left out once it recovered.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<&'a str>,
+}
+
+/// The message kinds, to check templates against.
+#[derive(Debug, Clone, Copy)]
+pub enum Kind {
+    Changes,
+    Digest,
+    Alert,
+}
+
+/// Renders `source` with `context`. A missing value, such as the start of an
+/// event without one, renders as nothing.
+pub fn render(source: &str, context: impl Serialize) -> Result<String, minijinja::Error> {
+    let text = environment(UndefinedBehavior::Lenient).render_str(source, context)?;
+    Ok(text.trim_end().to_string())
+}
+
+/// Checks that `source` compiles and renders a sample message of `kind`
+/// without using a name the message doesn't have.
+pub fn check(source: &str, kind: Kind) -> Result<(), minijinja::Error> {
+    let environment = environment(UndefinedBehavior::SemiStrict);
+    let event = EventSummary {
+        uid: "sample".to_string(),
+        recurrence_id: None,
+        summary: "Sample".to_string(),
+        start: Some("2024-05-01T09:00:00+00:00".to_string()),
+        properties: vec!["LOCATION".to_string()],
+    };
+    let events = std::slice::from_ref(&event);
+    match kind {
+        Kind::Changes => {
+            environment.render_str(source, Changes::new("Sample", events, events, events))?
+        }
+        Kind::Digest => environment.render_str(
+            source,
+            Digest {
+                group: "Sample".to_string(),
+                date: Locale::default().format_day(chrono::NaiveDate::MIN),
+                count: 1,
+                events: vec![DigestEvent {
+                    time: "09:00–10:00".to_string(),
+                    summary: "Sample".to_string(),
+                    source: "Sample".to_string(),
+                    location: Some("Sample".to_string()),
+                    all_day: false,
+                }],
+                no_events: Locale::default().no_events(),
+            },
+        )?,
+        Kind::Alert => environment.render_str(
+            source,
+            Alert {
+                source: "Sample",
+                group: "Sample",
+                failures: 3,
+                error: Some("Sample"),
+            },
+        )?,
+    };
+    Ok(())
+}
+
+/// Block tags don't leave their line behind, so a template file can put each
+/// on a line of its own.
+fn environment(undefined: UndefinedBehavior) -> Environment<'static> {
+    let mut environment = Environment::new();
+    environment.set_undefined_behavior(undefined);
+    environment.set_trim_blocks(true);
+    environment.set_lstrip_blocks(true);
+    environment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(summary: &str, start: Option<&str>) -> EventSummary {
+        EventSummary {
+            uid: summary.to_string(),
+            recurrence_id: None,
+            summary: summary.to_string(),
+            start: start.map(str::to_string),
+            properties: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn default_changes_list_events_without_a_start() {
+        let added = [event("Concert", Some("2024-12-05"))];
+        let removed = [event("Fair", None)];
+        let context = Changes::new("School", &added, &[], &removed);
+        assert_eq!(
+            render(DEFAULT_CHANGES, &context).unwrap(),
+            "School: 1 added, 0 changed, 1 removed\nAdded: Concert (2024-12-05)\nRemoved: Fair"
+        );
+    }
+
+    #[test]
+    fn check_rejects_unknown_names() {
+        assert!(check("{{ event.summary }}", Kind::Alert).is_err());
+        assert!(check("{% for event in events %}", Kind::Changes).is_err());
+        assert!(check("{{ source }}: {{ error }}", Kind::Alert).is_ok());
+    }
+}
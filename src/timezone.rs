@@ -0,0 +1,363 @@
+use crate::ics::{self, DateValue, Property};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, Offset, TimeZone, Utc};
+use chrono_tz::{OffsetComponents, OffsetName, Tz};
+use std::collections::BTreeMap;
+
+/// Date-time properties rewritten into the target zone.
+const DATE_TIME_PROPERTIES: &[&str] = &["DTSTART", "DTEND", "RECURRENCE-ID", "EXDATE", "RDATE"];
+/// Years past the current one covered by generated VTIMEZONEs, so open-ended
+/// recurring events keep the right offsets.
+const LOOKAHEAD_YEARS: i32 = 10;
+/// Earliest year generated VTIMEZONEs list transitions for; older values fall
+/// back to the first observance.
+const EARLIEST_YEAR: i32 = 1970;
+
+/// Moves the date-times of an unfolded, `\n`-separated VEVENT by `offset`,
+/// keeping each value's form: UTC stays UTC, and values with a TZID or floating
+/// times keep their zone and shift on the wall clock. An RRULE's `UNTIL` moves
+/// with them, so the series keeps its last occurrence. All-day dates and
+/// periods are left alone.
+pub fn shift_event(event_text: &str, offset: Duration) -> String {
+    event_text
+        .lines()
+        .map(|line| shift_line(line, offset).unwrap_or_else(|| line.to_string()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn shift_line(line: &str, offset: Duration) -> Option<String> {
+    let mut property = Property::parse(line)?;
+    if property.name == "RRULE" {
+        property.value = shift_until(&property.value, offset)?;
+        return Some(property.to_string());
+    }
+    if !DATE_TIME_PROPERTIES.contains(&property.name.as_str()) {
+        return None;
+    }
+    let shifted: Option<Vec<String>> = property
+        .value
+        .split(',')
+        .map(|value| shift_value(value.trim(), offset))
+        .collect();
+    property.value = shifted?.join(",");
+    Some(property.to_string())
+}
+
+/// Moves the `UNTIL` of an RRULE value, if it's a date-time.
+fn shift_until(rule: &str, offset: Duration) -> Option<String> {
+    let mut shifted = false;
+    let parts: Vec<String> = rule
+        .split(';')
+        .map(|part| match part.split_once('=') {
+            Some((name, value)) if name.eq_ignore_ascii_case("UNTIL") => {
+                match shift_value(value, offset) {
+                    Some(value) => {
+                        shifted = true;
+                        format!("{}={}", name, value)
+                    }
+                    None => part.to_string(),
+                }
+            }
+            _ => part.to_string(),
+        })
+        .collect();
+    shifted.then(|| parts.join(";"))
+}
+
+fn shift_value(value: &str, offset: Duration) -> Option<String> {
+    let (local, utc) = match value.strip_suffix('Z') {
+        Some(local) => (local, "Z"),
+        None => (value, ""),
+    };
+    let parsed = NaiveDateTime::parse_from_str(local, "%Y%m%dT%H%M%S").ok()?;
+    Some(format!(
+        "{}{}",
+        (parsed + offset).format("%Y%m%dT%H%M%S"),
+        utc
+    ))
+}
+
+/// Gives the floating date-times of an unfolded, `\n`-separated VEVENT the zone
+/// `tzid`. Values in UTC or with a TZID and all-day dates are left alone.
+pub fn assume_zone(event_text: &str, tzid: &str) -> String {
+    event_text
+        .lines()
+        .map(|line| assume_zone_line(line, tzid).unwrap_or_else(|| line.to_string()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn assume_zone_line(line: &str, tzid: &str) -> Option<String> {
+    let mut property = Property::parse(line)?;
+    if !DATE_TIME_PROPERTIES.contains(&property.name.as_str()) || property.param("TZID").is_some() {
+        return None;
+    }
+    let floating = property
+        .value
+        .split(',')
+        .all(|value| NaiveDateTime::parse_from_str(value.trim(), "%Y%m%dT%H%M%S").is_ok());
+    if !floating {
+        return None;
+    }
+    property
+        .params
+        .insert(0, ("TZID".to_string(), tzid.to_string()));
+    Some(property.to_string())
+}
+
+/// Rewrites event date-times into a single target zone and builds the matching
+/// VTIMEZONE.
+///
+/// Values with a TZID or in UTC are converted; all-day dates and floating times
+/// are left alone, since they don't belong to any zone. Recurring events keep
+/// their RRULE, so their wall-clock time follows the target zone's DST rules
+/// rather than the original zone's.
+pub struct TimezoneConverter {
+    tz: Tz,
+    first_year: FirstYear,
+}
+
+impl TimezoneConverter {
+    pub fn new(tz: Tz) -> Self {
+        Self {
+            tz,
+            first_year: FirstYear::default(),
+        }
+    }
+
+    pub fn tz(&self) -> Tz {
+        self.tz
+    }
+
+    /// Converts the date-time properties of an unfolded, `\n`-separated VEVENT.
+    pub fn convert_event(&mut self, event_text: &str) -> String {
+        event_text
+            .lines()
+            .map(|line| self.convert_line(line).unwrap_or_else(|| line.to_string()))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn convert_line(&mut self, line: &str) -> Option<String> {
+        let mut property = Property::parse(line)?;
+        if !DATE_TIME_PROPERTIES.contains(&property.name.as_str()) {
+            return None;
+        }
+        let zoned = property.param("TZID").is_some() || property.value.trim().ends_with('Z');
+        let is_date_time = property
+            .param("VALUE")
+            .is_none_or(|v| v.eq_ignore_ascii_case("DATE-TIME"));
+        if !zoned || !is_date_time {
+            return None;
+        }
+
+        let values = DateValue::parse_list(&property);
+        if values.is_empty() || values.iter().any(|value| value.all_day) {
+            return None;
+        }
+
+        let formatted: Vec<String> = values
+            .iter()
+            .map(|value| {
+                let utc = value.utc();
+                self.first_year.include(utc);
+                self.format(utc)
+            })
+            .collect();
+
+        property.params.retain(|(key, _)| key != "TZID");
+        if self.tz != Tz::UTC {
+            property
+                .params
+                .insert(0, ("TZID".to_string(), self.tz.name().to_string()));
+        }
+        property.value = formatted.join(",");
+        Some(property.to_string())
+    }
+
+    fn format(&self, dt: DateTime<Utc>) -> String {
+        if self.tz == Tz::UTC {
+            ics::format_utc(dt)
+        } else {
+            dt.with_timezone(&self.tz)
+                .format("%Y%m%dT%H%M%S")
+                .to_string()
+        }
+    }
+
+    /// The VTIMEZONE for the target zone. UTC values need no VTIMEZONE, so this is
+    /// `None` for UTC.
+    pub fn vtimezone(&self, now: DateTime<Utc>) -> Option<String> {
+        if self.tz == Tz::UTC {
+            return None;
+        }
+        Some(vtimezone(self.tz.name(), self.tz, self.first_year, now))
+    }
+}
+
+/// The earliest year a zone's date-times fall in, where its VTIMEZONE starts.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FirstYear(Option<i32>);
+
+impl FirstYear {
+    pub fn include(&mut self, dt: DateTime<Utc>) {
+        let year = dt.year();
+        self.0 = Some(self.0.map_or(year, |first| first.min(year)));
+    }
+}
+
+/// Collects the TZIDs referenced by the properties of an unfolded VEVENT, along
+/// with the earliest year their values fall in.
+pub fn referenced_tzids(event_text: &str, tzids: &mut BTreeMap<String, FirstYear>) {
+    for line in event_text.lines() {
+        let Some(property) = Property::parse(line) else {
+            continue;
+        };
+        let Some(tzid) = property.param("TZID") else {
+            continue;
+        };
+        let span = tzids.entry(tzid.to_string()).or_default();
+        for value in DateValue::parse_list(&property) {
+            span.include(value.utc());
+        }
+    }
+}
+
+/// Builds a `\n`-separated VTIMEZONE for `tz` from the IANA database, labelled
+/// `tzid`.
+///
+/// Every transition from `first_year` (but not before
+/// `EARLIEST_YEAR`) through `LOOKAHEAD_YEARS` past `now` is listed explicitly,
+/// so the output doesn't depend on the zone's current DST rules.
+pub fn vtimezone(tzid: &str, tz: Tz, first_year: FirstYear, now: DateTime<Utc>) -> String {
+    let first = first_year.0.unwrap_or(now.year());
+    let start = year_start(first.clamp(EARLIEST_YEAR, now.year()));
+    let end = year_start(now.year() + LOOKAHEAD_YEARS + 1);
+
+    // Observances with identical offsets and names are merged, listing later
+    // onsets as RDATEs
+    let mut observances: Vec<Observance> = Vec::new();
+    let mut add = |observance: Observance| match observances
+        .iter_mut()
+        .find(|existing| existing.same_rule(&observance))
+    {
+        Some(existing) => existing.rdates.push(observance.onset),
+        None => observances.push(observance),
+    };
+
+    let initial = tz.offset_from_utc_datetime(&start.naive_utc());
+    add(Observance::new(start, &initial, &initial));
+
+    let mut previous = start;
+    let mut cursor = start + Duration::days(1);
+    while cursor <= end {
+        let before = tz.offset_from_utc_datetime(&previous.naive_utc());
+        let after = tz.offset_from_utc_datetime(&cursor.naive_utc());
+        if before != after {
+            let transition = find_transition(tz, previous, cursor);
+            add(Observance::new(transition, &before, &after));
+        }
+        previous = cursor;
+        cursor += Duration::days(1);
+    }
+
+    let mut output = String::new();
+    output.push_str("BEGIN:VTIMEZONE\n");
+    output.push_str(&format!("TZID:{}\n", tzid));
+    for observance in &observances {
+        observance.render(&mut output);
+    }
+    output.push_str("END:VTIMEZONE\n");
+    output
+}
+
+fn year_start(year: i32) -> DateTime<Utc> {
+    NaiveDate::from_ymd_opt(year, 1, 1)
+        .unwrap()
+        .and_hms_opt(0, 0, 0)
+        .unwrap()
+        .and_utc()
+}
+
+/// Binary-searches `(before, after]` for the instant the zone's offset changes.
+fn find_transition(tz: Tz, mut before: DateTime<Utc>, mut after: DateTime<Utc>) -> DateTime<Utc> {
+    let offset = tz.offset_from_utc_datetime(&before.naive_utc());
+    while after - before > Duration::seconds(1) {
+        let mid = before + (after - before) / 2;
+        if tz.offset_from_utc_datetime(&mid.naive_utc()) == offset {
+            before = mid;
+        } else {
+            after = mid;
+        }
+    }
+    after
+}
+
+/// A STANDARD or DAYLIGHT sub-component of a VTIMEZONE.
+struct Observance {
+    daylight: bool,
+    name: String,
+    offset_from: i32,
+    offset_to: i32,
+    /// Local wall-clock time of the first onset, expressed in `offset_from`.
+    onset: String,
+    rdates: Vec<String>,
+}
+
+impl Observance {
+    fn new(at: DateTime<Utc>, before: &chrono_tz::TzOffset, after: &chrono_tz::TzOffset) -> Self {
+        let offset_from = before.fix().local_minus_utc();
+        let onset = (at + Duration::seconds(offset_from.into()))
+            .format("%Y%m%dT%H%M%S")
+            .to_string();
+        Self {
+            daylight: !after.dst_offset().is_zero(),
+            name: after.abbreviation().unwrap_or_default().to_string(),
+            offset_from,
+            offset_to: after.fix().local_minus_utc(),
+            onset,
+            rdates: Vec::new(),
+        }
+    }
+
+    fn same_rule(&self, other: &Self) -> bool {
+        self.daylight == other.daylight
+            && self.name == other.name
+            && self.offset_from == other.offset_from
+            && self.offset_to == other.offset_to
+    }
+
+    fn render(&self, output: &mut String) {
+        let kind = if self.daylight {
+            "DAYLIGHT"
+        } else {
+            "STANDARD"
+        };
+        output.push_str(&format!("BEGIN:{}\n", kind));
+        output.push_str(&format!("DTSTART:{}\n", self.onset));
+        for rdate in &self.rdates {
+            output.push_str(&format!("RDATE:{}\n", rdate));
+        }
+        output.push_str(&format!(
+            "TZOFFSETFROM:{}\n",
+            format_offset(self.offset_from)
+        ));
+        output.push_str(&format!("TZOFFSETTO:{}\n", format_offset(self.offset_to)));
+        if !self.name.is_empty() {
+            output.push_str(&format!("TZNAME:{}\n", self.name));
+        }
+        output.push_str(&format!("END:{}\n", kind));
+    }
+}
+
+/// Formats a UTC offset in seconds as an RFC 5545 UTC-OFFSET, e.g. `+0100`.
+fn format_offset(seconds: i32) -> String {
+    let sign = if seconds < 0 { '-' } else { '+' };
+    let seconds = seconds.abs();
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if seconds == 0 {
+        format!("{}{:02}{:02}", sign, hours, minutes)
+    } else {
+        format!("{}{:02}{:02}{:02}", sign, hours, minutes, seconds)
+    }
+}
@@ -0,0 +1,129 @@
+//! Durations and sizes in config.json written with a unit, e.g. `"90s"`, `"5m"`,
+//! `"2h"` or `"10MB"`, for the fields that take them through `deserialize_with`.
+//! Bare numbers keep meaning the unit in the field's name, so existing configs
+//! load as before.
+
+use serde::de::{Deserializer, Error};
+use serde::Deserialize;
+
+/// A value as written in the config: a bare number or a string with a unit.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Raw {
+    Number(u64),
+    Text(String),
+}
+
+/// The units of one kind of quantity, each with its size in the smallest unit.
+struct Units {
+    kind: &'static str,
+    examples: &'static str,
+    table: &'static [(&'static str, u64)],
+}
+
+const DURATION: Units = Units {
+    kind: "duration",
+    examples: "\"30s\", \"5m\" or \"2h\"",
+    table: &[
+        ("ms", 1),
+        ("s", 1000),
+        ("m", 60 * 1000),
+        ("h", 60 * 60 * 1000),
+        ("d", 24 * 60 * 60 * 1000),
+    ],
+};
+
+const SIZE: Units = Units {
+    kind: "size",
+    examples: "\"512KB\", \"10MB\" or \"1GiB\"",
+    table: &[
+        ("B", 1),
+        ("KB", 1000),
+        ("MB", 1000 * 1000),
+        ("GB", 1000 * 1000 * 1000),
+        ("KiB", 1 << 10),
+        ("MiB", 1 << 20),
+        ("GiB", 1 << 30),
+    ],
+};
+
+/// For `*_seconds` fields.
+pub fn seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    in_unit(Raw::deserialize(deserializer)?, &DURATION, "s", "seconds").map_err(D::Error::custom)
+}
+
+/// For optional `*_seconds` fields.
+pub fn opt_seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    Option::<Raw>::deserialize(deserializer)?
+        .map(|raw| in_unit(raw, &DURATION, "s", "seconds"))
+        .transpose()
+        .map_err(D::Error::custom)
+}
+
+/// For `*_ms` fields.
+pub fn millis<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    in_unit(
+        Raw::deserialize(deserializer)?,
+        &DURATION,
+        "ms",
+        "milliseconds",
+    )
+    .map_err(D::Error::custom)
+}
+
+/// For `*_bytes` fields.
+pub fn bytes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    in_unit(Raw::deserialize(deserializer)?, &SIZE, "B", "bytes").map_err(D::Error::custom)
+}
+
+/// For optional `*_bytes` fields.
+pub fn opt_bytes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    Option::<Raw>::deserialize(deserializer)?
+        .map(|raw| in_unit(raw, &SIZE, "B", "bytes"))
+        .transpose()
+        .map_err(D::Error::custom)
+}
+
+/// Converts `raw` into a whole number of `unit`, called `unit_name` in errors.
+fn in_unit(raw: Raw, units: &Units, unit: &str, unit_name: &str) -> Result<u64, String> {
+    let text = match raw {
+        Raw::Number(value) => return Ok(value),
+        Raw::Text(text) => text,
+    };
+    let trimmed = text.trim();
+    let split = trimmed
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(trimmed.len());
+    let (number, suffix) = trimmed.split_at(split);
+    let suffix = suffix.trim();
+    let invalid = || {
+        format!(
+            "invalid {} \"{}\", expected e.g. {}",
+            units.kind, text, units.examples
+        )
+    };
+
+    let value: u64 = number.parse().map_err(|_| invalid())?;
+    if suffix.is_empty() {
+        return Ok(value);
+    }
+    let per = |name: &str| {
+        units
+            .table
+            .iter()
+            .find(|(unit, _)| *unit == name)
+            .map(|(_, size)| *size)
+    };
+    let from = per(suffix).ok_or_else(invalid)?;
+    let to = per(unit).expect("field units are in the table");
+    let smallest = value
+        .checked_mul(from)
+        .ok_or_else(|| format!("{} \"{}\" is too large", units.kind, text))?;
+    if smallest % to != 0 {
+        return Err(format!(
+            "{} \"{}\" must be a whole number of {}",
+            units.kind, text, unit_name
+        ));
+    }
+    Ok(smallest / to)
+}
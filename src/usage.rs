@@ -0,0 +1,196 @@
+use crate::view::escape_html;
+use chrono::{DateTime, Datelike, Days, NaiveDate, Timelike, Utc};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use webcal_combiner::api::{DailyUsage, UsageReport, UsageStats};
+
+/// Days of per-day counts kept for each group and key.
+pub const DAYS: u64 = 30;
+
+const WEEKDAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+const STYLE: &str = "body{font-family:sans-serif;margin:1.5em;background:#fafafa;color:#222}\
+h1{margin-top:0}\
+h2{font-size:1.1em;border-bottom:1px solid #ccc;padding-bottom:.2em;margin-top:1.5em}\
+h3{font-size:1em;margin-bottom:.3em}\
+.meta{color:#555;font-size:.9em}\
+table{border-collapse:collapse;font-size:.75em}\
+th{font-weight:normal;color:#555;padding:0 .3em}\
+td{width:1.6em;height:1.4em;text-align:center;border:1px solid #fff}";
+
+/// Request counts by group and by key, for spotting when clients poll and which
+/// subscriptions have gone quiet.
+pub struct UsageTracker {
+    activity: Mutex<Activity>,
+}
+
+#[derive(Default)]
+struct Activity {
+    groups: BTreeMap<String, Counts>,
+    keys: BTreeMap<String, Counts>,
+}
+
+struct Counts {
+    total: u64,
+    last_seen: DateTime<Utc>,
+    /// By weekday from Monday and hour, in UTC.
+    hourly: [[u64; 24]; 7],
+    daily: BTreeMap<NaiveDate, u64>,
+}
+
+impl Counts {
+    fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            total: 0,
+            last_seen: now,
+            hourly: [[0; 24]; 7],
+            daily: BTreeMap::new(),
+        }
+    }
+
+    fn record(&mut self, now: DateTime<Utc>) {
+        self.total += 1;
+        self.last_seen = now;
+        self.hourly[now.weekday().num_days_from_monday() as usize][now.hour() as usize] += 1;
+        let today = now.date_naive();
+        *self.daily.entry(today).or_insert(0) += 1;
+        if let Some(oldest) = today.checked_sub_days(Days::new(DAYS)) {
+            self.daily.retain(|&day, _| day > oldest);
+        }
+    }
+
+    fn stats(&self, now: DateTime<Utc>) -> UsageStats {
+        let today = now.date_naive();
+        let daily = (0..DAYS)
+            .rev()
+            .filter_map(|ago| today.checked_sub_days(Days::new(ago)))
+            .map(|day| DailyUsage {
+                date: day.format("%Y-%m-%d").to_string(),
+                count: self.daily.get(&day).copied().unwrap_or(0),
+            })
+            .collect();
+        UsageStats {
+            total: self.total,
+            last_seen_seconds_ago: u64::try_from((now - self.last_seen).num_seconds()).unwrap_or(0),
+            hourly: self.hourly.to_vec(),
+            daily,
+        }
+    }
+}
+
+impl UsageTracker {
+    pub fn new() -> Self {
+        Self {
+            activity: Mutex::new(Activity::default()),
+        }
+    }
+
+    /// Counts one request for `group` made with the key or token `key`.
+    pub fn record(&self, key: &str, group: &str) {
+        let now = Utc::now();
+        let mut guard = self.activity.lock().unwrap();
+        let activity = &mut *guard;
+        for (map, name) in [(&mut activity.groups, group), (&mut activity.keys, key)] {
+            map.entry(name.to_string())
+                .or_insert_with(|| Counts::new(now))
+                .record(now);
+        }
+    }
+
+    pub fn report(&self) -> UsageReport {
+        let now = Utc::now();
+        let activity = self.activity.lock().unwrap();
+        let stats = |map: &BTreeMap<String, Counts>| {
+            map.iter()
+                .map(|(name, counts)| (name.clone(), counts.stats(now)))
+                .collect()
+        };
+        UsageReport {
+            groups: stats(&activity.groups),
+            keys: stats(&activity.keys),
+        }
+    }
+}
+
+/// Renders `report` as heatmaps: a weekday-by-hour grid and a row of days for
+/// every group and key, shaded relative to their own busiest cell.
+pub fn render(report: &UsageReport) -> String {
+    let mut output = String::new();
+    output.push_str("<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n");
+    output.push_str("<meta charset=\"utf-8\">\n");
+    output.push_str("<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n");
+    output.push_str("<title>Subscription usage</title>\n");
+    output.push_str(&format!("<style>{}</style>\n", STYLE));
+    output.push_str("</head>\n<body>\n<h1>Subscription usage</h1>\n");
+    output.push_str("<p class=\"meta\">Requests since startup. Hours and days are in UTC.</p>\n");
+
+    for (heading, entries) in [("Groups", &report.groups), ("Keys", &report.keys)] {
+        output.push_str(&format!("<h2>{}</h2>\n", heading));
+        if entries.is_empty() {
+            output.push_str("<p>No requests yet.</p>\n");
+        }
+        for (name, stats) in entries {
+            output.push_str(&format!(
+                "<h3>{}</h3>\n<p class=\"meta\">{} requests, last {}</p>\n",
+                escape_html(name),
+                stats.total,
+                format_ago(stats.last_seen_seconds_ago)
+            ));
+            render_hourly(&mut output, &stats.hourly);
+            render_daily(&mut output, &stats.daily);
+        }
+    }
+
+    output.push_str("</body>\n</html>\n");
+    output
+}
+
+fn render_hourly(output: &mut String, hourly: &[[u64; 24]]) {
+    let max = hourly.iter().flatten().copied().max().unwrap_or(0);
+    output.push_str("<table>\n<tr><th></th>");
+    for hour in 0..24 {
+        output.push_str(&format!("<th>{:02}</th>", hour));
+    }
+    output.push_str("</tr>\n");
+    for (weekday, hours) in WEEKDAYS.iter().zip(hourly) {
+        output.push_str(&format!("<tr><th>{}</th>", weekday));
+        for &count in hours {
+            output.push_str(&cell(count, max, &count.to_string()));
+        }
+        output.push_str("</tr>\n");
+    }
+    output.push_str("</table>\n");
+}
+
+fn render_daily(output: &mut String, daily: &[DailyUsage]) {
+    let max = daily.iter().map(|day| day.count).max().unwrap_or(0);
+    output.push_str("<table>\n<tr><th>Days</th>");
+    for day in daily {
+        let title = format!("{}: {}", day.date, day.count);
+        output.push_str(&cell(day.count, max, &title));
+    }
+    output.push_str("</tr>\n</table>\n");
+}
+
+/// A table cell shaded by `count` relative to `max`, with `title` on hover.
+fn cell(count: u64, max: u64, title: &str) -> String {
+    let alpha = if max == 0 {
+        0.0
+    } else {
+        count as f64 / max as f64
+    };
+    format!(
+        "<td style=\"background:rgba(51,102,170,{:.2})\" title=\"{}\"></td>",
+        alpha,
+        escape_html(title)
+    )
+}
+
+fn format_ago(seconds: u64) -> String {
+    match seconds {
+        0..60 => "just now".to_string(),
+        60..3600 => format!("{} min ago", seconds / 60),
+        3600..86400 => format!("{} h ago", seconds / 3600),
+        _ => format!("{} days ago", seconds / 86400),
+    }
+}
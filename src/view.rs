@@ -0,0 +1,119 @@
+use crate::calendar::AgendaItem;
+use crate::i18n::Locale;
+use chrono::NaiveDate;
+use chrono_tz::Tz;
+
+const STYLE: &str = "body{font-family:sans-serif;margin:1.5em;background:#fafafa;color:#222}\
+h1{margin-top:0}\
+section{margin-bottom:1.2em}\
+h2{font-size:1.1em;border-bottom:1px solid #ccc;padding-bottom:.2em}\
+ul{list-style:none;padding:0;margin:0}\
+li{padding:.25em 0}\
+.time{display:inline-block;min-width:8em;color:#555}\
+.source{font-size:.85em;color:#fff;background:#678;border-radius:3px;padding:0 .4em;margin-left:.4em}\
+.location{display:block;margin-left:8em;font-size:.9em;color:#666}";
+
+pub fn escape_html(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            '"' => output.push_str("&quot;"),
+            '\'' => output.push_str("&#39;"),
+            _ => output.push(c),
+        }
+    }
+    output
+}
+
+/// The calendar day an item is listed under. All-day events are floating dates,
+/// so they keep their own date rather than being shifted into `tz`.
+pub fn item_day(item: &AgendaItem, tz: Tz) -> NaiveDate {
+    if item.all_day {
+        item.start.date_naive()
+    } else {
+        item.start.with_timezone(&tz).date_naive()
+    }
+}
+
+/// Renders a read-only HTML agenda of `items` grouped by day. The page refreshes
+/// itself every 15 minutes so it can be left open on a wall display.
+pub fn render(
+    title: &str,
+    items: &[AgendaItem],
+    tz: Tz,
+    weeks: u32,
+    locale: Locale,
+) -> String {
+    let title = escape_html(title);
+    let mut output = String::new();
+    output.push_str(&format!(
+        "<!DOCTYPE html>\n<html lang=\"{}\">\n<head>\n",
+        locale.tag()
+    ));
+    output.push_str("<meta charset=\"utf-8\">\n");
+    output.push_str("<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n");
+    output.push_str("<meta http-equiv=\"refresh\" content=\"900\">\n");
+    output.push_str(&format!("<title>{}</title>\n", title));
+    output.push_str(&format!("<style>{}</style>\n", STYLE));
+    output.push_str("</head>\n<body>\n");
+    output.push_str(&format!("<h1>{}</h1>\n", title));
+    output.push_str(&format!(
+        "<p>{} ({})</p>\n",
+        locale.next_weeks(weeks),
+        escape_html(tz.name())
+    ));
+
+    if items.is_empty() {
+        output.push_str(&format!("<p>{}</p>\n", locale.no_events()));
+    }
+
+    let mut current_day = None;
+    for item in items {
+        let day = item_day(item, tz);
+        if current_day != Some(day) {
+            if current_day.is_some() {
+                output.push_str("</ul>\n</section>\n");
+            }
+            output.push_str(&format!(
+                "<section>\n<h2>{}</h2>\n<ul>\n",
+                locale.format_day(day)
+            ));
+            current_day = Some(day);
+        }
+
+        let time = if item.all_day {
+            locale.all_day().to_string()
+        } else {
+            let start = item.start.with_timezone(&tz);
+            let end = item.end.with_timezone(&tz);
+            if end > start {
+                format!("{}&ndash;{}", start.format("%H:%M"), end.format("%H:%M"))
+            } else {
+                start.format("%H:%M").to_string()
+            }
+        };
+
+        output.push_str(&format!(
+            "<li><span class=\"time\">{}</span>{}<span class=\"source\">{}</span>",
+            time,
+            escape_html(&item.summary),
+            escape_html(&item.source)
+        ));
+        if let Some(location) = item.location.as_deref().filter(|l| !l.is_empty()) {
+            output.push_str(&format!(
+                "<span class=\"location\">{}</span>",
+                escape_html(location)
+            ));
+        }
+        output.push_str("</li>\n");
+    }
+    if current_day.is_some() {
+        output.push_str("</ul>\n</section>\n");
+    }
+
+    output.push_str("</body>\n</html>\n");
+    output
+}
@@ -0,0 +1,124 @@
+//! A minimal writer of zip archives, for `/export`. Entries are deflated in
+//! memory, so their sizes and checksums are known before the local header is
+//! written and no data descriptors are needed.
+
+use chrono::{Datelike, NaiveDateTime, Timelike};
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+use std::io::{self, Write};
+
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+/// Zip 2.0, the first version with deflate.
+const VERSION: u16 = 20;
+/// File names are UTF-8.
+const FLAG_UTF8: u16 = 1 << 11;
+const METHOD_DEFLATE: u16 = 8;
+
+#[derive(Default)]
+pub struct ZipWriter {
+    data: Vec<u8>,
+    central_directory: Vec<u8>,
+    entries: u16,
+}
+
+impl ZipWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a file. Archives are limited to 65535 files of up to 4 GiB,
+    /// since zip64 isn't written.
+    pub fn add(&mut self, name: &str, contents: &[u8], modified: NaiveDateTime) -> io::Result<()> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(contents)?;
+        let compressed = encoder.finish()?;
+        let mut crc = Crc::new();
+        crc.update(contents);
+
+        let size = u32::try_from(contents.len()).map_err(|_| too_large())?;
+        let compressed_size = u32::try_from(compressed.len()).map_err(|_| too_large())?;
+        let offset = u32::try_from(self.data.len()).map_err(|_| too_large())?;
+        let name_length = u16::try_from(name.len()).map_err(|_| too_large())?;
+        let entries = self.entries.checked_add(1).ok_or_else(too_large)?;
+        let (time, date) = dos_time(modified);
+
+        // The fields the local and central headers share
+        let mut common = Vec::new();
+        put_u16(&mut common, VERSION);
+        put_u16(&mut common, FLAG_UTF8);
+        put_u16(&mut common, METHOD_DEFLATE);
+        put_u16(&mut common, time);
+        put_u16(&mut common, date);
+        put_u32(&mut common, crc.sum());
+        put_u32(&mut common, compressed_size);
+        put_u32(&mut common, size);
+        put_u16(&mut common, name_length);
+        // Extra field length
+        put_u16(&mut common, 0);
+
+        put_u32(&mut self.data, LOCAL_HEADER);
+        self.data.extend_from_slice(&common);
+        self.data.extend_from_slice(name.as_bytes());
+        self.data.extend_from_slice(&compressed);
+
+        let central = &mut self.central_directory;
+        put_u32(central, CENTRAL_HEADER);
+        // Version made by
+        put_u16(central, VERSION);
+        central.extend_from_slice(&common);
+        // Comment length, disk number, internal and external attributes
+        put_u16(central, 0);
+        put_u16(central, 0);
+        put_u16(central, 0);
+        put_u32(central, 0);
+        put_u32(central, offset);
+        central.extend_from_slice(name.as_bytes());
+
+        self.entries = entries;
+        Ok(())
+    }
+
+    /// The finished archive.
+    pub fn finish(mut self) -> io::Result<Vec<u8>> {
+        let offset = u32::try_from(self.data.len()).map_err(|_| too_large())?;
+        let size = u32::try_from(self.central_directory.len()).map_err(|_| too_large())?;
+        self.data.append(&mut self.central_directory);
+
+        let end = &mut self.data;
+        put_u32(end, END_OF_CENTRAL_DIRECTORY);
+        // This disk and the one the central directory starts on
+        put_u16(end, 0);
+        put_u16(end, 0);
+        put_u16(end, self.entries);
+        put_u16(end, self.entries);
+        put_u32(end, size);
+        put_u32(end, offset);
+        // Comment length
+        put_u16(end, 0);
+        Ok(self.data)
+    }
+}
+
+/// `time` in MS-DOS format, with two-second precision, clamped to the years
+/// it can hold.
+fn dos_time(time: NaiveDateTime) -> (u16, u16) {
+    let year = time.year().clamp(1980, 2107) as u16;
+    let seconds = time.second() as u16 / 2;
+    let dos_time = (time.hour() as u16) << 11 | (time.minute() as u16) << 5 | seconds;
+    let dos_date = (year - 1980) << 9 | (time.month() as u16) << 5 | time.day() as u16;
+    (dos_time, dos_date)
+}
+
+fn too_large() -> io::Error {
+    io::Error::other("too large for a zip archive without zip64")
+}
+
+fn put_u16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buffer: &mut Vec<u8>, value: u32) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}